@@ -0,0 +1,81 @@
+extern crate vusbip;
+
+use vusbip::protocol::{DeviceDescriptor, InterfaceDescriptor, RepImport, CmdSubmit, RetSubmit, Direction};
+use vusbip::server::{DeviceProvider, UsbIpServer};
+
+struct TestDevice;
+
+impl TestDevice {
+    fn descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            path: "/foo/bar".to_string(),
+            busid: "3-2".to_string(),
+            busnum: 3,
+            devnum: 2,
+            speed: 2,
+            id_vendor: 0x0403,
+            id_product: 0x6001,
+            bcd_device: 0x0110,
+            device_class: 255,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 2,
+            num_interfaces: 2,
+            interfaces: vec![
+                InterfaceDescriptor {
+                    interface_class: 255,
+                    interface_subclass: 26,
+                    interface_protocol: 29
+                }, InterfaceDescriptor {
+                    interface_class: 255,
+                    interface_subclass: 85,
+                    interface_protocol: 2
+                }
+            ]
+        }
+    }
+}
+
+impl DeviceProvider for TestDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![self.descriptor()]
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        let dev = self.descriptor();
+        if busid != dev.busid {
+            return None;
+        }
+        Some(RepImport::from_device(&dev, 0))
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        println!("Submit: {:?}", cmd);
+        // Answer every IN transfer with an empty buffer and acknowledge every OUT transfer
+        let (length, data) = match cmd.direction {
+            Direction::In => (0, Some(Vec::new())),
+            Direction::Out => (cmd.buffer_length, None),
+        };
+        RetSubmit {
+            seqnum: cmd.seqnum,
+            devid: cmd.devid,
+            direction: cmd.direction,
+            ep: cmd.ep,
+            status: 0,
+            length,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data,
+            iso_packets: Vec::new()
+        }
+    }
+}
+
+fn main() {
+    println!("USBIP Testserver");
+    let server = UsbIpServer::new(TestDevice);
+    server.serve("127.0.0.1:3240").unwrap();
+}
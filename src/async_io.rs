@@ -0,0 +1,228 @@
+use byteorder::{BigEndian, ByteOrder};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::{Packet, PacketError, PacketResult, PacketTypes, ReadLimits, Direction, read_op_header,
+                      DEVICE_INFO_LEN, PAYLOAD_CHUNK};
+
+// num_interfaces is the last byte of the device info
+const NUM_INTERFACES_AT: usize = DEVICE_INFO_LEN - 1;
+
+// The bytes of one packet, fetched a section at a time as far as its layout
+// is known from the fields read so far, so that nothing of the next packet is
+// consumed. The packet is decoded once it is complete.
+struct Frame<'a, R> {
+    src: &'a mut R,
+    buf: Vec<u8>,
+}
+
+impl<'a, R: AsyncRead + Unpin> Frame<'a, R> {
+    // In chunks, so that a length field claiming a lot only costs as much
+    // memory as the peer actually sends
+    async fn fixed(&mut self, len: usize) -> PacketResult<()> {
+        let end = self.buf.len() + len;
+        while self.buf.len() < end {
+            let start = self.buf.len();
+            self.buf.resize(end.min(start + PAYLOAD_CHUNK), 0);
+            self.src.read_exact(&mut self.buf[start..]).await?;
+        }
+        Ok(())
+    }
+
+    // Bytes the peer may also leave out by hanging up instead
+    async fn optional(&mut self, len: usize) -> PacketResult<()> {
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        let n = self.src.read(&mut self.buf[start..]).await?;
+        self.buf.truncate(start + n);
+        if n > 0 {
+            self.fixed(len - n).await?;
+        }
+        Ok(())
+    }
+
+    fn u32_at(&self, pos: usize) -> u32 {
+        BigEndian::read_u32(&self.buf[pos..pos + 4])
+    }
+
+    // Everything after the header word. A field the rest depends on that is
+    // out of limits or invalid ends this early, the decoder then reports it.
+    async fn body(&mut self, ptype: PacketTypes, limits: &ReadLimits) -> PacketResult<()> {
+        let status_len = if limits.request_status { 4 } else { 0 };
+        match ptype {
+            PacketTypes::ReqDevList => self.fixed(status_len).await,
+            PacketTypes::ReqImport => self.fixed(status_len + 32).await,
+            PacketTypes::RepImport => {
+                self.fixed(4).await?;
+                if self.u32_at(4) != 0 {
+                    return self.optional(DEVICE_INFO_LEN).await;
+                }
+                self.fixed(DEVICE_INFO_LEN).await
+            },
+            PacketTypes::RepDevList => {
+                self.fixed(4).await?;
+                if self.u32_at(4) != 0 {
+                    return self.optional(4).await;
+                }
+                self.fixed(4).await?;
+                let num_devices = self.u32_at(8);
+                if num_devices > limits.max_devices {
+                    return Ok(());
+                }
+                let interface_len = if limits.padded_interfaces { 4 } else { 3 };
+                for _ in 0..num_devices {
+                    let start = self.buf.len();
+                    self.fixed(DEVICE_INFO_LEN).await?;
+                    let num_interfaces = self.buf[start + NUM_INTERFACES_AT];
+                    if num_interfaces > limits.max_interfaces {
+                        return Ok(());
+                    }
+                    self.fixed(interface_len * num_interfaces as usize).await?;
+                }
+                Ok(())
+            },
+            // The URB header, then the payload (OUT submits and IN replies)
+            // of buffer_length or actual_length bytes and the iso descriptors
+            PacketTypes::CmdSubmit | PacketTypes::RetSubmit => {
+                self.fixed(44).await?;
+                let direction = match Direction::from_u32(self.u32_at(12)) {
+                    Ok(d) => d,
+                    Err(_) => return Ok(()),
+                };
+                let payload = ptype == PacketTypes::CmdSubmit && direction == Direction::Out
+                    || ptype == PacketTypes::RetSubmit && direction == Direction::In;
+                if payload {
+                    let len = self.u32_at(24);
+                    if len > limits.max_payload {
+                        return Ok(());
+                    }
+                    self.fixed(len as usize).await?;
+                }
+                let num_packets = self.u32_at(32) as usize;
+                self.fixed(num_packets.saturating_mul(16)).await
+            },
+            PacketTypes::CmdUnlink | PacketTypes::RetUnlink => self.fixed(44).await,
+        }
+    }
+}
+
+impl Packet {
+    pub async fn read_async<R: AsyncRead + Unpin>(src: &mut R) -> PacketResult<Packet> {
+        let limits = ReadLimits::default();
+        let mut frame = Frame { src, buf: Vec::new() };
+        frame.optional(4).await?;
+        if frame.buf.is_empty() {
+            return Err(PacketError::ConnectionClosed);
+        }
+        let ptype = PacketTypes::from_u32(read_op_header(&mut frame.buf.as_slice())?)?;
+        frame.body(ptype, &limits).await?;
+        Packet::read_with_limits(&mut frame.buf.as_slice(), &limits)
+    }
+
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, dst: &mut W) -> PacketResult<()> {
+        let buf = self.write_to_vec()?;
+        dst.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::protocol::tests::device;
+    use crate::protocol::{Packet, PacketError, RepDevList, RepImport, UsbIpStatus,
+                          CmdSubmitBuilder, RetSubmit, CmdUnlink, RetUnlink, IsoPacketDescriptor, Direction,
+                          DEFAULT_MAX_DEVICES};
+
+    #[tokio::test]
+    async fn test_rep_device_list_async() {
+        let dl = Packet::RepDevList(RepDevList {
+            status: 0,
+            num_devices: 1,
+            devices: vec![device()]
+        });
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = async {
+            dl.write_async(&mut client).await.unwrap();
+            Packet::ReqDevList.write_async(&mut client).await.unwrap();
+        };
+        let reader = async {
+            let first = Packet::read_async(&mut server).await.unwrap();
+            let second = Packet::read_async(&mut server).await.unwrap();
+            (first, second)
+        };
+        let (_, (first, second)) = tokio::join!(writer, reader);
+        assert_eq!(dl, first);
+        assert_eq!(Packet::ReqDevList, second);
+    }
+
+    #[tokio::test]
+    async fn test_failed_import_async() {
+        let failed = Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice));
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = async {
+            failed.write_async(&mut client).await.unwrap();
+            Packet::ReqDevList.write_async(&mut client).await.unwrap();
+            // Unpadded like the Linux usbipd, followed by a hang up
+            client.write_all(&[0x01, 0x11, 0x00, 0x03, 0, 0, 0, 4]).await.unwrap();
+            drop(client);
+        };
+        let reader = async {
+            let first = Packet::read_async(&mut server).await.unwrap();
+            let second = Packet::read_async(&mut server).await.unwrap();
+            let third = Packet::read_async(&mut server).await.unwrap();
+            (first, second, third)
+        };
+        let (_, (first, second, third)) = tokio::join!(writer, reader);
+        assert_eq!(failed, first);
+        assert_eq!(Packet::ReqDevList, second);
+        assert_eq!(failed, third);
+    }
+
+    #[tokio::test]
+    async fn test_urbs_async() {
+        let iso = IsoPacketDescriptor { offset: 0, length: 3, actual_length: 3, status: 0 };
+        let packets = vec![
+            Packet::CmdSubmit(CmdSubmitBuilder::new(Direction::Out, 2).seqnum(1).data(vec![1, 2, 3])
+                              .num_packets(1).iso_packets(vec![iso.clone()]).build()),
+            Packet::CmdSubmit(CmdSubmitBuilder::new(Direction::In, 1).seqnum(2).buffer_length(8).build()),
+            Packet::RetSubmit(RetSubmit { seqnum: 2, direction: Direction::In, ep: 1, length: 2,
+                                          data: Some(vec![0x12, 0x01]), ..Default::default() }),
+            Packet::RetSubmit(RetSubmit { seqnum: 1, ep: 2, length: 3, num_packets: 1, iso_packets: vec![iso],
+                                          ..Default::default() }),
+            Packet::CmdUnlink(CmdUnlink { seq: 3, devid: 0, direction: Direction::In, ep: 1, seqnum: 2 }),
+            Packet::RetUnlink(RetUnlink { seqnum: 3, status: -104i32 as u32, ..Default::default() }),
+        ];
+        let (mut client, mut server) = tokio::io::duplex(16);
+        let writer = async {
+            for pkt in &packets {
+                pkt.write_async(&mut client).await.unwrap();
+            }
+            drop(client);
+        };
+        let reader = async {
+            let mut read = Vec::new();
+            loop {
+                match Packet::read_async(&mut server).await {
+                    Ok(pkt) => read.push(pkt),
+                    Err(PacketError::ConnectionClosed) => return read,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        };
+        let (_, read) = tokio::join!(writer, reader);
+        assert_eq!(read, packets);
+    }
+
+    // Rejected as soon as the count is in, not after waiting for the devices
+    #[tokio::test]
+    async fn test_device_limit_async() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x01, 0x11, 0x00, 0x05, 0, 0, 0, 0]).await.unwrap();
+        client.write_all(&(DEFAULT_MAX_DEVICES + 1).to_be_bytes()).await.unwrap();
+        match Packet::read_async(&mut server).await {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "num_devices 1025 exceeds limit of 1024"),
+            x => panic!("Expected PacketError, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
+
+use crate::libusb::LibUsbProvider;
+use crate::protocol::{Packet, PacketEncoder, PacketError, PacketResult, CmdSubmit, RepDevList, RepImport,
+                      RetUnlink, UsbIpStatus};
+use crate::server::{DeviceProvider, OutstandingUrbs, error_reply};
+use crate::client::log_packet;
+
+const ECONNRESET: i32 = 104;
+const EPIPE: i32 = 32;
+
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+// The submits of a connection the server has not answered yet: running ones
+// are still being transferred on the blocking pool, outstanding ones were left
+// without a reply by DeviceProvider::submit.
+#[derive(Debug,Default)]
+struct Urbs {
+    running: HashSet<u32>,
+    outstanding: OutstandingUrbs,
+}
+
+// Async counterpart of UsbIpServer. Each connection gets a task of its own,
+// while the provider (libusb transfers are synchronous) runs on tokio's
+// blocking pool.
+pub struct AsyncUsbIpServer<P: DeviceProvider = LibUsbProvider> {
+    provider: Arc<P>,
+    max_in_flight: usize,
+}
+
+impl<P: DeviceProvider> Clone for AsyncUsbIpServer<P> {
+    fn clone(&self) -> AsyncUsbIpServer<P> {
+        AsyncUsbIpServer { provider: Arc::clone(&self.provider), max_in_flight: self.max_in_flight }
+    }
+}
+
+impl<P: DeviceProvider + 'static> AsyncUsbIpServer<P> {
+    pub fn new(provider: P) -> AsyncUsbIpServer<P> {
+        AsyncUsbIpServer { provider: Arc::new(provider), max_in_flight: DEFAULT_MAX_IN_FLIGHT }
+    }
+
+    // Most submits a connection has running at once. Once they are all busy
+    // (or their replies wait for the client to read them) the server stops
+    // reading from the socket, which pushes back on the client through TCP.
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    pub async fn serve<A: ToSocketAddrs>(&self, addr: A) -> PacketResult<()> {
+        self.serve_listener(TcpListener::bind(addr).await?).await
+    }
+
+    pub async fn serve_listener(&self, listener: TcpListener) -> PacketResult<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("Client connected from {:?}", addr);
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(stream).await {
+                    warn!("Connection closed with error: {}", e);
+                }
+            });
+        }
+    }
+
+    // Runs the op phase (devlist / import) until a device is imported, then
+    // switches to exchanging URBs until the client disconnects. The imported
+    // device is released when the connection ends, also with an error.
+    pub async fn handle<S>(&self, mut stream: S) -> PacketResult<()>
+        where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        loop {
+            let pkt = match Packet::read_async(&mut stream).await {
+                Ok(pkt) => pkt,
+                Err(PacketError::ConnectionClosed) => {
+                    debug!("Client disconnected");
+                    return Ok(());
+                },
+                Err(e) => return Err(e),
+            };
+            log_packet("Received", &pkt);
+            let mut imported = None;
+            let reply = match pkt {
+                Packet::ReqDevList => {
+                    let devices = self.blocking(|provider| provider.list()).await?;
+                    Packet::RepDevList(RepDevList {
+                        status: UsbIpStatus::Ok.as_u32(),
+                        num_devices: devices.len() as u32,
+                        devices
+                    })
+                },
+                Packet::ReqImport(req) => match self.blocking(move |provider| provider.import(&req.busid)).await? {
+                    Some(ri) => {
+                        if ri.status().is_ok() {
+                            imported = Some(ri.clone());
+                        }
+                        Packet::RepImport(ri)
+                    },
+                    None => Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice)),
+                },
+                p => match self.provider.unhandled(&p, false) {
+                    Some(reply) => reply,
+                    None => return Err(PacketError::PacketError(
+                        format!("Unexpected {:?} packet (device imported: false)", p.packet_type()))),
+                },
+            };
+            log_packet("Sent", &reply);
+            reply.write_async(&mut stream).await?;
+            stream.flush().await?;
+            if let Some(device) = imported {
+                let res = self.exchange_urbs(stream).await;
+                let released = self.blocking(move |provider| provider.release(&device)).await;
+                return res.and(released);
+            }
+        }
+    }
+
+    // The socket is read here and written by a task of its own, which gets
+    // the replies through a channel as the transfers complete (in any order).
+    // A submit only gets read once a transfer slot is free, and it holds that
+    // slot until its reply is in the channel, so neither the blocking pool nor
+    // the replies pile up without bound.
+    async fn exchange_urbs<S>(&self, stream: S) -> PacketResult<()>
+        where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        let (mut rd, mut wr) = io::split(stream);
+        let (replies, mut rx) = mpsc::channel::<Packet>(self.max_in_flight);
+        let writer = tokio::spawn(async move {
+            let mut encoder = PacketEncoder::new();
+            while let Some(reply) = rx.recv().await {
+                log_packet("Sent", &reply);
+                wr.write_all(encoder.encode(&reply)?).await?;
+                wr.flush().await?;
+            }
+            Ok::<(), PacketError>(())
+        });
+        let slots = Arc::new(Semaphore::new(self.max_in_flight));
+        let urbs = Arc::new(Mutex::new(Urbs::default()));
+        let res = loop {
+            let pkt = match Packet::read_async(&mut rd).await {
+                Ok(pkt) => pkt,
+                Err(PacketError::ConnectionClosed) => {
+                    debug!("Client disconnected");
+                    break Ok(());
+                },
+                Err(e) => break Err(e),
+            };
+            log_packet("Received", &pkt);
+            let reply = match pkt {
+                Packet::CmdSubmit(cmd) => {
+                    let slot = Arc::clone(&slots).acquire_owned().await.expect("Semaphore is never closed");
+                    urbs.lock().unwrap().running.insert(cmd.seqnum);
+                    let provider = Arc::clone(&self.provider);
+                    let urbs = Arc::clone(&urbs);
+                    let replies = replies.clone();
+                    tokio::spawn(async move {
+                        if let Some(ret) = transfer(provider, cmd, &urbs).await {
+                            // Only fails once the writer is gone, which ends the connection anyway
+                            let _ = replies.send(ret).await;
+                        }
+                        drop(slot);
+                    });
+                    continue;
+                },
+                // -ECONNRESET if the URB was cancelled, 0 if it had already completed,
+                // as with UsbIpServer. A running transfer can not be stopped, its
+                // reply is dropped instead. The provider hears of an outstanding URB
+                // being cancelled on the blocking pool, once the lock is given up.
+                Packet::CmdUnlink(cmd) => {
+                    let (running, outstanding) = {
+                        let mut urbs = urbs.lock().unwrap();
+                        (urbs.running.remove(&cmd.seqnum), urbs.outstanding.remove(cmd.seqnum))
+                    };
+                    if let Some(urb) = outstanding {
+                        let seqnum = cmd.seqnum;
+                        if let Err(e) = self.blocking(move |provider| provider.unlink(seqnum, &urb)).await {
+                            warn!("Unlink of {} failed: {}", seqnum, e);
+                        }
+                    }
+                    let status = if running || outstanding.is_some() { -ECONNRESET as u32 } else { 0 };
+                    Packet::RetUnlink(RetUnlink {
+                        seqnum: cmd.seq, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep, status
+                    })
+                },
+                p => match self.provider.unhandled(&p, true) {
+                    Some(reply) => reply,
+                    None => break Err(PacketError::PacketError(
+                        format!("Unexpected {:?} packet (device imported: true)", p.packet_type()))),
+                },
+            };
+            if replies.send(reply).await.is_err() {
+                break Ok(());
+            }
+        };
+        // The writer finishes once the last running transfer dropped its sender
+        drop(replies);
+        let written = writer.await.map_err(join_error)?;
+        res.and(written)
+    }
+
+    async fn blocking<F, T>(&self, f: F) -> PacketResult<T>
+        where F: FnOnce(&P) -> T + Send + 'static, T: Send + 'static
+    {
+        let provider = Arc::clone(&self.provider);
+        task::spawn_blocking(move || f(&provider)).await.map_err(join_error)
+    }
+}
+
+// Runs a submit on the blocking pool. None if there is nothing to send (yet),
+// because the provider left the URB outstanding or it was unlinked meanwhile.
+// A provider that panics fails the URB with -EPIPE, so the client is not left
+// waiting for it.
+async fn transfer<P: DeviceProvider + 'static>(provider: Arc<P>, cmd: CmdSubmit, urbs: &Mutex<Urbs>) -> Option<Packet> {
+    let seqnum = cmd.seqnum;
+    // What error_reply needs of the submit, which moves to the blocking pool
+    let header = CmdSubmit {
+        seqnum, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep, setup: cmd.setup, ..Default::default()
+    };
+    let res = task::spawn_blocking(move || {
+        let ret = provider.submit(&cmd);
+        (cmd, ret)
+    }).await;
+    let mut urbs = urbs.lock().unwrap();
+    if !urbs.running.remove(&seqnum) {
+        return None;
+    }
+    match res {
+        Ok((_, Some(ret))) => Some(Packet::RetSubmit(ret)),
+        Ok((cmd, None)) => {
+            urbs.outstanding.insert(&cmd);
+            None
+        },
+        Err(e) => {
+            warn!("Submit {} failed: {}", seqnum, e);
+            error_reply(&Packet::CmdSubmit(header), EPIPE)
+        },
+    }
+}
+
+fn join_error(e: task::JoinError) -> PacketError {
+    PacketError::PacketError(format!("Provider task failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::async_server::AsyncUsbIpServer;
+    use crate::protocol::{Packet, CmdSubmit, CmdSubmitBuilder, CmdUnlink, DeviceDescriptor, RepImport,
+                          ReqImport, RetSubmit, Direction, UsbIpStatus};
+    use crate::server::{DeviceProvider, PendingUrb};
+
+    struct StubDevice;
+
+    impl StubDevice {
+        fn descriptor() -> DeviceDescriptor {
+            DeviceDescriptor {
+                path: "/sys/bus/usb/devices/1-1".to_string(),
+                busid: "1-1".to_string(),
+                busnum: 1,
+                devnum: 2,
+                speed: 2,
+                id_vendor: 0xaffe,
+                id_product: 0xbeef,
+                bcd_device: 0x0100,
+                device_class: 0,
+                device_subclass: 0,
+                device_protocol: 0,
+                configuration_value: 1,
+                num_configurations: 1,
+                num_interfaces: 0,
+                interfaces: vec![]
+            }
+        }
+    }
+
+    impl DeviceProvider for StubDevice {
+        fn list(&self) -> Vec<DeviceDescriptor> {
+            vec![StubDevice::descriptor()]
+        }
+
+        fn import(&self, busid: &str) -> Option<RepImport> {
+            if busid == "1-1" {
+                Some(RepImport::from_device(&StubDevice::descriptor(), UsbIpStatus::Ok.as_u32()))
+            } else {
+                None
+            }
+        }
+
+        fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+            RetSubmit {
+                seqnum: cmd.seqnum,
+                devid: cmd.devid,
+                direction: cmd.direction,
+                ep: cmd.ep,
+                status: 0,
+                length: 4,
+                start_frame: 0,
+                num_packets: 0,
+                error_count: 0,
+                setup: cmd.setup,
+                data: Some(vec![1, 2, 3, 4]),
+                iso_packets: vec![]
+            }
+        }
+    }
+
+    struct PanickingDevice;
+
+    impl DeviceProvider for PanickingDevice {
+        fn list(&self) -> Vec<DeviceDescriptor> {
+            StubDevice.list()
+        }
+
+        fn import(&self, busid: &str) -> Option<RepImport> {
+            StubDevice.import(busid)
+        }
+
+        fn handle_submit(&self, _cmd: &CmdSubmit) -> RetSubmit {
+            panic!("Device gone");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_server_provider_panics() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let srv = tokio::spawn(async move {
+            AsyncUsbIpServer::new(PanickingDevice).handle(server).await
+        });
+
+        Packet::ReqImport(ReqImport { busid: "1-1".to_string() }).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RepImport(ri) => assert!(ri.status().is_ok()),
+            p => panic!("Expected RepImport, got {:?}", p),
+        }
+        for seqnum in 1..3 {
+            let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(seqnum).buffer_length(4).build();
+            Packet::CmdSubmit(cmd).write_async(&mut client).await.unwrap();
+            match Packet::read_async(&mut client).await.unwrap() {
+                Packet::RetSubmit(ret) => {
+                    assert_eq!(ret.seqnum, seqnum);
+                    assert_eq!(ret.status, -32i32 as u32);
+                    assert_eq!(ret.data, Some(vec![]));
+                },
+                p => panic!("Expected RetSubmit, got {:?}", p),
+            }
+        }
+
+        drop(client);
+        srv.await.unwrap().unwrap();
+    }
+
+    // Leaves submit 5 outstanding and records the unlink of it
+    struct PendingDevice {
+        unlinked: AtomicU32,
+    }
+
+    impl DeviceProvider for PendingDevice {
+        fn list(&self) -> Vec<DeviceDescriptor> {
+            StubDevice.list()
+        }
+
+        fn import(&self, busid: &str) -> Option<RepImport> {
+            StubDevice.import(busid)
+        }
+
+        fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+            StubDevice.handle_submit(cmd)
+        }
+
+        fn submit(&self, cmd: &CmdSubmit) -> Option<RetSubmit> {
+            if cmd.seqnum == 5 { None } else { Some(self.handle_submit(cmd)) }
+        }
+
+        fn unlink(&self, seqnum: u32, _urb: &PendingUrb) {
+            self.unlinked.store(seqnum, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_server_unlink_outstanding() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut srv = AsyncUsbIpServer::new(PendingDevice { unlinked: AtomicU32::new(0) });
+        // Submit 6 is only read once submit 5 has freed its slot, so its reply
+        // means 5 has been left outstanding
+        srv.set_max_in_flight(1);
+        let handler = srv.clone();
+        let handler = tokio::spawn(async move { handler.handle(server).await });
+
+        Packet::ReqImport(ReqImport { busid: "1-1".to_string() }).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RepImport(ri) => assert!(ri.status().is_ok()),
+            p => panic!("Expected RepImport, got {:?}", p),
+        }
+        for seqnum in 5..7 {
+            let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(seqnum).buffer_length(4).build();
+            Packet::CmdSubmit(cmd).write_async(&mut client).await.unwrap();
+        }
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RetSubmit(ret) => assert_eq!(ret.seqnum, 6),
+            p => panic!("Expected RetSubmit, got {:?}", p),
+        }
+        let unlink = CmdUnlink { seq: 7, devid: 0, direction: Direction::In, ep: 1, seqnum: 5 };
+        Packet::CmdUnlink(unlink).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RetUnlink(ret) => assert_eq!((ret.seqnum, ret.status), (7, -104i32 as u32)),
+            p => panic!("Expected RetUnlink, got {:?}", p),
+        }
+        assert_eq!(srv.provider().unlinked.load(Ordering::SeqCst), 5);
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_server_handshake() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let srv = tokio::spawn(async move {
+            AsyncUsbIpServer::new(StubDevice).handle(server).await
+        });
+
+        Packet::ReqDevList.write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RepDevList(dl) => assert_eq!(dl.devices, vec![StubDevice::descriptor()]),
+            p => panic!("Expected RepDevList, got {:?}", p),
+        }
+
+        Packet::ReqImport(ReqImport { busid: "2-1".to_string() }).write_async(&mut client).await.unwrap();
+        assert_eq!(Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice)),
+                   Packet::read_async(&mut client).await.unwrap());
+        Packet::ReqImport(ReqImport { busid: "1-1".to_string() }).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RepImport(ri) => assert!(ri.status().is_ok()),
+            p => panic!("Expected RepImport, got {:?}", p),
+        }
+
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(7).buffer_length(4).build();
+        Packet::CmdSubmit(cmd).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            Packet::RetSubmit(ret) => {
+                assert_eq!(ret.seqnum, 7);
+                assert_eq!(ret.data, Some(vec![1, 2, 3, 4]));
+            },
+            p => panic!("Expected RetSubmit, got {:?}", p),
+        }
+
+        let unlink = CmdUnlink { seq: 8, devid: 0, direction: Direction::In, ep: 1, seqnum: 7 };
+        Packet::CmdUnlink(unlink).write_async(&mut client).await.unwrap();
+        match Packet::read_async(&mut client).await.unwrap() {
+            // The submit was answered before
+            Packet::RetUnlink(ret) => assert_eq!(ret.status, 0),
+            p => panic!("Expected RetUnlink, got {:?}", p),
+        }
+
+        drop(client);
+        srv.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use bufstream::BufStream;
+
+use crate::protocol::{Packet, PacketError, PacketResult, PacketHeader, DeviceDescriptor, RepImport, ReqImport,
+                      CmdSubmit, CmdUnlink, ImportError, BusId, Direction, ReadLimits, read_op_header};
+use crate::spans;
+
+// Talks to a USB/IP server over any byte stream. TCP is the default, other
+// transports (e.g. crate::memory for tests) go through new.
+pub struct UsbIpClient<S: Read + Write = BufStream<TcpStream>> {
+    addr: Option<SocketAddr>,
+    stream: S,
+}
+
+impl UsbIpClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> PacketResult<UsbIpClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(UsbIpClient { addr: Some(stream.peer_addr()?), stream: BufStream::new(stream) })
+    }
+
+    // A connection can only import a single device, so importing further
+    // busids from the same server needs a fresh connection.
+    pub fn reconnect(&self) -> PacketResult<UsbIpClient> {
+        match self.addr {
+            Some(addr) => UsbIpClient::connect(addr),
+            None => UsbIpClient::connect(self.stream.get_ref().peer_addr()?),
+        }
+    }
+
+    // Applies to every read and write on the connection, a server that does
+    // not answer in time then fails the request with PacketError::Timeout.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> PacketResult<()> {
+        set_timeout(self.stream.get_ref(), timeout)
+    }
+
+    // Shuts the connection down both ways, so the server sees the end of
+    // stream right away instead of whenever the socket gets dropped. Every
+    // request is flushed as it is sent, so there is nothing left to write.
+    pub fn close(mut self) -> PacketResult<()> {
+        self.stream.flush()?;
+        shutdown(self.stream.get_ref())
+    }
+}
+
+impl<S: Read + Write> UsbIpClient<S> {
+    pub fn new(stream: S) -> UsbIpClient<S> {
+        UsbIpClient { addr: None, stream }
+    }
+
+    pub fn list_devices(&mut self) -> PacketResult<Vec<DeviceDescriptor>> {
+        match self.request(Packet::ReqDevList)? {
+            Packet::RepDevList(dl) => Ok(dl.devices),
+            p => Err(unexpected_reply(&p)),
+        }
+    }
+
+    // On success the connection leaves the op phase and only carries URBs from
+    // then on, so the client is consumed and handed over to the ImportedDevice.
+    // A malformed busid fails before anything is sent.
+    pub fn import(mut self, busid: &str) -> PacketResult<ImportedDevice<S>> {
+        let busid: BusId = busid.parse()?;
+        let req = Packet::ReqImport(ReqImport { busid: busid.as_str().to_string() });
+        match self.request(req)? {
+            Packet::RepImport(info) => match ImportError::from_status(info.status()) {
+                Some(e) => Err(PacketError::ImportFailed(e)),
+                None => Ok(ImportedDevice {
+                    info, stream: self.stream, seqnums: SeqnumAllocator::new(),
+                    directions: HashMap::new(), unlinks: HashMap::new()
+                }),
+            },
+            p => Err(unexpected_reply(&p)),
+        }
+    }
+
+    // The reply's header is checked before anything else of it is decoded, so
+    // a server speaking another protocol version (or a URB packet, version 0)
+    // fails the request with UnsupportedVersion and the header it sent.
+    fn request(&mut self, pkt: Packet) -> PacketResult<Packet> {
+        let _span = spans::handshake(&pkt);
+        send(&mut self.stream, &pkt)?;
+        let header = read_op_header(&mut self.stream)?;
+        if header >> 16 == 0 {
+            return Err(PacketError::UnsupportedVersion(PacketHeader::from_u32(header)));
+        }
+        let header = header.to_be_bytes();
+        let reply = Packet::read(&mut (&header[..]).chain(&mut self.stream))?;
+        log_packet("Received", &reply);
+        Ok(reply)
+    }
+}
+
+// Hands out the seqnums of a connection: 1, 2, ... and after u32::MAX back
+// to 1, skipping 0 which stands for "not assigned yet". Safe to share between
+// threads submitting on the same device.
+#[derive(Debug)]
+pub struct SeqnumAllocator {
+    next: AtomicU32,
+}
+
+impl Default for SeqnumAllocator {
+    fn default() -> SeqnumAllocator {
+        SeqnumAllocator::new()
+    }
+}
+
+impl SeqnumAllocator {
+    pub fn new() -> SeqnumAllocator {
+        SeqnumAllocator::starting_at(1)
+    }
+
+    pub fn starting_at(seqnum: u32) -> SeqnumAllocator {
+        SeqnumAllocator { next: AtomicU32::new(seqnum) }
+    }
+
+    pub fn next(&self) -> u32 {
+        loop {
+            // fetch_add wraps around, the 0 it passes through is skipped
+            let seqnum = self.next.fetch_add(1, Ordering::Relaxed);
+            if seqnum != 0 {
+                return seqnum;
+            }
+        }
+    }
+}
+
+pub struct ImportedDevice<S: Read + Write = BufStream<TcpStream>> {
+    info: RepImport,
+    stream: S,
+    seqnums: SeqnumAllocator,
+    // Direction of every submit not answered yet, by seqnum, as the replies
+    // of the Linux stub do not tell (see Packet::read_reply)
+    directions: HashMap<u32, Direction>,
+    // Seqnum of the submit each outstanding unlink is for, by its seq
+    unlinks: HashMap<u32, u32>,
+}
+
+impl<S: Read + Write> ImportedDevice<S> {
+    pub fn info(&self) -> &RepImport {
+        &self.info
+    }
+
+    // A seqnum of 0 (the CmdSubmitBuilder default) is replaced by the next
+    // one from the device's SeqnumAllocator, others are sent as they are.
+    // Returns the seqnum the reply will carry.
+    pub fn submit(&mut self, mut cmd: CmdSubmit) -> PacketResult<u32> {
+        let seqnum = self.assign(&mut cmd.seqnum);
+        self.directions.insert(seqnum, cmd.direction);
+        send(&mut self.stream, &Packet::CmdSubmit(cmd))?;
+        Ok(seqnum)
+    }
+
+    // Like submit, but leaves the packet in the write buffer so that a batch
+    // of submits goes out together. Nothing is sent before the next flush or
+    // submit, so a reply to a queued submit must not be waited for until then.
+    pub fn queue(&mut self, mut cmd: CmdSubmit) -> PacketResult<u32> {
+        let seqnum = self.assign(&mut cmd.seqnum);
+        self.directions.insert(seqnum, cmd.direction);
+        let pkt = Packet::CmdSubmit(cmd);
+        log_packet("Queued", &pkt);
+        pkt.write(&mut self.stream)?;
+        Ok(seqnum)
+    }
+
+    pub fn flush(&mut self) -> PacketResult<()> {
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    // The reply is a RetUnlink, read like any other by read_reply. Its own
+    // seq is assigned like a submit's.
+    pub fn unlink(&mut self, mut cmd: CmdUnlink) -> PacketResult<u32> {
+        let seq = self.assign(&mut cmd.seq);
+        self.unlinks.insert(seq, cmd.seqnum);
+        send(&mut self.stream, &Packet::CmdUnlink(cmd))?;
+        Ok(seq)
+    }
+
+    pub fn seqnums(&self) -> &SeqnumAllocator {
+        &self.seqnums
+    }
+
+    fn assign(&self, seqnum: &mut u32) -> u32 {
+        if *seqnum == 0 {
+            *seqnum = self.seqnums.next();
+        }
+        *seqnum
+    }
+
+    // A RetSubmit is decoded with the direction of its submit, whatever its
+    // header says. An URB that an unlink cancelled gets no RetSubmit, only a
+    // RetUnlink with a nonzero status.
+    pub fn read_reply(&mut self) -> PacketResult<Packet> {
+        let directions = &mut self.directions;
+        let reply = Packet::read_reply(&mut self.stream, &ReadLimits::default(), |seqnum| directions.remove(&seqnum))?;
+        log_packet("Received", &reply);
+        if let Packet::RetUnlink(ref ret) = reply {
+            if let Some(target) = self.unlinks.remove(&ret.seqnum) {
+                if ret.status != 0 {
+                    self.directions.remove(&target);
+                }
+            }
+        }
+        match reply {
+            p @ Packet::RetSubmit(_) | p @ Packet::RetUnlink(_) => Ok(p),
+            p => Err(unexpected_reply(&p)),
+        }
+    }
+}
+
+// Queued submits still in the write buffer go out before the connection is
+// dropped, as far as the peer takes them
+impl<S: Read + Write> Drop for ImportedDevice<S> {
+    fn drop(&mut self) {
+        let _ = self.stream.flush();
+    }
+}
+
+impl ImportedDevice {
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> PacketResult<()> {
+        set_timeout(self.stream.get_ref(), timeout)
+    }
+
+    // Like UsbIpClient::close, after sending what was queued. Replies still
+    // outstanding are never read, the server sees its URBs end with the
+    // connection.
+    pub fn close(mut self) -> PacketResult<()> {
+        self.stream.flush()?;
+        shutdown(self.stream.get_ref())
+    }
+}
+
+// The socket of an imported device is what gets handed to vhci_hcd
+#[cfg(unix)]
+impl AsRawFd for ImportedDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.get_ref().as_raw_fd()
+    }
+}
+
+// One debug line for every packet sent or received by the client and the
+// servers, to follow a handshake that goes wrong. Trace adds the bytes.
+pub(crate) fn log_packet(what: &str, pkt: &Packet) {
+    debug!("{}: {}", what, pkt);
+    if log_enabled!(log::Level::Trace) {
+        if let Ok(hex) = pkt.to_hex() {
+            trace!("{} bytes: {}", what, hex);
+        }
+    }
+}
+
+// Writes a whole packet and pushes it out of any write buffer, as the peer
+// will not answer (or go on) before it has seen all of it.
+pub(crate) fn send<S: Write>(stream: &mut S, pkt: &Packet) -> PacketResult<()> {
+    log_packet("Sent", pkt);
+    pkt.write(stream)?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub(crate) fn set_timeout(stream: &TcpStream, timeout: Option<Duration>) -> PacketResult<()> {
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    Ok(())
+}
+
+// A peer that hung up first leaves nothing to shut down
+fn shutdown(stream: &TcpStream) -> PacketResult<()> {
+    match stream.shutdown(Shutdown::Both) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+fn unexpected_reply(pkt: &Packet) -> PacketError {
+    PacketError::PacketError(format!("Unexpected reply: {:?}", pkt.packet_type()))
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::protocol::Packet;
+
+// Fans the URB packets of a connection carrying several devices (e.g. a
+// multi-device proxy) out to one channel per devid. Each handler reads its
+// packets from the Receiver it got from add, in the order they came in.
+#[derive(Debug,Default)]
+pub struct DevidRouter {
+    routes: HashMap<u32, Sender<Packet>>,
+}
+
+impl DevidRouter {
+    pub fn new() -> DevidRouter {
+        DevidRouter::default()
+    }
+
+    // Replaces an earlier route for devid, whose handler then sees its
+    // channel close
+    pub fn add(&mut self, devid: u32) -> Receiver<Packet> {
+        let (tx, rx) = channel();
+        self.routes.insert(devid, tx);
+        rx
+    }
+
+    pub fn remove(&mut self, devid: u32) -> bool {
+        self.routes.remove(&devid).is_some()
+    }
+
+    pub fn contains(&self, devid: u32) -> bool {
+        self.routes.contains_key(&devid)
+    }
+
+    // Hands back what no handler took: op packets, devids without a route and
+    // packets for a handler that has gone away (whose route is dropped).
+    pub fn route(&mut self, pkt: Packet) -> Option<Packet> {
+        let devid = match pkt.devid() {
+            Some(devid) => devid,
+            None => return Some(pkt),
+        };
+        let res = match self.routes.get(&devid) {
+            Some(tx) => tx.send(pkt),
+            None => return Some(pkt),
+        };
+        match res {
+            Ok(()) => None,
+            Err(e) => {
+                self.routes.remove(&devid);
+                Some(e.0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use crate::demux::DevidRouter;
+    use crate::protocol::{Packet, CmdSubmitBuilder, CmdUnlink, Direction, devid};
+    use crate::stream::PacketStream;
+
+    #[test]
+    fn test_route_by_devid() {
+        let (a, b) = (devid(1, 2), devid(1, 3));
+        let mut recording = Vec::new();
+        Packet::ReqDevList.write(&mut recording).unwrap();
+        for seqnum in 1..=4 {
+            let dev = if seqnum % 2 == 0 { b } else { a };
+            let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(seqnum).devid(dev).buffer_length(8).build();
+            Packet::CmdSubmit(cmd).write(&mut recording).unwrap();
+        }
+        let unlink = CmdUnlink { seq: 5, devid: a, direction: Direction::In, ep: 1, seqnum: 1 };
+        Packet::CmdUnlink(unlink).write(&mut recording).unwrap();
+        let stray = CmdSubmitBuilder::new(Direction::Out, 2).seqnum(6).devid(devid(2, 1)).data(vec![1]).build();
+        Packet::CmdSubmit(stray.clone()).write(&mut recording).unwrap();
+
+        let mut router = DevidRouter::new();
+        let rx_a = router.add(a);
+        let rx_b = router.add(b);
+        let mut unrouted = Vec::new();
+        for pkt in PacketStream::new(recording.as_slice()) {
+            unrouted.extend(router.route(pkt.unwrap()));
+        }
+        assert_eq!(unrouted, vec![Packet::ReqDevList, Packet::CmdSubmit(stray)]);
+
+        let received = |rx: &Receiver<Packet>| rx.try_iter().map(|p| match p {
+            Packet::CmdSubmit(cmd) => (cmd.devid, cmd.seqnum),
+            Packet::CmdUnlink(cmd) => (cmd.devid, cmd.seq),
+            p => panic!("Unexpected {:?}", p),
+        }).collect::<Vec<(u32, u32)>>();
+        assert_eq!(received(&rx_a), vec![(a, 1), (a, 3), (a, 5)]);
+        assert_eq!(received(&rx_b), vec![(b, 2), (b, 4)]);
+
+        drop(rx_b);
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(7).devid(b).build();
+        assert_eq!(router.route(Packet::CmdSubmit(cmd.clone())), Some(Packet::CmdSubmit(cmd)));
+        assert!(!router.contains(b));
+        assert!(router.contains(a));
+    }
+}
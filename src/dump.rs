@@ -0,0 +1,173 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::format;
+use core::fmt::Write;
+
+use crate::protocol::{Packet, PacketRef, PacketError, PacketResult};
+use crate::stream::PacketStream;
+
+// Helpers for looking at captured traffic, e.g. a Wireshark "Copy as Hex
+// Stream" of a USB/IP TCP payload.
+impl Packet {
+    // Hex digits may be contiguous or separated by whitespace, but each byte
+    // has to be written as two digits.
+    pub fn from_hex(hex: &str) -> PacketResult<Packet> {
+        let bytes = parse_hex(hex)?;
+        let mut src = bytes.as_slice();
+        let pkt = Packet::read(&mut src)?;
+        if !src.is_empty() {
+            return Err(PacketError::PacketError(format!("{} bytes left after packet", src.len())));
+        }
+        Ok(pkt)
+    }
+
+    pub fn to_hex(&self) -> PacketResult<String> {
+        let buf = self.write_to_vec()?;
+        let mut hex = String::with_capacity(2 * buf.len());
+        for b in buf {
+            write!(hex, "{:02x}", b).unwrap();
+        }
+        Ok(hex)
+    }
+}
+
+// Decodes all packets in a capture. A packet cut off at the end of the
+// capture is reported as an error rather than silently dropped.
+pub fn decode_all(bytes: &[u8]) -> PacketResult<Vec<Packet>> {
+    PacketStream::new(bytes).collect()
+}
+
+// Checks that every packet of a capture encodes back to the very bytes it was
+// decoded from. Returns the number of packets, or the offset of the first one
+// that fails to decode or comes out different.
+pub fn verify_roundtrip(bytes: &[u8]) -> Result<usize, (usize, PacketError)> {
+    let mut src = bytes;
+    let mut count = 0;
+    while !src.is_empty() {
+        let offset = bytes.len() - src.len();
+        let pkt = Packet::read(&mut src).map_err(|e| (offset, e))?;
+        let original = &bytes[offset..bytes.len() - src.len()];
+        let encoded = pkt.write_to_vec().map_err(|e| (offset, e))?;
+        if encoded != original {
+            let pos = encoded.iter().zip(original).position(|(a, b)| a != b)
+                .unwrap_or_else(|| encoded.len().min(original.len()));
+            return Err((offset, PacketError::PacketError(
+                format!("{:?} re-encodes differently from byte {} on", pkt.packet_type(), pos))));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Entry point for fuzzers. Decodes data as a capture, with the owned and the
+// borrowing reader in lockstep, and panics if they disagree or if a decoded
+// packet that can be encoded again does not decode to itself.
+pub fn fuzz_decode(data: &[u8]) {
+    let mut owned = data;
+    let mut borrowed = data;
+    let mut buf = Vec::new();
+    loop {
+        let pkt = match Packet::read(&mut owned) {
+            Ok(pkt) => pkt,
+            Err(_) => {
+                assert!(Packet::read_into(&mut borrowed, &mut buf).is_err());
+                return;
+            },
+        };
+        let view = Packet::read_into(&mut borrowed, &mut buf).map(PacketRef::into_owned);
+        assert_eq!(view.ok().as_ref(), Some(&pkt));
+        assert_eq!(owned.len(), borrowed.len());
+        if let Ok(bytes) = pkt.write_to_vec() {
+            assert_eq!(bytes.len(), pkt.encoded_len());
+            assert_eq!(Packet::from_slice(&bytes).ok(), Some(pkt));
+        }
+    }
+}
+
+pub fn parse_hex(hex: &str) -> PacketResult<Vec<u8>> {
+    let digits: Vec<u8> = hex.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let pairs = digits.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(PacketError::PacketError("Odd number of hex digits".to_string()));
+    }
+    pairs.map(|pair| {
+        match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(hi), Some(lo)) => Ok(hi << 4 | lo),
+            _ => Err(PacketError::PacketError(
+                format!("Invalid hex byte: {:?}", String::from_utf8_lossy(pair)))),
+        }
+    }).collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{decode_all, fuzz_decode, parse_hex, verify_roundtrip};
+    use crate::protocol::{Packet, PacketError, PacketHeader, ReqImport};
+
+    #[test]
+    fn test_req_device_list_hex() {
+        assert_eq!(Packet::from_hex("0111800500000000").unwrap(), Packet::ReqDevList);
+        assert_eq!(Packet::from_hex("01 11 80 05\n00 00 00 00").unwrap(), Packet::ReqDevList);
+        assert_eq!(Packet::ReqDevList.to_hex().unwrap(), "0111800500000000");
+        assert!(Packet::from_hex("01118005000000").is_err());
+        assert!(Packet::from_hex("011180050000000000").is_err());
+        match Packet::from_hex("0111800500000g00") {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Invalid hex byte: \"0g\""),
+            x => panic!("Expected hex error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let import = Packet::ReqImport(ReqImport { busid: "3-2".to_string() });
+        let capture = format!("{} {}", Packet::ReqDevList.to_hex().unwrap(), import.to_hex().unwrap());
+        let bytes = parse_hex(&capture).unwrap();
+        assert_eq!(decode_all(&bytes).unwrap(), vec![Packet::ReqDevList, import]);
+        assert!(decode_all(&bytes[..bytes.len() - 1]).is_err());
+        assert_eq!(decode_all(&[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let mut bytes = Packet::ReqDevList.write_to_vec().unwrap();
+        bytes.extend(Packet::ReqImport(ReqImport { busid: "3-2".to_string() }).write_to_vec().unwrap());
+        assert_eq!(verify_roundtrip(&bytes).unwrap(), 2);
+
+        // Garbage after the NUL of the busid is dropped on decode
+        bytes[8 + 8 + 5] = b'x';
+        match verify_roundtrip(&bytes) {
+            Err((8, PacketError::PacketError(ref msg))) => assert_eq!(msg, "ReqImport re-encodes differently from byte 13 on"),
+            x => panic!("Expected mismatch, got {:?}", x)
+        }
+        bytes[8] = 0xff;
+        match verify_roundtrip(&bytes) {
+            Err((8, PacketError::UnsupportedVersion(PacketHeader { version: 0xff11, .. }))) => (),
+            x => panic!("Expected UnsupportedVersion, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_fuzz_decode() {
+        let import = Packet::ReqImport(ReqImport { busid: "3-2".to_string() }).to_hex().unwrap();
+        let inputs = [
+            "",
+            "0111",
+            // RepDevList claiming 1024 devices, followed by nothing
+            "01110005 00000000 00000400",
+            // CmdSubmit OUT claiming a 16 MiB payload and 2^32 - 1 iso packets
+            "00000001 00000001 00010002 00000000 00000001 00000000 01000000 00000000 ffffffff 00000000
+             0000000000000000 01020304",
+            // RepImport failure without the padding
+            "01110003 00000001",
+            // Busid filling all 32 bytes without a terminating NUL
+            "01118003 00000000 4141414141414141414141414141414141414141414141414141414141414141",
+        ];
+        for input in inputs.iter().chain([import.as_str()].iter()) {
+            fuzz_decode(&parse_hex(input).unwrap());
+        }
+    }
+}
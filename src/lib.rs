@@ -1,6 +1,38 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 extern crate byteorder;
+#[cfg(feature = "std")]
+extern crate bufstream;
 #[macro_use] extern crate bitflags;
-#[macro_use] extern crate enum_primitive;
-extern crate num;
+#[macro_use] extern crate log;
+
+pub mod protocol;
+pub mod util;
+pub mod stream;
+pub mod dump;
+mod spans;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod demux;
+#[cfg(feature = "std")]
+pub mod proxy;
+#[cfg(feature = "linux")]
+pub mod vhci;
+#[cfg(feature = "libusb")]
+pub mod libusb;
+#[cfg(feature = "tokio")]
+mod async_io;
+#[cfg(all(feature = "tokio", feature = "libusb"))]
+pub mod async_server;
+#[cfg(all(feature = "std", any(test, feature = "test-util")))]
+pub mod test_util;
 
-pub mod protocol;
\ No newline at end of file
+pub use util::{read_fix_string, write_fix_string, read_fix_ascii, write_fix_ascii};
\ No newline at end of file
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusb::{ffi, Device, DeviceHandle, GlobalContext, Speed, UsbContext};
+
+use crate::protocol::{PacketError, PacketResult, DeviceDescriptor, InterfaceDescriptor, RepImport,
+                      CmdSubmit, RetSubmit, Direction, TransferType, UsbIpStatus, UsbSpeed, devid};
+use crate::server::DeviceProvider;
+
+const EIO: i32 = 5;
+const ENODEV: i32 = 19;
+const EINVAL: i32 = 22;
+const EPIPE: i32 = 32;
+const EPROTO: i32 = 71;
+const EOVERFLOW: i32 = 75;
+const EOPNOTSUPP: i32 = 95;
+const ETIMEDOUT: i32 = 110;
+const USB_CLASS_HUB: u8 = 0x09;
+
+impl From<rusb::Error> for PacketError {
+    fn from(error: rusb::Error) -> Self {
+        PacketError::PacketError(format!("libusb: {}", error))
+    }
+}
+
+// The transfers a submit turns into. rusb's DeviceHandle provides them under
+// the same names, tests put a mock in its place.
+pub trait Transfers {
+    fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                    buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                     buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl<T: UsbContext> Transfers for DeviceHandle<T> {
+    fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                    buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                     buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_interrupt(self, endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_interrupt(self, endpoint, buf, timeout)
+    }
+}
+
+// Exports the USB devices attached to this machine, as enumerated by libusb.
+// Hubs are left out like usbipd does, they cannot be bound to usbip-host.
+// Importing a device opens it and claims its interfaces (detaching kernel
+// drivers), its URBs are then run as synchronous libusb transfers. Once the
+// connection ends the interfaces are released, which lets libusb reattach the
+// kernel drivers.
+pub struct LibUsbProvider<T: UsbContext = GlobalContext> {
+    context: T,
+    timeout: Duration,
+    handles: Mutex<HashMap<u32, Claimed<T>>>,
+}
+
+// An imported device, the interfaces claimed on it and the number of
+// connections that imported it and have not ended yet
+struct Claimed<T: UsbContext> {
+    handle: Arc<DeviceHandle<T>>,
+    interfaces: Vec<u8>,
+    connections: usize,
+}
+
+impl<T: UsbContext> Claimed<T> {
+    // Transfers still holding the handle keep it open, but with the
+    // interfaces gone they fail
+    fn release(self) {
+        for &interface in &self.interfaces {
+            if let Err(e) = self.handle.release_interface(interface) {
+                warn!("Releasing interface {} failed: {}", interface, e);
+            }
+        }
+    }
+}
+
+impl LibUsbProvider {
+    pub fn new() -> LibUsbProvider {
+        LibUsbProvider::with_context(GlobalContext::default())
+    }
+}
+
+impl Default for LibUsbProvider {
+    fn default() -> LibUsbProvider {
+        LibUsbProvider::new()
+    }
+}
+
+impl<T: UsbContext> LibUsbProvider<T> {
+    pub fn with_context(context: T) -> LibUsbProvider<T> {
+        LibUsbProvider { context, timeout: Duration::from_secs(0), handles: Mutex::new(HashMap::new()) }
+    }
+
+    // Limit for a single transfer, zero (the default) waits forever like
+    // usbip-host. A transfer blocks the connection it came in on, so without
+    // a limit an interrupt IN on a quiet device holds up all later URBs.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn devices(&self) -> PacketResult<Vec<DeviceDescriptor>> {
+        let mut devices = Vec::new();
+        for device in self.context.devices()?.iter() {
+            let dev = describe(&device)?;
+            if dev.device_class != USB_CLASS_HUB {
+                devices.push(dev);
+            }
+        }
+        Ok(devices)
+    }
+
+    fn open(&self, busid: &str) -> PacketResult<Option<DeviceDescriptor>> {
+        for device in self.context.devices()?.iter() {
+            let dev = describe(&device)?;
+            if dev.busid != busid || dev.device_class == USB_CLASS_HUB {
+                continue;
+            }
+            // A device imported again (e.g. by a client reconnecting before
+            // its old connection was noticed to be gone) replaces the old
+            // handle. It stays claimed until both connections have ended.
+            let id = devid(dev.busnum as u16, dev.devnum as u16);
+            let stale = self.handles.lock().unwrap().remove(&id);
+            let connections = match stale {
+                Some(stale) => {
+                    let connections = stale.connections;
+                    stale.release();
+                    connections + 1
+                },
+                None => 1,
+            };
+            let handle = device.open()?;
+            match handle.set_auto_detach_kernel_driver(true) {
+                Ok(()) | Err(rusb::Error::NotSupported) => (),
+                Err(e) => return Err(e.into()),
+            }
+            let mut claimed = Claimed { handle: Arc::new(handle), interfaces: Vec::new(), connections };
+            if let Ok(config) = device.active_config_descriptor() {
+                for interface in config.interfaces() {
+                    if let Err(e) = claimed.handle.claim_interface(interface.number()) {
+                        claimed.release();
+                        return Err(e.into());
+                    }
+                    claimed.interfaces.push(interface.number());
+                }
+            }
+            self.handles.lock().unwrap().insert(id, claimed);
+            return Ok(Some(dev));
+        }
+        Ok(None)
+    }
+
+    fn release_device(&self, id: u32) {
+        let mut handles = self.handles.lock().unwrap();
+        let last = match handles.get_mut(&id) {
+            Some(claimed) => {
+                claimed.connections -= 1;
+                claimed.connections == 0
+            },
+            None => false,
+        };
+        if last {
+            let claimed = handles.remove(&id).unwrap();
+            drop(handles);
+            claimed.release();
+        }
+    }
+}
+
+impl<T: UsbContext> DeviceProvider for LibUsbProvider<T> {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        self.devices().unwrap_or_else(|e| {
+            warn!("Enumerating USB devices failed: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        match self.open(busid) {
+            Ok(dev) => dev.map(|dev| RepImport::from_device(&dev, UsbIpStatus::Ok.as_u32())),
+            Err(e) => {
+                warn!("Opening {} failed: {}", busid, e);
+                Some(RepImport::failed(UsbIpStatus::DeviceError))
+            },
+        }
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        // Cloned out so that transfers on other connections are not held up
+        let handle = self.handles.lock().unwrap().get(&cmd.devid).map(|c| Arc::clone(&c.handle));
+        match handle {
+            Some(handle) => forward(&*handle, cmd, self.timeout),
+            None => reply(cmd, -ENODEV),
+        }
+    }
+
+    fn release(&self, device: &RepImport) {
+        self.release_device(devid(device.busnum as u16, device.devnum as u16));
+    }
+}
+
+// Runs cmd as a blocking transfer and reports the outcome like usbip-host
+// would: the transferred length, the data read for IN transfers, and a
+// negative errno in status if the transfer failed.
+pub fn forward<H: Transfers + ?Sized>(handle: &H, cmd: &CmdSubmit, timeout: Duration) -> RetSubmit {
+    let endpoint = cmd.endpoint().to_usb_byte();
+    let out = cmd.data.as_deref().unwrap_or(&[]);
+    let mut buf = Vec::new();
+    let res = match (cmd.transfer_type(), cmd.direction) {
+        (TransferType::Control, Direction::In) => {
+            let setup = cmd.setup_packet();
+            buf.resize(setup.length as usize, 0);
+            handle.read_control(setup.request_type, setup.request, setup.value, setup.index, &mut buf, timeout)
+        },
+        (TransferType::Control, Direction::Out) => {
+            let setup = cmd.setup_packet();
+            handle.write_control(setup.request_type, setup.request, setup.value, setup.index, out, timeout)
+        },
+        (TransferType::Bulk, Direction::In) => {
+            buf.resize(cmd.buffer_length as usize, 0);
+            handle.read_bulk(endpoint, &mut buf, timeout)
+        },
+        (TransferType::Bulk, Direction::Out) => handle.write_bulk(endpoint, out, timeout),
+        (TransferType::Interrupt, Direction::In) => {
+            buf.resize(cmd.buffer_length as usize, 0);
+            handle.read_interrupt(endpoint, &mut buf, timeout)
+        },
+        (TransferType::Interrupt, Direction::Out) => handle.write_interrupt(endpoint, out, timeout),
+        // libusb only does isochronous transfers asynchronously
+        (TransferType::Isochronous, _) => return reply(cmd, -EOPNOTSUPP),
+    };
+    match res {
+        Ok(length) => {
+            let mut ret = reply(cmd, 0);
+            ret.length = length as u32;
+            if cmd.direction == Direction::In {
+                buf.truncate(length);
+                ret.data = Some(buf);
+            }
+            ret
+        },
+        Err(e) => {
+            debug!("Transfer for {} failed: {}", cmd.seqnum, e);
+            reply(cmd, -errno(e))
+        },
+    }
+}
+
+fn errno(error: rusb::Error) -> i32 {
+    match error {
+        rusb::Error::Pipe => EPIPE,
+        rusb::Error::Timeout => ETIMEDOUT,
+        rusb::Error::NoDevice => ENODEV,
+        rusb::Error::Overflow => EOVERFLOW,
+        rusb::Error::Io => EPROTO,
+        rusb::Error::InvalidParam => EINVAL,
+        rusb::Error::NotSupported => EOPNOTSUPP,
+        _ => EIO,
+    }
+}
+
+fn describe<T: UsbContext>(device: &Device<T>) -> PacketResult<DeviceDescriptor> {
+    let mut desc = std::mem::MaybeUninit::<ffi::libusb_device_descriptor>::uninit();
+    // Cannot fail since libusb 1.0.16, the descriptor is cached on enumeration
+    let res = unsafe { ffi::libusb_get_device_descriptor(device.as_raw(), desc.as_mut_ptr()) };
+    if res != 0 {
+        return Err(PacketError::PacketError(format!("libusb: reading device descriptor failed ({})", res)));
+    }
+    let desc = unsafe { desc.assume_init() };
+    // Unconfigured devices have no active configuration and no interfaces
+    let (configuration_value, interfaces) = match device.active_config_descriptor() {
+        Ok(config) => {
+            let interfaces = config.interfaces()
+                .filter_map(|i| i.descriptors().next())
+                .map(|alt| InterfaceDescriptor {
+                    interface_class: alt.class_code(),
+                    interface_subclass: alt.sub_class_code(),
+                    interface_protocol: alt.protocol_code()
+                })
+                .collect();
+            (config.number(), interfaces)
+        },
+        Err(rusb::Error::NotFound) => (0, Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let ports = device.port_numbers()?;
+    Ok(device_descriptor(device.bus_number(), &ports, device.address(), device.speed(), &desc,
+                         configuration_value, interfaces))
+}
+
+// Maps what libusb knows about a device to the descriptor usbipd would list
+// for it. The busid is made up the way Linux names devices in sysfs, from the
+// bus number and the chain of hub ports the device hangs off.
+pub fn device_descriptor(busnum: u8, ports: &[u8], devnum: u8, speed: Speed,
+                         desc: &ffi::libusb_device_descriptor, configuration_value: u8,
+                         interfaces: Vec<InterfaceDescriptor>) -> DeviceDescriptor {
+    let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+    let busid = if ports.is_empty() {
+        format!("usb{}", busnum)
+    } else {
+        format!("{}-{}", busnum, ports.join("."))
+    };
+    DeviceDescriptor {
+        path: format!("/sys/bus/usb/devices/{}", busid),
+        busid,
+        busnum: busnum as u32,
+        devnum: devnum as u32,
+        speed: usb_speed(speed).as_u32(),
+        id_vendor: desc.idVendor,
+        id_product: desc.idProduct,
+        bcd_device: desc.bcdDevice,
+        device_class: desc.bDeviceClass,
+        device_subclass: desc.bDeviceSubClass,
+        device_protocol: desc.bDeviceProtocol,
+        configuration_value,
+        num_configurations: desc.bNumConfigurations,
+        num_interfaces: interfaces.len() as u8,
+        interfaces
+    }
+}
+
+pub fn usb_speed(speed: Speed) -> UsbSpeed {
+    match speed {
+        Speed::Low => UsbSpeed::Low,
+        Speed::Full => UsbSpeed::Full,
+        Speed::High => UsbSpeed::High,
+        Speed::Super => UsbSpeed::Super,
+        Speed::SuperPlus => UsbSpeed::SuperPlus,
+        _ => UsbSpeed::Unknown,
+    }
+}
+
+fn reply(cmd: &CmdSubmit, status: i32) -> RetSubmit {
+    RetSubmit {
+        seqnum: cmd.seqnum,
+        devid: cmd.devid,
+        direction: cmd.direction,
+        ep: cmd.ep,
+        status: status as u32,
+        length: 0,
+        start_frame: 0,
+        num_packets: 0,
+        error_count: 0,
+        setup: cmd.setup,
+        data: match cmd.direction {
+            Direction::In => Some(Vec::new()),
+            Direction::Out => None,
+        },
+        iso_packets: Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rusb::{ffi, Speed};
+
+    use crate::libusb::{device_descriptor, forward, LibUsbProvider, Transfers};
+    use crate::protocol::{DeviceDescriptor, InterfaceDescriptor, RepDevList, Packet, UsbSpeed,
+                          CmdSubmitBuilder, Direction};
+
+    // Records the transfers it is asked to do, answering IN transfers with a
+    // canned reply.
+    struct MockDevice {
+        calls: Mutex<Vec<String>>,
+        reply: rusb::Result<Vec<u8>>,
+    }
+
+    impl MockDevice {
+        fn new(reply: rusb::Result<Vec<u8>>) -> MockDevice {
+            MockDevice { calls: Mutex::new(Vec::new()), reply }
+        }
+
+        fn read(&self, call: String, buf: &mut [u8]) -> rusb::Result<usize> {
+            self.calls.lock().unwrap().push(call);
+            let data = self.reply.clone()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn write(&self, call: String, buf: &[u8]) -> rusb::Result<usize> {
+            self.calls.lock().unwrap().push(call);
+            self.reply.clone().map(|_| buf.len())
+        }
+    }
+
+    impl Transfers for MockDevice {
+        fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                        buf: &mut [u8], _: Duration) -> rusb::Result<usize> {
+            self.read(format!("read_control {:02x} {:02x} {:04x} {:04x} {}", request_type, request, value, index, buf.len()), buf)
+        }
+
+        fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16,
+                         buf: &[u8], _: Duration) -> rusb::Result<usize> {
+            self.write(format!("write_control {:02x} {:02x} {:04x} {:04x} {:?}", request_type, request, value, index, buf), buf)
+        }
+
+        fn read_bulk(&self, endpoint: u8, buf: &mut [u8], _: Duration) -> rusb::Result<usize> {
+            self.read(format!("read_bulk {:02x} {}", endpoint, buf.len()), buf)
+        }
+
+        fn write_bulk(&self, endpoint: u8, buf: &[u8], _: Duration) -> rusb::Result<usize> {
+            self.write(format!("write_bulk {:02x} {:?}", endpoint, buf), buf)
+        }
+
+        fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], _: Duration) -> rusb::Result<usize> {
+            self.read(format!("read_interrupt {:02x} {}", endpoint, buf.len()), buf)
+        }
+
+        fn write_interrupt(&self, endpoint: u8, buf: &[u8], _: Duration) -> rusb::Result<usize> {
+            self.write(format!("write_interrupt {:02x} {:?}", endpoint, buf), buf)
+        }
+    }
+
+    #[test]
+    fn test_device_descriptor_mapping() {
+        let desc = ffi::libusb_device_descriptor {
+            bLength: 18,
+            bDescriptorType: 1,
+            bcdUSB: 0x0200,
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 64,
+            idVendor: 0x0781,
+            idProduct: 0x5567,
+            bcdDevice: 0x0126,
+            iManufacturer: 1,
+            iProduct: 2,
+            iSerialNumber: 3,
+            bNumConfigurations: 1,
+        };
+        let interfaces = vec![InterfaceDescriptor {
+            interface_class: 8,
+            interface_subclass: 6,
+            interface_protocol: 80
+        }];
+        let dev = device_descriptor(1, &[1, 2], 5, Speed::High, &desc, 1, interfaces.clone());
+        assert_eq!(dev, DeviceDescriptor {
+            path: "/sys/bus/usb/devices/1-1.2".to_string(),
+            busid: "1-1.2".to_string(),
+            busnum: 1,
+            devnum: 5,
+            speed: UsbSpeed::High.as_u32(),
+            id_vendor: 0x0781,
+            id_product: 0x5567,
+            bcd_device: 0x0126,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 1,
+            interfaces
+        });
+
+        // Has to survive the wire like any other listed device
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![dev] });
+        assert_eq!(Packet::from_slice(&dl.write_to_vec().unwrap()).unwrap(), dl);
+    }
+
+    #[test]
+    fn test_forward_transfers() {
+        let timeout = Duration::from_secs(1);
+        let dev = MockDevice::new(Ok(vec![0x12, 0x01, 0x00, 0x02]));
+
+        // GET_DESCRIPTOR(DEVICE), wValue 0x0100 and wLength 18 little-endian
+        let cmd = CmdSubmitBuilder::new(Direction::In, 0).seqnum(1).buffer_length(18)
+            .setup([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]).build();
+        let ret = forward(&dev, &cmd, timeout);
+        assert_eq!((ret.seqnum, ret.status, ret.length), (1, 0, 4));
+        assert_eq!(ret.data, Some(vec![0x12, 0x01, 0x00, 0x02]));
+        assert_eq!(ret.setup, cmd.setup);
+
+        // HID SET_REPORT to interface 1
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 0).seqnum(2)
+            .setup([0x21, 0x09, 0x00, 0x02, 0x01, 0x00, 0x02, 0x00]).data(vec![1, 2]).build();
+        let ret = forward(&dev, &cmd, timeout);
+        assert_eq!((ret.status, ret.length, ret.data), (0, 2, None));
+
+        let cmd = CmdSubmitBuilder::new(Direction::In, 2).seqnum(3).buffer_length(512).build();
+        assert_eq!(forward(&dev, &cmd, timeout).length, 4);
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 1).seqnum(4).data(vec![9; 3]).build();
+        assert_eq!(forward(&dev, &cmd, timeout).length, 3);
+        let cmd = CmdSubmitBuilder::new(Direction::In, 3).seqnum(5).buffer_length(8).interval(10).build();
+        assert_eq!(forward(&dev, &cmd, timeout).length, 4);
+
+        assert_eq!(*dev.calls.lock().unwrap(), vec![
+            "read_control 80 06 0100 0000 18",
+            "write_control 21 09 0200 0001 [1, 2]",
+            "read_bulk 82 512",
+            "write_bulk 01 [9, 9, 9]",
+            "read_interrupt 83 8",
+        ]);
+    }
+
+    #[test]
+    fn test_forward_stall() {
+        let dev = MockDevice::new(Err(rusb::Error::Pipe));
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(7).buffer_length(64).build();
+        let ret = forward(&dev, &cmd, Duration::from_secs(1));
+        assert_eq!((ret.seqnum, ret.status as i32, ret.length), (7, -32, 0));
+        assert_eq!(ret.data, Some(vec![]));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_list_local_devices() {
+        let devices = LibUsbProvider::new().devices().unwrap();
+        assert!(!devices.is_empty(), "No USB devices attached");
+        for dev in devices {
+            assert_eq!(dev.num_interfaces as usize, dev.interfaces.len());
+            assert!(dev.busid.starts_with(&format!("{}-", dev.busnum)), "{}", dev.busid);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+// One end of an in-memory connection, for running a client and a server in
+// the same process without sockets. Reads block until the other end writes
+// something, and return end of stream once the other end has been dropped.
+pub struct MemoryStream {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+pub fn pair() -> (MemoryStream, MemoryStream) {
+    let (a_tx, b_rx) = channel();
+    let (b_tx, a_rx) = channel();
+    (MemoryStream { tx: a_tx, rx: a_rx, pending: VecDeque::new() },
+     MemoryStream { tx: b_tx, rx: b_rx, pending: VecDeque::new() })
+}
+
+// The receiving half of a split MemoryStream
+pub struct MemoryReader {
+    rx: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+// The sending half of a split MemoryStream. Dropping it ends the stream for
+// the other end, even while the reader is still in use.
+pub struct MemoryWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+impl MemoryStream {
+    // For reading and writing on different threads
+    pub fn split(self) -> (MemoryReader, MemoryWriter) {
+        (MemoryReader { rx: self.rx, pending: self.pending }, MemoryWriter { tx: self.tx })
+    }
+}
+
+fn recv(rx: &Receiver<Vec<u8>>, pending: &mut VecDeque<u8>, dst: &mut [u8]) -> io::Result<usize> {
+    while pending.is_empty() && !dst.is_empty() {
+        match rx.recv() {
+            Ok(chunk) => pending.extend(chunk),
+            Err(_) => return Ok(0),
+        }
+    }
+    pending.read(dst)
+}
+
+fn send(tx: &Sender<Vec<u8>>, buf: &[u8]) -> io::Result<usize> {
+    tx.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+    Ok(buf.len())
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        recv(&self.rx, &mut self.pending, dst)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        send(&self.tx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        recv(&self.rx, &mut self.pending, dst)
+    }
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        send(&self.tx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::memory::pair;
+
+    #[test]
+    fn test_pair() {
+        let (mut a, mut b) = pair();
+        a.write_all(b"abc").unwrap();
+        a.write_all(b"de").unwrap();
+        b.write_all(b"xyz").unwrap();
+        drop(b);
+        let mut buf = [0u8; 4];
+        a.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"xyz");
+        assert_eq!(a.read(&mut buf).unwrap(), 0);
+        assert!(a.write_all(b"f").is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let (a, mut b) = pair();
+        let (mut reader, mut writer) = a.split();
+        writer.write_all(b"abc").unwrap();
+        b.write_all(b"xyz").unwrap();
+        let mut buf = [0u8; 3];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        drop(writer);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"xyz");
+    }
+}
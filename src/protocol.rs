@@ -1,20 +1,81 @@
+use core::convert::TryFrom;
+use core::fmt;
+use core::str::FromStr;
+#[cfg(feature = "std")]
 use std::io;
-use std::vec::Vec;
-use std::string::{String, FromUtf8Error};
-use num::FromPrimitive;
+#[cfg(feature = "std")]
+use std::error::Error;
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::{String, ToString, FromUtf8Error};
+use alloc::format;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, LittleEndian, ByteOrder};
+
+use crate::util::{ByteReader, ByteWriter, read_fix_string, write_fix_string, read_fix_ascii, write_fix_ascii};
+use crate::spans;
 
 #[derive(Debug)]
 pub enum PacketError {
     PacketError(String),
+    #[cfg(feature = "std")]
     IoError(io::Error),
     Utf8Error(FromUtf8Error),
+    // The header of a packet with a version other than 0 or USBIP_VERSION
+    UnsupportedVersion(PacketHeader),
+    Truncated,
+    // Like Truncated, naming the structure and field that was cut off
+    TruncatedField(&'static str, &'static str),
+    ConnectionClosed,
+    ImportFailed(ImportError),
+    // A read timed out before the first byte of a packet, the stream is
+    // still in sync and reading can be retried.
+    Timeout,
+    // A read timed out within a packet, the bytes read so far are lost.
+    TimeoutMidPacket,
+}
+
+// Payload-free mirror of PacketError, comparable in tests
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum PacketErrorKind {
+    Protocol,
+    Io,
+    Utf8,
+    UnsupportedVersion,
+    Truncated,
+    ConnectionClosed,
+    ImportFailed,
+    Timeout,
+    TimeoutMidPacket,
+}
+
+impl PacketError {
+    pub fn kind(&self) -> PacketErrorKind {
+        match *self {
+            PacketError::PacketError(_) => PacketErrorKind::Protocol,
+            #[cfg(feature = "std")]
+            PacketError::IoError(_) => PacketErrorKind::Io,
+            PacketError::Utf8Error(_) => PacketErrorKind::Utf8,
+            PacketError::UnsupportedVersion(_) => PacketErrorKind::UnsupportedVersion,
+            PacketError::Truncated => PacketErrorKind::Truncated,
+            PacketError::TruncatedField(..) => PacketErrorKind::Truncated,
+            PacketError::ConnectionClosed => PacketErrorKind::ConnectionClosed,
+            PacketError::ImportFailed(_) => PacketErrorKind::ImportFailed,
+            PacketError::Timeout => PacketErrorKind::Timeout,
+            PacketError::TimeoutMidPacket => PacketErrorKind::TimeoutMidPacket,
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for PacketError {
     fn from(error: io::Error) -> Self {
-        PacketError::IoError(error)
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => PacketError::Truncated,
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => PacketError::Timeout,
+            _ => PacketError::IoError(error)
+        }
     }
 }
 
@@ -24,9 +85,118 @@ impl From<FromUtf8Error> for PacketError {
     }
 }
 
-type PacketResult<T> = Result<T, PacketError>;
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketError::PacketError(ref msg) => write!(f, "Invalid packet: {}", msg),
+            #[cfg(feature = "std")]
+            PacketError::IoError(ref e) => write!(f, "I/O error: {}", e),
+            PacketError::Utf8Error(ref e) => write!(f, "Invalid UTF-8 in string field: {}", e),
+            PacketError::UnsupportedVersion(h) => {
+                write!(f, "Unknown command 0x{:04x} for unsupported version 0x{:04x}", h.command, h.version)?;
+                if let Some(ptype) = h.known_command() {
+                    write!(f, " ({:?} under version 0x{:04x})", ptype, USBIP_VERSION)?;
+                }
+                write!(f, ", header 0x{:08x}", h.to_u32())
+            },
+            PacketError::Truncated => write!(f, "Packet truncated by end of stream"),
+            PacketError::TruncatedField(ty, field) => write!(f, "EOF while reading {}.{}", ty, field),
+            PacketError::ConnectionClosed => write!(f, "Connection closed by peer"),
+            PacketError::ImportFailed(e) => write!(f, "Import failed: {}", e),
+            PacketError::Timeout => write!(f, "Timed out waiting for a packet"),
+            PacketError::TimeoutMidPacket => write!(f, "Timed out in the middle of a packet"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PacketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PacketError::PacketError(_) => None,
+            PacketError::IoError(ref e) => Some(e),
+            PacketError::Utf8Error(ref e) => Some(e),
+            PacketError::UnsupportedVersion(_) => None,
+            PacketError::Truncated => None,
+            PacketError::TruncatedField(..) => None,
+            PacketError::ConnectionClosed => None,
+            PacketError::ImportFailed(_) => None,
+            PacketError::Timeout => None,
+            PacketError::TimeoutMidPacket => None,
+        }
+    }
+}
+
+pub type PacketResult<T> = Result<T, PacketError>;
+
+/// USB/IP protocol version carried in the upper 16 bits of every OP header
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// OP headers of the device list and import exchanges, USBIP_VERSION in the
+/// upper 16 bits and the command in the lower
+pub const OP_REQ_DEVLIST: u32 = 0x01118005;
+pub const OP_REP_DEVLIST: u32 = 0x01110005;
+pub const OP_REQ_IMPORT: u32 = 0x01118003;
+pub const OP_REP_IMPORT: u32 = 0x01110003;
+
+/// Commands of the URB packets exchanged once a device is imported
+pub const USBIP_CMD_SUBMIT: u32 = 0x00000001;
+pub const USBIP_RET_SUBMIT: u32 = 0x00000003;
+pub const USBIP_CMD_UNLINK: u32 = 0x00000002;
+pub const USBIP_RET_UNLINK: u32 = 0x00000004;
+
+/// Default upper bound for the payload of a single submit packet
+pub const DEFAULT_MAX_PAYLOAD: u32 = 16 * 1024 * 1024;
+
+/// Default upper bound for the number of devices in a device list reply
+pub const DEFAULT_MAX_DEVICES: u32 = 1024;
+
+/// Most interfaces a device may declare, as in the Linux USB core
+pub const MAX_INTERFACES: u8 = 32;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct ReadLimits {
+    pub max_payload: u32,
+    pub max_devices: u32,
+    pub max_interfaces: u8,
+    // Reject transfer flags with unknown bits instead of dropping those bits
+    pub strict_transfer_flags: bool,
+    // Interfaces carry a padding byte like the kernel sends them. Some third
+    // party servers pack them without one, clear this to talk to those.
+    pub padded_interfaces: bool,
+    // Op requests (devlist and import) carry a reserved status word after the
+    // header. Some minimal peers leave it out, clear this to read theirs.
+    pub request_status: bool,
+}
+
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        ReadLimits {
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_devices: DEFAULT_MAX_DEVICES,
+            max_interfaces: MAX_INTERFACES,
+            strict_transfer_flags: true,
+            padded_interfaces: true,
+            request_status: true
+        }
+    }
+}
+
+impl ReadLimits {
+    fn check_payload(&self, len: u32) -> PacketResult<()> {
+        if len > self.max_payload {
+            return Err(PacketError::PacketError("buffer_length exceeds limit".to_string()));
+        }
+        Ok(())
+    }
+}
 
-#[derive(Debug,PartialEq)]
+// Types made up of plain numbers (Direction, EndpointAddress, the statuses and
+// speeds, setup packets, iso descriptors, interface descriptors and the
+// unlink packets) are Eq and Hash, so they can key maps of outstanding URBs.
+// Packets carrying strings or payloads are only PartialEq.
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Packet {
     ReqDevList,
     RepDevList(RepDevList),
@@ -38,14 +208,16 @@ pub enum Packet {
     RetUnlink(RetUnlink)
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RepDevList {
     pub status: u32,
     pub num_devices: u32,
     pub devices: Vec<DeviceDescriptor>
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceDescriptor {
     pub path: String,
     pub busid: String,
@@ -64,19 +236,22 @@ pub struct DeviceDescriptor {
     pub interfaces: Vec<InterfaceDescriptor>
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InterfaceDescriptor {
     pub interface_class: u8,
     pub interface_subclass: u8,
     pub interface_protocol: u8
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReqImport {
     pub busid: String
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RepImport {
     pub status: u32,
     pub path: String,
@@ -95,23 +270,74 @@ pub struct RepImport {
     pub num_interfaces: u8
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CmdSubmit {
     pub seqnum: u32,
     pub devid: u32,
     pub direction: Direction,
     pub ep: u32,
     pub transfer_flags: TransferFlags,
+    // Size of the transfer asked for: the bytes sent with an OUT, the most
+    // a device may return for an IN
     pub buffer_length: u32,
     pub start_frame: u32,
     pub num_packets: u32,
     pub interval: u32,
-    pub setup: Vec<u8>,
-    pub data: Option<Vec<u8>>
+    pub setup: [u8; 8],
+    pub data: Option<Vec<u8>>,
+    pub iso_packets: Vec<IsoPacketDescriptor>
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Default,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RetSubmit {
+    pub seqnum: u32,
+    pub devid: u32,
+    pub direction: Direction,
+    pub ep: u32,
+    pub status: u32,
+    // Size of what was actually transferred (the kernel's actual_length), at
+    // most the submit's buffer_length. An IN reply carries exactly this many
+    // bytes of data, which write checks, an OUT reply none.
+    pub length: u32,
+    pub start_frame: u32,
+    pub num_packets: u32,
+    pub error_count: u32,
+    pub setup: [u8; 8],
+    pub data: Option<Vec<u8>>,
+    pub iso_packets: Vec<IsoPacketDescriptor>
+}
+
+// Borrowed counterparts of CmdSubmit and RetSubmit, used to forward URBs
+// without allocating for every payload. When decoded by Packet::read_into the
+// data points into the caller's buffer, so the view has to be dropped before
+// that buffer can be reused for the next packet.
+#[derive(Debug,PartialEq,Clone)]
+pub enum PacketRef<'a> {
+    CmdSubmit(CmdSubmitRef<'a>),
+    RetSubmit(RetSubmitRef<'a>),
+    Other(Packet)
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct CmdSubmitRef<'a> {
+    pub seqnum: u32,
+    pub devid: u32,
+    pub direction: Direction,
+    pub ep: u32,
+    pub transfer_flags: TransferFlags,
+    pub buffer_length: u32,
+    pub start_frame: u32,
+    pub num_packets: u32,
+    pub interval: u32,
+    pub setup: [u8; 8],
+    pub data: Option<&'a [u8]>,
+    pub iso_packets: Cow<'a, [IsoPacketDescriptor]>
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct RetSubmitRef<'a> {
     pub seqnum: u32,
     pub devid: u32,
     pub direction: Direction,
@@ -121,11 +347,32 @@ pub struct RetSubmit {
     pub start_frame: u32,
     pub num_packets: u32,
     pub error_count: u32,
-    pub setup: Vec<u8>,
-    pub data: Option<Vec<u8>>
+    pub setup: [u8; 8],
+    pub data: Option<&'a [u8]>,
+    pub iso_packets: Cow<'a, [IsoPacketDescriptor]>
+}
+
+#[derive(Debug,Default,PartialEq,Eq,Hash,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IsoPacketDescriptor {
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    pub status: u32
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Default,PartialEq,Eq,Hash,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsbSetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16
+}
+
+#[derive(Debug,Default,PartialEq,Eq,Hash,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CmdUnlink {
     pub seq: u32,
     pub devid: u32,
@@ -134,7 +381,8 @@ pub struct CmdUnlink {
     pub seqnum: u32,
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Default,PartialEq,Eq,Hash,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RetUnlink {
     pub seqnum: u32,
     pub devid: u32,
@@ -143,406 +391,1943 @@ pub struct RetUnlink {
     pub status: u32,
 }
 
+// URB_* transfer flags from the kernel's include/linux/usb.h. The DMA and
+// mapping flags are host controller internals, but Linux peers pass the whole
+// word through, so they do show up on the wire.
 bitflags! {
+    #[derive(Default)]
     pub struct TransferFlags: u32 {
         const SHORT_NOT_OK = 0x001;
         const ISO_ASAP = 0x002;
         const NO_TRANSFER_DMA_MAP = 0x004;
+        const NO_SETUP_DMA_MAP = 0x008;
         const NO_FSBR = 0x020;
         const ZERO_PACKET = 0x040;
         const NO_INTERRUPT = 0x080;
         const FREE_BUFFER = 0x100;
+        const DIR_IN = 0x200;
         const DIR_MASK = 0x200;
+        const DMA_MAP_SINGLE = 0x0001_0000;
+        const DMA_MAP_PAGE = 0x0002_0000;
+        const DMA_MAP_SG = 0x0004_0000;
+        const MAP_LOCAL = 0x0008_0000;
+        const SETUP_MAP_SINGLE = 0x0010_0000;
+        const SETUP_MAP_LOCAL = 0x0020_0000;
+        const DMA_SG_COMBINED = 0x0040_0000;
+        const ALIGNED_TEMP_BUFFER = 0x0080_0000;
     }
 }
 
 impl TransferFlags {
-    fn from_u32(val: u32) -> Result<TransferFlags, PacketError> {
+    fn from_u32(val: u32, strict: bool) -> Result<TransferFlags, PacketError> {
         match TransferFlags::from_bits(val) {
             Some(x) => Ok(x),
-            None => Err(PacketError::PacketError("Invalid transfer_flags".to_string())),
+            None if !strict => Ok(TransferFlags::from_bits_truncate(val)),
+            None => Err(PacketError::PacketError(format!("Invalid transfer_flags: 0x{:08x}", val))),
         }
     }
 }
 
-enum_from_primitive! {
-    #[derive(Debug,PartialEq)]
-    pub enum Direction {
-        In = 0x00000001,
-        Out = 0x00000000
+// bitflags 1.x has no serde support, so go through the raw bit value
+#[cfg(feature = "serde")]
+impl serde::Serialize for TransferFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TransferFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<TransferFlags, D::Error> {
+        let bits = <u32 as serde::Deserialize>::deserialize(deserializer)?;
+        TransferFlags::from_bits(bits)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid transfer_flags: 0x{:08x}", bits)))
     }
 }
 
+// Out is 0 on the wire, so it is also the default of the zeroed structures
+#[derive(Debug,Default,PartialEq,Eq,Hash,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    In = 0x00000001,
+    #[default]
+    Out = 0x00000000
+}
+
 impl Direction {
-    fn from_u32_err(val: u32) -> Result<Direction, PacketError> {
-        match Direction::from_u32(val) {
-            Some(x) => Ok(x),
-            None => Err(PacketError::PacketError("Invalid direction value".to_string()))
+    pub fn from_u32(val: u32) -> PacketResult<Direction> {
+        match val {
+            0x00000001 => Ok(Direction::In),
+            0x00000000 => Ok(Direction::Out),
+            _ => Err(PacketError::PacketError("Invalid direction value".to_string()))
         }
     }
-}
 
-enum_from_primitive! {
-    #[derive(Debug,PartialEq)]
-    enum PacketTypes {
-        ReqDevList = 0x01118005,
-        RepDevList = 0x01110005,
-        ReqImport = 0x01118003,
-        RepImport = 0x01110003,
-        CmdSubmit = 0x00000001,
-        RetSubmit = 0x00000003,
-        CmdUnlink = 0x00000002,
-        RetUnlink = 0x00000004,
+    pub fn to_u32(self) -> u32 {
+        self as u32
     }
-}
 
-impl Packet {
-    pub fn read(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        let header = src.read_u32::<BigEndian>()?;
-        match PacketTypes::from_u32(header) {
-            Some(PacketTypes::ReqDevList) => Packet::read_req_devlist(src),
-            Some(PacketTypes::RepDevList) => RepDevList::read(src),
-            Some(PacketTypes::ReqImport) => ReqImport::read(src),
-            Some(PacketTypes::RepImport) => RepImport::read(src),
-            Some(PacketTypes::CmdSubmit) => CmdSubmit::read(src),
-            Some(PacketTypes::RetSubmit) => Err(PacketError::PacketError("RetSubmit not implemented".to_string())),
-            Some(PacketTypes::CmdUnlink) => Err(PacketError::PacketError("CmdUnlink not implemented".to_string())),
-            Some(PacketTypes::RetUnlink) => Err(PacketError::PacketError("RetUnlink not implemented".to_string())),
-            None => Err(PacketError::PacketError(format!("Unknown packet header: 0x{:08x}", header).to_string()))
-        }
-    }
-
-    pub fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
+    pub fn is_in(self) -> bool {
+        self == Direction::In
+    }
+
+    pub fn is_out(self) -> bool {
+        self == Direction::Out
+    }
+
+    pub fn opposite(self) -> Direction {
         match self {
-            &Packet::ReqDevList => Packet::write_req_devlist(dst),
-            &Packet::RepDevList(ref s) => s.write(dst),
-            &Packet::ReqImport(ref s) => s.write(dst),
-            &Packet::RepImport(ref s) => s.write(dst),
-            &Packet::CmdSubmit(ref s) => s.write(dst),
-            &Packet::RetSubmit(ref s) => Err(PacketError::PacketError("RetSubmit not implemented".to_string())),
-            &Packet::CmdUnlink(ref s) => Err(PacketError::PacketError("CmdUnlink not implemented".to_string())),
-            &Packet::RetUnlink(ref s) => Err(PacketError::PacketError("RetUnlink not implemented".to_string())),
-        }    
+            Direction::In => Direction::Out,
+            Direction::Out => Direction::In,
+        }
     }
 
-    fn read_req_devlist(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        src.read_u32::<BigEndian>()?;
-        Ok(Packet::ReqDevList)
+    // All URB headers go through these two, so the four packet types cannot
+    // disagree on the encoding.
+    fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<Direction> {
+        Direction::from_u32(src.read_u32::<BigEndian>()?)
     }
 
-    fn write_req_devlist(dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u32::<BigEndian>(PacketTypes::ReqDevList as u32)?;
-        dst.write_u32::<BigEndian>(0)?;
-        Ok(())
-    }    
+    fn write<W: ByteWriter + ?Sized>(self, dst: &mut W) -> PacketResult<()> {
+        dst.write_u32::<BigEndian>(self.to_u32())
+    }
 }
 
-impl RepDevList {
-    fn read(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        let status = src.read_u32::<BigEndian>()?;
-        let num_devices = src.read_u32::<BigEndian>()?;
-        let mut devices = Vec::new();
-        for _ in 0..num_devices {
-            let device = DeviceDescriptor::read(src)?;
-            devices.push(device);
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::In => write!(f, "IN"),
+            Direction::Out => write!(f, "OUT"),
         }
-        Ok(Packet::RepDevList(RepDevList{ status, num_devices, devices }))
     }
+}
 
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u32::<BigEndian>(PacketTypes::RepDevList as u32)?;
-        dst.write_u32::<BigEndian>(self.status)?;
-        dst.write_u32::<BigEndian>(self.num_devices)?;
-        for dev in &self.devices {
-            dev.write(dst)?;
+// An endpoint as USB addresses it in a single byte: the number in the low
+// nibble and the direction in bit 7, e.g. 0x81 for endpoint 1 IN.
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EndpointAddress {
+    pub number: u8,
+    pub direction: Direction,
+}
+
+impl EndpointAddress {
+    pub fn from_usb_byte(addr: u8) -> EndpointAddress {
+        let direction = if addr & 0x80 != 0 { Direction::In } else { Direction::Out };
+        EndpointAddress { number: addr & 0x0f, direction }
+    }
+
+    pub fn to_usb_byte(self) -> u8 {
+        match self.direction {
+            Direction::In => self.number | 0x80,
+            Direction::Out => self.number,
         }
-        Ok(())
     }
 }
 
-impl DeviceDescriptor {
-    fn read(src: &mut dyn io::Read) -> PacketResult<DeviceDescriptor> {
-        let path = read_fix_string(src, 256)?;
-        let busid = read_fix_string(src, 32)?;
-        let busnum = src.read_u32::<BigEndian>()?;
-        let devnum = src.read_u32::<BigEndian>()?;
-        let speed = src.read_u32::<BigEndian>()?;
-        let id_vendor = src.read_u16::<BigEndian>()?;
-        let id_product = src.read_u16::<BigEndian>()?;
-        let bcd_device = src.read_u16::<BigEndian>()?;
-        let device_class = src.read_u8()?;
-        let device_subclass = src.read_u8()?;
-        let device_protocol = src.read_u8()?;
-        let configuration_value = src.read_u8()?;
-        let num_configurations = src.read_u8()?;
-        let num_interfaces = src.read_u8()?;
-        let mut interfaces = Vec::new();
-        for _ in 0..num_interfaces {
-            let interface = InterfaceDescriptor::read(src)?;
-            interfaces.push(interface);
+// USB/IP does not transmit the transfer type, so it is inferred from the
+// endpoint number, the iso descriptors and the polling interval.
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Interrupt,
+    Bulk
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum UsbIpStatus {
+    Ok,
+    NotAvailable,
+    DeviceBusy,
+    DeviceError,
+    NoDevice,
+    Error,
+    Other(u32)
+}
+
+impl UsbIpStatus {
+    pub fn from_u32(val: u32) -> UsbIpStatus {
+        match val {
+            0 => UsbIpStatus::Ok,
+            1 => UsbIpStatus::NotAvailable,
+            2 => UsbIpStatus::DeviceBusy,
+            3 => UsbIpStatus::DeviceError,
+            4 => UsbIpStatus::NoDevice,
+            5 => UsbIpStatus::Error,
+            x => UsbIpStatus::Other(x)
         }
-        Ok(DeviceDescriptor{
-            path, busid, busnum, devnum, speed, id_vendor, id_product,
-            bcd_device, device_class, device_subclass, device_protocol,
-            configuration_value, num_configurations, num_interfaces, interfaces
-        })
     }
 
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        write_fix_string(dst, &self.path, 256)?;
-        write_fix_string(dst, &self.busid, 32)?;
-        dst.write_u32::<BigEndian>(self.busnum)?;
-        dst.write_u32::<BigEndian>(self.devnum)?;
-        dst.write_u32::<BigEndian>(self.speed)?;
-        dst.write_u16::<BigEndian>(self.id_vendor)?;
-        dst.write_u16::<BigEndian>(self.id_product)?;
-        dst.write_u16::<BigEndian>(self.bcd_device)?;
-        dst.write_u8(self.device_class)?;
-        dst.write_u8(self.device_subclass)?;
-        dst.write_u8(self.device_protocol)?;
-        dst.write_u8(self.configuration_value)?;
-        dst.write_u8(self.num_configurations)?;
-        dst.write_u8(self.num_interfaces)?;
-        for interface in &self.interfaces {
-            interface.write(dst)?;
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            UsbIpStatus::Ok => 0,
+            UsbIpStatus::NotAvailable => 1,
+            UsbIpStatus::DeviceBusy => 2,
+            UsbIpStatus::DeviceError => 3,
+            UsbIpStatus::NoDevice => 4,
+            UsbIpStatus::Error => 5,
+            UsbIpStatus::Other(x) => x
         }
-        Ok(())
+    }
+
+    pub fn is_ok(&self) -> bool {
+        *self == UsbIpStatus::Ok
     }
 }
 
-impl InterfaceDescriptor {
-    fn read(src: &mut dyn io::Read) -> PacketResult<InterfaceDescriptor> {
-        let interface_class = src.read_u8()?;
-        let interface_subclass = src.read_u8()?;
-        let interface_protocol = src.read_u8()?;
-        src.read_u8()?;    // Padding
-        Ok(InterfaceDescriptor{
-            interface_class, interface_subclass, interface_protocol
-        })
+// The kernel's enum usb_device_speed, as carried in the speed fields
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum UsbSpeed {
+    Unknown,
+    Low,
+    Full,
+    High,
+    Wireless,
+    Super,
+    SuperPlus,
+    Other(u32)
+}
+
+impl UsbSpeed {
+    pub fn from_u32(val: u32) -> UsbSpeed {
+        match val {
+            0 => UsbSpeed::Unknown,
+            1 => UsbSpeed::Low,
+            2 => UsbSpeed::Full,
+            3 => UsbSpeed::High,
+            4 => UsbSpeed::Wireless,
+            5 => UsbSpeed::Super,
+            6 => UsbSpeed::SuperPlus,
+            x => UsbSpeed::Other(x)
+        }
     }
 
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u8(self.interface_class)?;
-        dst.write_u8(self.interface_subclass)?;
-        dst.write_u8(self.interface_protocol)?;
-        dst.write_u8(0u8)?;    // Padding
-        Ok(())
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            UsbSpeed::Unknown => 0,
+            UsbSpeed::Low => 1,
+            UsbSpeed::Full => 2,
+            UsbSpeed::High => 3,
+            UsbSpeed::Wireless => 4,
+            UsbSpeed::Super => 5,
+            UsbSpeed::SuperPlus => 6,
+            UsbSpeed::Other(x) => x
+        }
     }
 }
 
-impl ReqImport {
-    fn read(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        let status = src.read_u32::<BigEndian>()?;
-        let busid = read_fix_string(src, 32)?;
-        Ok(Packet::ReqImport(ReqImport{ busid }))
+impl fmt::Display for UsbSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsbSpeed::Unknown => write!(f, "Unknown Speed"),
+            UsbSpeed::Low => write!(f, "Low Speed"),
+            UsbSpeed::Full => write!(f, "Full Speed"),
+            UsbSpeed::High => write!(f, "High Speed"),
+            UsbSpeed::Wireless => write!(f, "Wireless"),
+            UsbSpeed::Super => write!(f, "SuperSpeed"),
+            UsbSpeed::SuperPlus => write!(f, "SuperSpeed+"),
+            UsbSpeed::Other(x) => write!(f, "Speed {}", x),
+        }
     }
+}
 
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u32::<BigEndian>(PacketTypes::ReqImport as u32)?; 
-        dst.write_u32::<BigEndian>(0)?;
-        write_fix_string(dst, &self.busid, 32)?;;
-        Ok(())
-    }    
+// Why a server refused an import, taken from the status of its RepImport
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum ImportError {
+    NotAvailable,
+    DeviceBusy,
+    DeviceError,
+    NotFound,
+    Other(u32)
 }
 
-/*    pub status: u32,
-    pub path: String,
-    pub busid: String,
-    pub busnum: u32,
-    pub devnum: u32,
-    pub speed: u32,
-    pub id_vendor: u16,
-    pub id_product: u16,
-    pub bcd_device: u16,
-    pub device_class: u8,
-    pub device_subclass: u8,
-    pub device_protocol: u8,
-    pub configuration_value: u8,
-    pub num_configurations: u8,
-    pub num_interfaces: u8*/
-impl RepImport {
-    fn read(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        let status = src.read_u32::<BigEndian>()?;
-        if status != 0x0 {
-            return Ok(Packet::RepImport(RepImport {
-                status, path: "".to_string(), busid: "".to_string(),
-                busnum: 0, devnum: 0, speed: 0, id_vendor: 0, id_product: 0,
-                bcd_device: 0, device_class: 0, device_subclass: 0, device_protocol: 0,
-                configuration_value: 0, num_configurations: 0, num_interfaces: 0
-            }));
-        }
-        let path = read_fix_string(src, 256)?;
-        let busid = read_fix_string(src, 32)?;
-        let busnum = src.read_u32::<BigEndian>()?;
-        let devnum = src.read_u32::<BigEndian>()?;
-        let speed = src.read_u32::<BigEndian>()?;
-        let id_vendor = src.read_u16::<BigEndian>()?;
-        let id_product = src.read_u16::<BigEndian>()?;
-        let bcd_device = src.read_u16::<BigEndian>()?;
-        let device_class = src.read_u8()?;
-        let device_subclass = src.read_u8()?;
-        let device_protocol = src.read_u8()?;
-        let configuration_value = src.read_u8()?;
-        let num_configurations = src.read_u8()?;
-        let num_interfaces = src.read_u8()?;
-        Ok(Packet::RepImport(RepImport{ 
-            status, path, busid, busnum, devnum, speed, id_vendor, id_product, bcd_device,
-            device_class, device_subclass, device_protocol, configuration_value,
-            num_configurations, num_interfaces
-        }))
+impl ImportError {
+    pub fn from_status(status: UsbIpStatus) -> Option<ImportError> {
+        match status {
+            UsbIpStatus::Ok => None,
+            UsbIpStatus::NotAvailable => Some(ImportError::NotAvailable),
+            UsbIpStatus::DeviceBusy => Some(ImportError::DeviceBusy),
+            UsbIpStatus::DeviceError => Some(ImportError::DeviceError),
+            UsbIpStatus::NoDevice => Some(ImportError::NotFound),
+            s => Some(ImportError::Other(s.as_u32())),
+        }
     }
-
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u32::<BigEndian>(PacketTypes::RepImport as u32)?;
-        dst.write_u32::<BigEndian>(self.status)?;
-        if self.status != 0 { return Ok(()) }
-        write_fix_string(dst, &self.path, 256)?;
-        write_fix_string(dst, &self.busid, 32)?;
-        dst.write_u32::<BigEndian>(self.busnum)?;
-        dst.write_u32::<BigEndian>(self.devnum)?;
-        dst.write_u32::<BigEndian>(self.speed)?;
-        dst.write_u16::<BigEndian>(self.id_vendor)?;
-        dst.write_u16::<BigEndian>(self.id_product)?;
-        dst.write_u16::<BigEndian>(self.bcd_device)?;
-        dst.write_u8(self.device_class)?;
-        dst.write_u8(self.device_subclass)?;
-        dst.write_u8(self.device_protocol)?;
-        dst.write_u8(self.configuration_value)?;
-        dst.write_u8(self.num_configurations)?;
-        dst.write_u8(self.num_interfaces)?;
-        Ok(())
-    }    
 }
 
-impl CmdSubmit {
-    fn read(src: &mut dyn io::Read) -> PacketResult<Packet> {
-        let seqnum = src.read_u32::<BigEndian>()?;
-        println!("Seqnum: {:?}", seqnum);
-        let devid = src.read_u32::<BigEndian>()?;
-        println!("Devid: {:?}", devid);
-        let direction = Direction::from_u32_err(try!(src.read_u32::<BigEndian>()))?;
-        println!("Direction: {:?}", direction);
-        let ep = src.read_u32::<BigEndian>()?;
-        println!("Ep: {:?}", ep);
-        let transfer_flags = TransferFlags::from_u32(try!(src.read_u32::<BigEndian>()))?;
-        println!("flags: {:?}", transfer_flags);
-        let buffer_length = src.read_u32::<BigEndian>()?;
-        println!("Buffer_length: {:?}", buffer_length);
-        let start_frame = src.read_u32::<BigEndian>()?;
-        println!("Start_frame: {:?}", start_frame);
-        let num_packets = src.read_u32::<BigEndian>()?;
-        println!("Num_Packets: {:?}", num_packets);
-        let interval = src.read_u32::<BigEndian>()?;
-        println!("Interval: {:?}", interval);
-        let mut setup = vec![0u8; 8];
-        src.read_exact(&mut setup)?;
-        println!("Setup: {:?}", setup);
-        let mut data: Option<Vec<u8>> = None;
-        if direction == Direction::Out {
-            let mut dv = vec![0u8; buffer_length as usize];
-            src.read_exact(dv.as_mut_slice())?;
-            println!("Data: {:?}", dv);
-            data = Some(dv);
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::NotAvailable => write!(f, "device not available"),
+            ImportError::DeviceBusy => write!(f, "device busy"),
+            ImportError::DeviceError => write!(f, "device error"),
+            ImportError::NotFound => write!(f, "no such device"),
+            ImportError::Other(status) => write!(f, "status {}", status),
         }
-        Ok(Packet::CmdSubmit(CmdSubmit{ 
-            seqnum, devid, direction, ep, transfer_flags, buffer_length,
-            start_frame, num_packets, interval, setup, data
-        }))
     }
+}
 
-    fn write(&self, dst: &mut dyn io::Write) -> PacketResult<()> {
-        dst.write_u32::<BigEndian>(PacketTypes::CmdSubmit as u32)?;
-/*    pub seqnum: u32,
-    pub devid: u32,
-    pub direction: Direction,
-    pub ep: u32,
-    pub transfer_flags: TransferFlags,
-    pub buffer_length: u32,
-    pub start_frame: u32,
-    pub num_packets: u32,
-    pub interval: u32,
-    pub setup: [u8; 8],
-    pub data: Vec<u8>*/
-        dst.write_u32::<BigEndian>(self.seqnum)?;
-        dst.write_u32::<BigEndian>(self.devid)?;
-        dst.write_u32::<BigEndian>(self.ep)?;
-        dst.write_u32::<BigEndian>(self.transfer_flags.bits())?;
-        dst.write_u32::<BigEndian>(self.buffer_length)?;
-        dst.write_u32::<BigEndian>(self.start_frame)?;
-        dst.write_u32::<BigEndian>(self.num_packets)?;
-        dst.write_u32::<BigEndian>(self.interval)?;
-        dst.write(&self.setup)?;
-        if let Some(dv) = &self.data {
-            dst.write(dv)?;
+// URBs address a device by busnum in the upper and devnum in the lower half
+// of devid, so device 3-2 is 0x00030002.
+pub fn devid(busnum: u16, devnum: u16) -> u32 {
+    (busnum as u32) << 16 | devnum as u32
+}
+
+pub fn split_devid(devid: u32) -> (u16, u16) {
+    ((devid >> 16) as u16, devid as u16)
+}
+
+// A busid as the kernel names USB devices: the bus number, then the port on
+// the root hub and on each hub further down, e.g. 3-2 or 1-1.4.2
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub struct BusId(String);
+
+impl BusId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for BusId {
+    type Err = PacketError;
+
+    fn from_str(s: &str) -> PacketResult<BusId> {
+        fn number(s: &str) -> bool {
+            !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
         }
-        Ok(())
-    }    
+        let valid = match s.split_once('-') {
+            Some((bus, ports)) => number(bus) && ports.split('.').all(number),
+            None => false,
+        };
+        if !valid {
+            return Err(PacketError::PacketError(format!("Invalid busid {:?}, expected e.g. 3-2 or 1-1.4.2", s)));
+        }
+        // Has to fit the 32 byte field with its terminating NUL
+        if s.len() > 31 {
+            return Err(PacketError::PacketError(format!("Busid {} is longer than 31 bytes", s)));
+        }
+        Ok(BusId(s.to_string()))
+    }
 }
 
-fn read_fix_string(src: &mut dyn io::Read, len: usize) -> PacketResult<String> {
-    let mut buf = vec![0u8; len];
-    src.read_exact(&mut buf)?;
-    if !buf.is_ascii() {
-        return Err(PacketError::PacketError("Read string is not ASCII".to_string()));
+impl fmt::Display for BusId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
     }
-    let len = match buf.iter().position(|&x| x == 0) {
-        Some(i) => i,
-        None => buf.len()
-    };
-    let s = String::from_utf8(Vec::from(&buf[0..len]))?;
-    Ok(s)
 }
 
-fn write_fix_string(dst: &mut dyn io::Write, s: &str, size: usize) -> PacketResult<()> {
-    if s.len() > (size-1) { // We require one 0-byte at end
-        return Err(PacketError::PacketError("Write string is longer than buffer".to_string()));
+// The header word opening every packet, split into the protocol version (0 for
+// URB packets, whose commands fit into the lower half) and the command
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub struct PacketHeader {
+    pub version: u16,
+    pub command: u16,
+}
+
+impl PacketHeader {
+    pub fn from_u32(header: u32) -> PacketHeader {
+        PacketHeader { version: (header >> 16) as u16, command: header as u16 }
     }
-    if !s.is_ascii() {
-        return Err(PacketError::PacketError("Write string is not ASCII".to_string()));
+
+    pub fn to_u32(self) -> u32 {
+        (self.version as u32) << 16 | self.command as u32
     }
-    dst.write_all(s.as_bytes())?;
-    if s.len() < size {
-        let padding = vec![0u8; size-s.len()];
-        dst.write_all(&padding)?;
+
+    // The op packet command would be under USBIP_VERSION. A match on a header
+    // with another version points at a version mismatch rather than garbage.
+    pub fn known_command(self) -> Option<PacketTypes> {
+        match self.version {
+            0 => PacketTypes::from_u32(self.command as u32).ok(),
+            _ => PacketTypes::from_u32(op_header(self.command)).ok(),
+        }
     }
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use packet::{Packet, PacketResult, read_fix_string, write_fix_string, RepDevList,
-                 DeviceDescriptor, InterfaceDescriptor, ReqImport, RepImport};
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum PacketTypes {
+    ReqDevList = 0x01118005,
+    RepDevList = 0x01110005,
+    ReqImport = 0x01118003,
+    RepImport = 0x01110003,
+    CmdSubmit = 0x00000001,
+    RetSubmit = 0x00000003,
+    CmdUnlink = 0x00000002,
+    RetUnlink = 0x00000004,
+}
 
-    #[test]
-    fn test_read_fix_string() {
-        let data1 : Vec<u8>= vec!['a' as u8 ,'b' as u8, 'c' as u8, 0, 0 ];
-        assert_eq!(read_fix_string(&mut data1.as_slice(), 5).unwrap(), "abc");
-        assert_eq!(read_fix_string(&mut data1.as_slice(), 3).unwrap(), "abc");
+impl PacketTypes {
+    pub fn from_u32(val: u32) -> PacketResult<PacketTypes> {
+        match val {
+            OP_REQ_DEVLIST => Ok(PacketTypes::ReqDevList),
+            OP_REP_DEVLIST => Ok(PacketTypes::RepDevList),
+            OP_REQ_IMPORT => Ok(PacketTypes::ReqImport),
+            OP_REP_IMPORT => Ok(PacketTypes::RepImport),
+            USBIP_CMD_SUBMIT => Ok(PacketTypes::CmdSubmit),
+            USBIP_RET_SUBMIT => Ok(PacketTypes::RetSubmit),
+            USBIP_CMD_UNLINK => Ok(PacketTypes::CmdUnlink),
+            USBIP_RET_UNLINK => Ok(PacketTypes::RetUnlink),
+            x if (x >> 16) as u16 == USBIP_VERSION => {
+                Err(PacketError::PacketError(format!("Unknown op command: 0x{:04x}", x & 0xffff)))
+            },
+            x if (x >> 16) == 0 => {
+                Err(PacketError::PacketError(format!("Unknown URB command: 0x{:08x}", x)))
+            },
+            x => Err(PacketError::UnsupportedVersion(PacketHeader::from_u32(x)))
+        }
     }
+}
 
-    #[test]
-    fn test_write_fix_string() {
-        let s = "abc";
-        let mut buf = Vec::with_capacity(5);
-        write_fix_string(&mut buf, &s, 5).unwrap();
-        assert_eq!(buf, [97, 98, 99, 0, 0])
+impl Packet {
+    pub fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<Packet> {
+        Packet::read_with_limits(src, &ReadLimits::default())
     }
 
-    #[test]
-    fn test_req_device_list() {
-        let dl = Packet::ReqDevList;
-        let mut buf = Vec::new();
-        dl.write(&mut buf).unwrap();
-        println!("Original structure: {:?}", dl);
-        println!("Encoded: {:?}", buf);
-        let dec = Packet::read(&mut buf.as_slice()).unwrap();
-        println!("Decoded structure: {:?}", dec);
-        assert_eq!(dl, dec);
+    // The header word of the next packet (as read_op_header returns it, but
+    // unchecked) without consuming anything, e.g. to route it before reading.
+    // Only buffered bytes can be looked at: BufReader refills just once its
+    // buffer is drained, so a header split by the end of the buffer fails to
+    // peek and the packet has to be read as usual.
+    #[cfg(feature = "std")]
+    pub fn peek_type<R: io::BufRead + ?Sized>(src: &mut R) -> PacketResult<u32> {
+        let buf = src.fill_buf()?;
+        match buf.len() {
+            0 => Err(PacketError::ConnectionClosed),
+            n if n < 4 => Err(PacketError::PacketError(
+                format!("Only {} bytes of the next header are buffered, cannot peek", n))),
+            _ => Ok(BigEndian::read_u32(buf)),
+        }
     }
 
-    #[test]
-    fn test_rep_device_list() {
-        let dl = Packet::RepDevList(RepDevList {
+    pub fn read_with_limits<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        let span = spans::read();
+        let ptype = read_packet_type(src)?;
+        let pkt = Packet::read_body(ptype, src, limits).map_err(mid_packet)?;
+        spans::record(&span, &pkt);
+        Ok(pkt)
+    }
+
+    // Reads a packet sent by a server. The Linux stub (and the protocol spec)
+    // leave devid, direction and ep of every RET_SUBMIT at 0, so whether it
+    // carries data cannot be told from its header. direction_of gets the
+    // seqnum of a RetSubmit and names the direction of the submit it answers,
+    // which the reply then decodes (and comes back) with. If it returns None
+    // the header's direction is used, as by read, which is only right for
+    // peers that fill it in, e.g. this crate's servers or captures of them.
+    pub fn read_reply<R, D>(src: &mut R, limits: &ReadLimits, direction_of: D) -> PacketResult<Packet>
+        where R: ByteReader + ?Sized, D: FnOnce(u32) -> Option<Direction>
+    {
+        let span = spans::read();
+        let pkt = match read_packet_type(src)? {
+            PacketTypes::RetSubmit => RetSubmit::read_replying(src, limits, direction_of),
+            ptype => Packet::read_body(ptype, src, limits),
+        }.map_err(mid_packet)?;
+        spans::record(&span, &pkt);
+        Ok(pkt)
+    }
+
+    // Decodes like read, except that the payload of a CmdSubmit or RetSubmit
+    // lands in buf (reusing its capacity) and the returned view borrows it.
+    pub fn read_into<'a, R: ByteReader + ?Sized>(src: &mut R, buf: &'a mut Vec<u8>) -> PacketResult<PacketRef<'a>> {
+        Packet::read_into_with_limits(src, &ReadLimits::default(), buf)
+    }
+
+    pub fn read_into_with_limits<'a, R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits,
+                                                            buf: &'a mut Vec<u8>) -> PacketResult<PacketRef<'a>> {
+        let res = match read_packet_type(src)? {
+            PacketTypes::CmdSubmit => CmdSubmitRef::read(src, limits, buf).map(PacketRef::CmdSubmit),
+            PacketTypes::RetSubmit => RetSubmitRef::read(src, limits, buf).map(PacketRef::RetSubmit),
+            ptype => Packet::read_body(ptype, src, limits).map(PacketRef::Other),
+        };
+        res.map_err(mid_packet)
+    }
+
+    // Like read_with_limits, except that the payload of a CmdSubmit or
+    // RetSubmit is not buffered but handed to on_chunk as it comes in, at most
+    // 64 KiB at a time, e.g. for a proxy to pass it on before the whole URB
+    // has arrived. The packet returned then has data None, its buffer_length
+    // or length says how many bytes went to on_chunk.
+    //
+    // If the stream fails or ends within the payload, or on_chunk returns an
+    // error (which stops the read and is returned as is), the chunks handed
+    // over so far are all there is of the URB. The rest of the packet is still
+    // in the stream, so the connection cannot be read from any more.
+    pub fn read_with_payload_callback<R, F>(src: &mut R, limits: &ReadLimits, mut on_chunk: F) -> PacketResult<Packet>
+        where R: ByteReader + ?Sized, F: FnMut(&[u8]) -> PacketResult<()>
+    {
+        let chunks = |src: &mut R, len| read_payload_chunks(src, len, &mut on_chunk).map(|_| None);
+        let res = match read_packet_type(src)? {
+            PacketTypes::CmdSubmit => CmdSubmitRef::read_with(src, limits, chunks).map(|c| Packet::CmdSubmit(c.without_data())),
+            PacketTypes::RetSubmit => RetSubmitRef::read_with(src, limits, |_| None, chunks).map(|r| Packet::RetSubmit(r.without_data())),
+            ptype => Packet::read_body(ptype, src, limits),
+        };
+        res.map_err(mid_packet)
+    }
+
+    fn read_body<R: ByteReader + ?Sized>(ptype: PacketTypes, src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        match ptype {
+            PacketTypes::ReqDevList => Packet::read_req_devlist(src, limits),
+            PacketTypes::RepDevList => RepDevList::read(src, limits),
+            PacketTypes::ReqImport => ReqImport::read(src, limits),
+            PacketTypes::RepImport => RepImport::read(src),
+            PacketTypes::CmdSubmit => CmdSubmit::read(src, limits),
+            PacketTypes::RetSubmit => RetSubmit::read(src, limits),
+            PacketTypes::CmdUnlink => CmdUnlink::read(src),
+            PacketTypes::RetUnlink => RetUnlink::read(src),
+        }
+    }
+
+    pub fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        let _span = spans::write(self);
+        match *self {
+            Packet::ReqDevList => Packet::write_req_devlist(dst),
+            Packet::RepDevList(ref s) => s.write(dst),
+            Packet::ReqImport(ref s) => s.write(dst),
+            Packet::RepImport(ref s) => s.write(dst),
+            Packet::CmdSubmit(ref s) => s.write(dst),
+            Packet::RetSubmit(ref s) => s.write(dst),
+            Packet::CmdUnlink(ref s) => s.write(dst),
+            Packet::RetUnlink(ref s) => s.write(dst),
+        }    
+    }
+
+    /// Encodes the packet into a freshly allocated buffer.
+    ///
+    /// ```
+    /// use vusbip::protocol::Packet;
+    ///
+    /// let bytes = Packet::ReqDevList.write_to_vec().unwrap();
+    /// assert_eq!(bytes, [0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]);
+    /// ```
+    pub fn write_to_vec(&self) -> PacketResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.write(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decodes one packet from the start of `bytes`, anything after it is ignored.
+    ///
+    /// ```
+    /// use vusbip::protocol::Packet;
+    ///
+    /// let pkt = Packet::from_slice(&[0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]).unwrap();
+    /// assert_eq!(pkt, Packet::ReqDevList);
+    /// ```
+    pub fn from_slice(mut bytes: &[u8]) -> PacketResult<Packet> {
+        Packet::read(&mut bytes)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            Packet::ReqDevList => 8,
+            Packet::RepDevList(ref s) => s.encoded_len(),
+            Packet::ReqImport(_) => 8 + 32,
+            Packet::RepImport(_) => 8 + DEVICE_INFO_LEN,
+            Packet::CmdSubmit(ref s) => {
+                48 + s.data.as_ref().map_or(0, |d| d.len()) + 16 * s.iso_packets.len()
+            },
+            Packet::RetSubmit(ref s) => {
+                let data_len = match s.direction {
+                    Direction::In => s.data.as_ref().map_or(0, |d| d.len()),
+                    Direction::Out => 0,
+                };
+                48 + data_len + 16 * s.iso_packets.len()
+            },
+            Packet::CmdUnlink(_) => 48,
+            Packet::RetUnlink(_) => 48,
+        }
+    }
+
+    // The device a URB packet is for, None for op packets which belong to the
+    // connection as a whole
+    pub fn devid(&self) -> Option<u32> {
+        match *self {
+            Packet::CmdSubmit(ref s) => Some(s.devid),
+            Packet::RetSubmit(ref s) => Some(s.devid),
+            Packet::CmdUnlink(ref s) => Some(s.devid),
+            Packet::RetUnlink(ref s) => Some(s.devid),
+            _ => None,
+        }
+    }
+
+    // The packet's own seqnum (for a CmdUnlink its seq, not that of the URB
+    // it unlinks), None for op packets
+    pub fn seqnum(&self) -> Option<u32> {
+        match *self {
+            Packet::CmdSubmit(ref s) => Some(s.seqnum),
+            Packet::RetSubmit(ref s) => Some(s.seqnum),
+            Packet::CmdUnlink(ref s) => Some(s.seq),
+            Packet::RetUnlink(ref s) => Some(s.seqnum),
+            _ => None,
+        }
+    }
+
+    pub fn packet_type(&self) -> PacketTypes {
+        match *self {
+            Packet::ReqDevList => PacketTypes::ReqDevList,
+            Packet::RepDevList(_) => PacketTypes::RepDevList,
+            Packet::ReqImport(_) => PacketTypes::ReqImport,
+            Packet::RepImport(_) => PacketTypes::RepImport,
+            Packet::CmdSubmit(_) => PacketTypes::CmdSubmit,
+            Packet::RetSubmit(_) => PacketTypes::RetSubmit,
+            Packet::CmdUnlink(_) => PacketTypes::CmdUnlink,
+            Packet::RetUnlink(_) => PacketTypes::RetUnlink,
+        }
+    }
+
+    fn read_req_devlist<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        if limits.request_status {
+            Fields::new(src, "ReqDevList").u32("status")?;
+        }
+        Ok(Packet::ReqDevList)
+    }
+
+    fn write_req_devlist<W: ByteWriter + ?Sized>(dst: &mut W) -> PacketResult<()> {
+        write_op_header(dst, PacketTypes::ReqDevList as u32)?;
+        dst.write_u32::<BigEndian>(0)?;
+        Ok(())
+    }    
+}
+
+// One line per packet for logs, leaving out paths and payloads. URB statuses
+// are negative errno values and shown as such.
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Packet::ReqDevList => write!(f, "ReqDevList"),
+            Packet::RepDevList(ref s) => write!(f, "RepDevList status={} devices={}", s.status, s.devices.len()),
+            Packet::ReqImport(ref s) => write!(f, "ReqImport busid={}", s.busid),
+            Packet::RepImport(ref s) => write!(f, "RepImport status={} busid={}", s.status, s.busid),
+            Packet::CmdSubmit(ref s) => {
+                let (bus, dev) = s.bus_dev();
+                write!(f, "CmdSubmit seq={} dev={}-{} ep={} {} len={}", s.seqnum, bus, dev, s.ep, s.direction, s.buffer_length)?;
+                if !s.iso_packets.is_empty() {
+                    write!(f, " iso={}", s.iso_packets.len())?;
+                }
+                Ok(())
+            },
+            Packet::RetSubmit(ref s) => {
+                let (bus, dev) = s.bus_dev();
+                write!(f, "RetSubmit seq={} dev={}-{} ep={} {} status={} len={}",
+                       s.seqnum, bus, dev, s.ep, s.direction, s.status as i32, s.length)?;
+                if !s.iso_packets.is_empty() {
+                    write!(f, " iso={}", s.iso_packets.len())?;
+                }
+                Ok(())
+            },
+            Packet::CmdUnlink(ref s) => {
+                let (bus, dev) = split_devid(s.devid);
+                write!(f, "CmdUnlink seq={} dev={}-{} unlink={}", s.seq, bus, dev, s.seqnum)
+            },
+            Packet::RetUnlink(ref s) => {
+                let (bus, dev) = split_devid(s.devid);
+                write!(f, "RetUnlink seq={} dev={}-{} status={}", s.seqnum, bus, dev, s.status as i32)
+            },
+        }
+    }
+}
+
+/// Decodes like `Packet::from_slice`, anything after the packet is ignored.
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use vusbip::protocol::{Packet, ReqImport};
+///
+/// let mut bytes = vec![0x01, 0x11, 0x80, 0x03, 0, 0, 0, 0];
+/// bytes.extend_from_slice(b"3-2");
+/// bytes.resize(8 + 32, 0);
+/// let pkt = Packet::try_from(bytes.as_slice()).unwrap();
+/// assert_eq!(pkt, Packet::ReqImport(ReqImport { busid: "3-2".to_string() }));
+/// ```
+impl<'a> TryFrom<&'a [u8]> for Packet {
+    type Error = PacketError;
+
+    fn try_from(bytes: &'a [u8]) -> PacketResult<Packet> {
+        Packet::from_slice(bytes)
+    }
+}
+
+/// Encodes like `Packet::write_to_vec`.
+///
+/// ```
+/// use std::convert::TryInto;
+/// use vusbip::protocol::{Packet, ReqImport};
+///
+/// let pkt = Packet::ReqImport(ReqImport { busid: "3-2".to_string() });
+/// let bytes: Vec<u8> = (&pkt).try_into().unwrap();
+/// assert_eq!(bytes.len(), 8 + 32);
+/// assert_eq!(&bytes[..11], b"\x01\x11\x80\x03\0\0\0\x003-2");
+/// ```
+impl<'a> TryFrom<&'a Packet> for Vec<u8> {
+    type Error = PacketError;
+
+    fn try_from(pkt: &'a Packet) -> PacketResult<Vec<u8>> {
+        pkt.write_to_vec()
+    }
+}
+
+// Encodes packet after packet into the same buffer, e.g. for a server loop
+// writing replies, which then only allocates while the buffer still grows.
+#[derive(Debug,Default)]
+pub struct PacketEncoder {
+    buf: Vec<u8>,
+}
+
+impl PacketEncoder {
+    pub fn new() -> PacketEncoder {
+        PacketEncoder::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> PacketEncoder {
+        PacketEncoder { buf: Vec::with_capacity(capacity) }
+    }
+
+    // The bytes of pkt, valid until the next encode
+    pub fn encode(&mut self, pkt: &Packet) -> PacketResult<&[u8]> {
+        self.buf.clear();
+        self.buf.reserve(pkt.encoded_len());
+        if let Err(e) = pkt.write(&mut self.buf) {
+            self.buf.clear();
+            return Err(e);
+        }
+        Ok(&self.buf)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    pub fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        match *self {
+            PacketRef::CmdSubmit(ref s) => s.write(dst),
+            PacketRef::RetSubmit(ref s) => s.write(dst),
+            PacketRef::Other(ref p) => p.write(dst),
+        }
+    }
+
+    pub fn into_owned(self) -> Packet {
+        match self {
+            PacketRef::CmdSubmit(s) => Packet::CmdSubmit(s.into_owned()),
+            PacketRef::RetSubmit(s) => Packet::RetSubmit(s.into_owned()),
+            PacketRef::Other(p) => p,
+        }
+    }
+}
+
+impl RepDevList {
+    pub fn status(&self) -> UsbIpStatus {
+        UsbIpStatus::from_u32(self.status)
+    }
+
+    // Bytes written for the whole reply: the op header, status and device
+    // count, then each device with its interfaces
+    pub fn encoded_len(&self) -> usize {
+        12 + self.devices.iter().map(|d| d.encoded_len()).sum::<usize>()
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        let mut f = Fields::new(src, "RepDevList");
+        let status = f.u32("status")?;
+        // A failed reply may stop after the status, like a failed import
+        if status != 0 {
+            skip_optional(f.src, 4)?;
+            return Ok(Packet::RepDevList(RepDevList{ status, num_devices: 0, devices: Vec::new() }));
+        }
+        let num_devices = f.u32("num_devices")?;
+        if num_devices > limits.max_devices {
+            return Err(PacketError::PacketError(
+                format!("num_devices {} exceeds limit of {}", num_devices, limits.max_devices)));
+        }
+        // A server exporting nothing sends num_devices = 0 and no descriptors
+        let mut devices = Vec::new();
+        for _ in 0..num_devices {
+            let device = DeviceDescriptor::read_with_limits(f.src, limits)?;
+            devices.push(device);
+        }
+        Ok(Packet::RepDevList(RepDevList{ status, num_devices, devices }))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        if self.devices.len() != self.num_devices as usize {
+            return Err(PacketError::PacketError(
+                format!("Device list declares {} devices but has {}", self.num_devices, self.devices.len())));
+        }
+        write_op_header(dst, PacketTypes::RepDevList as u32)?;
+        dst.write_u32::<BigEndian>(self.status)?;
+        dst.write_u32::<BigEndian>(self.num_devices)?;
+        for dev in &self.devices {
+            dev.write(dst)?;
+        }
+        Ok(())
+    }
+}
+
+// path, busid, busnum, devnum, speed, ids, bcd_device and the six class/config bytes
+pub(crate) const DEVICE_INFO_LEN: usize = 256 + 32 + 3 * 4 + 3 * 2 + 6;
+
+impl DeviceDescriptor {
+    pub fn speed(&self) -> UsbSpeed {
+        UsbSpeed::from_u32(self.speed)
+    }
+
+    // bcd_device as major.minor, e.g. 0x0110 gives "1.10"
+    pub fn device_version(&self) -> String {
+        bcd_version(self.bcd_device)
+    }
+
+    // Each interface along with the name of its class
+    pub fn interface_classes(&self) -> impl Iterator<Item = (&InterfaceDescriptor, &'static str)> + '_ {
+        self.interfaces.iter().map(|i| (i, i.class_name()))
+    }
+
+    // Whether an import reply describes this (listed) device, so a client can
+    // notice a server handing back a different device than the one it chose.
+    pub fn matches(&self, rep: &RepImport) -> bool {
+        self.busid == rep.busid && self.busnum == rep.busnum && self.devnum == rep.devnum
+            && self.id_vendor == rep.id_vendor && self.id_product == rep.id_product
+    }
+
+    // Checks the fields that depend on each other, for a server to catch a
+    // badly put together descriptor before listing it. Only the interface
+    // count is also enforced by write, descriptors read from a peer are not
+    // held to any of this (see read_with_limits).
+    //
+    // With device class 0 the class is up to each interface, so the device
+    // subclass and protocol must be 0 as well and no interface may leave its
+    // class at 0. Every device has at least one configuration. Whether the
+    // device answers GET_DESCRIPTOR for all of them is beyond what a
+    // descriptor can tell.
+    pub fn validate(&self) -> PacketResult<()> {
+        self.check_interface_count()?;
+        if self.num_configurations == 0 {
+            return Err(PacketError::PacketError(format!("Device {} has no configurations", self.busid)));
+        }
+        if self.device_class == 0 {
+            if self.device_subclass != 0 || self.device_protocol != 0 {
+                return Err(PacketError::PacketError(format!(
+                    "Device {} has class 0 but subclass {} and protocol {}",
+                    self.busid, self.device_subclass, self.device_protocol)));
+            }
+            if let Some(n) = self.interfaces.iter().position(|i| i.interface_class == 0) {
+                return Err(PacketError::PacketError(format!(
+                    "Interface {} of device {} has class 0, but the device leaves the class to its interfaces",
+                    n, self.busid)));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_interface_count(&self) -> PacketResult<()> {
+        if self.interfaces.len() != self.num_interfaces as usize {
+            return Err(PacketError::PacketError(
+                format!("Device {} declares {} interfaces but has {}", self.busid, self.num_interfaces, self.interfaces.len())));
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        DEVICE_INFO_LEN + 4 * self.interfaces.len()
+    }
+
+    /// Decodes a single descriptor and its interfaces, laid out as in a
+    /// device list reply but without the surrounding packet.
+    ///
+    /// ```
+    /// use vusbip::protocol::{DeviceDescriptor, InterfaceDescriptor};
+    ///
+    /// let dev = DeviceDescriptor {
+    ///     path: "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2".to_string(),
+    ///     busid: "1-2".to_string(),
+    ///     busnum: 1,
+    ///     devnum: 4,
+    ///     speed: 2,
+    ///     id_vendor: 0x046d,
+    ///     id_product: 0xc52b,
+    ///     bcd_device: 0x1211,
+    ///     device_class: 0,
+    ///     device_subclass: 0,
+    ///     device_protocol: 0,
+    ///     configuration_value: 1,
+    ///     num_configurations: 1,
+    ///     num_interfaces: 1,
+    ///     interfaces: vec![InterfaceDescriptor { interface_class: 3, interface_subclass: 1, interface_protocol: 2 }],
+    /// };
+    /// let mut buf = Vec::new();
+    /// dev.write(&mut buf).unwrap();
+    /// assert_eq!(buf.len(), 312 + 4);
+    /// assert_eq!(DeviceDescriptor::read(&mut buf.as_slice()).unwrap(), dev);
+    /// ```
+    pub fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<DeviceDescriptor> {
+        DeviceDescriptor::read_with_limits(src, &ReadLimits::default())
+    }
+
+    // bcd_device, speed and the class triplet are taken as sent, composite
+    // devices come with 0, the IAD triplet 0xef/0x02/0x01 or their first
+    // interface's class depending on device and server (e.g. usbipd-win).
+    // Only the interface count is checked.
+    pub fn read_with_limits<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<DeviceDescriptor> {
+        let mut f = Fields::new(src, "DeviceDescriptor");
+        let path = f.fix_string("path", 256)?;
+        let busid = f.fix_ascii("busid", 32)?;
+        let busnum = f.u32("busnum")?;
+        let devnum = f.u32("devnum")?;
+        let speed = f.u32("speed")?;
+        let id_vendor = f.u16("id_vendor")?;
+        let id_product = f.u16("id_product")?;
+        let bcd_device = f.u16("bcd_device")?;
+        let device_class = f.u8("device_class")?;
+        let device_subclass = f.u8("device_subclass")?;
+        let device_protocol = f.u8("device_protocol")?;
+        let configuration_value = f.u8("configuration_value")?;
+        let num_configurations = f.u8("num_configurations")?;
+        let num_interfaces = f.u8("num_interfaces")?;
+        if num_interfaces > limits.max_interfaces {
+            return Err(PacketError::PacketError(
+                format!("Device {} declares {} interfaces, at most {} are allowed", busid, num_interfaces, limits.max_interfaces)));
+        }
+        let mut interfaces = Vec::new();
+        for _ in 0..num_interfaces {
+            let interface = match InterfaceDescriptor::read_padded(f.src, limits.padded_interfaces) {
+                Ok(i) => i,
+                Err(ref e) if e.kind() == PacketErrorKind::Truncated => return Err(PacketError::PacketError(
+                    format!("Device {} declared {} interfaces, stream ended after {}", busid, num_interfaces, interfaces.len()))),
+                Err(e) => return Err(e),
+            };
+            interfaces.push(interface);
+        }
+        Ok(DeviceDescriptor{
+            path, busid, busnum, devnum, speed, id_vendor, id_product,
+            bcd_device, device_class, device_subclass, device_protocol,
+            configuration_value, num_configurations, num_interfaces, interfaces
+        })
+    }
+
+    pub fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        self.check_interface_count()?;
+        write_fix_string(dst, &self.path, 256)?;
+        write_fix_ascii(dst, &self.busid, 32)?;
+        dst.write_u32::<BigEndian>(self.busnum)?;
+        dst.write_u32::<BigEndian>(self.devnum)?;
+        dst.write_u32::<BigEndian>(self.speed)?;
+        dst.write_u16::<BigEndian>(self.id_vendor)?;
+        dst.write_u16::<BigEndian>(self.id_product)?;
+        dst.write_u16::<BigEndian>(self.bcd_device)?;
+        dst.write_u8(self.device_class)?;
+        dst.write_u8(self.device_subclass)?;
+        dst.write_u8(self.device_protocol)?;
+        dst.write_u8(self.configuration_value)?;
+        dst.write_u8(self.num_configurations)?;
+        dst.write_u8(self.num_interfaces)?;
+        for interface in &self.interfaces {
+            interface.write(dst)?;
+        }
+        Ok(())
+    }
+}
+
+impl InterfaceDescriptor {
+    // Name of the USB base class, as assigned by usb.org
+    pub fn class_name(&self) -> &'static str {
+        match self.interface_class {
+            0x01 => "Audio",
+            0x02 => "Communications (CDC)",
+            0x03 => "HID",
+            0x05 => "Physical",
+            0x06 => "Image",
+            0x07 => "Printer",
+            0x08 => "Mass Storage",
+            0x09 => "Hub",
+            0x0a => "CDC Data",
+            0x0b => "Smart Card",
+            0x0d => "Content Security",
+            0x0e => "Video",
+            0x0f => "Personal Healthcare",
+            0x10 => "Audio/Video",
+            0x11 => "Billboard",
+            0x12 => "USB Type-C Bridge",
+            0xdc => "Diagnostic",
+            0xe0 => "Wireless Controller",
+            0xef => "Miscellaneous",
+            0xfe => "Application Specific",
+            0xff => "Vendor Specific",
+            _ => "Unknown",
+        }
+    }
+
+    // Without padded, the 3 bytes of a packed interface as some non-kernel
+    // servers send them
+    pub fn read_padded<R: ByteReader + ?Sized>(src: &mut R, padded: bool) -> PacketResult<InterfaceDescriptor> {
+        let mut f = Fields::new(src, "InterfaceDescriptor");
+        let interface_class = f.u8("interface_class")?;
+        let interface_subclass = f.u8("interface_subclass")?;
+        let interface_protocol = f.u8("interface_protocol")?;
+        if padded {
+            f.u8("padding")?;
+        }
+        Ok(InterfaceDescriptor{
+            interface_class, interface_subclass, interface_protocol
+        })
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        dst.write_u8(self.interface_class)?;
+        dst.write_u8(self.interface_subclass)?;
+        dst.write_u8(self.interface_protocol)?;
+        dst.write_u8(0u8)?;    // Padding
+        Ok(())
+    }
+}
+
+impl ReqImport {
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        let mut f = Fields::new(src, "ReqImport");
+        let status = if limits.request_status { f.u32("status")? } else { 0 };
+        let busid = f.fix_ascii("busid", 32)?;
+        // Reserved in requests, anything else means a confused or hostile client
+        if status != 0 {
+            return Err(PacketError::PacketError(
+                format!("Import of {} has nonzero reserved status 0x{:08x}", busid, status)));
+        }
+        Ok(Packet::ReqImport(ReqImport{ busid }))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        write_op_header(dst, PacketTypes::ReqImport as u32)?;
+        dst.write_u32::<BigEndian>(0)?;
+        write_fix_ascii(dst, &self.busid, 32)?;
+        Ok(())
+    }    
+}
+
+/*    pub status: u32,
+    pub path: String,
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8*/
+impl RepImport {
+    pub fn status(&self) -> UsbIpStatus {
+        UsbIpStatus::from_u32(self.status)
+    }
+
+    pub fn speed(&self) -> UsbSpeed {
+        UsbSpeed::from_u32(self.speed)
+    }
+
+    // bcd_device as major.minor, e.g. 0x0110 gives "1.10"
+    pub fn device_version(&self) -> String {
+        bcd_version(self.bcd_device)
+    }
+
+    pub fn failed(status: UsbIpStatus) -> RepImport {
+        RepImport {
+            status: status.as_u32(), path: "".to_string(), busid: "".to_string(),
+            busnum: 0, devnum: 0, speed: 0, id_vendor: 0, id_product: 0,
+            bcd_device: 0, device_class: 0, device_subclass: 0, device_protocol: 0,
+            configuration_value: 0, num_configurations: 0, num_interfaces: 0
+        }
+    }
+
+    // The reply to importing dev, which carries everything but the interfaces
+    pub fn from_device(dev: &DeviceDescriptor, status: u32) -> RepImport {
+        RepImport {
+            status,
+            path: dev.path.clone(),
+            busid: dev.busid.clone(),
+            busnum: dev.busnum,
+            devnum: dev.devnum,
+            speed: dev.speed,
+            id_vendor: dev.id_vendor,
+            id_product: dev.id_product,
+            bcd_device: dev.bcd_device,
+            device_class: dev.device_class,
+            device_subclass: dev.device_subclass,
+            device_protocol: dev.device_protocol,
+            configuration_value: dev.configuration_value,
+            num_configurations: dev.num_configurations,
+            num_interfaces: dev.num_interfaces,
+        }
+    }
+
+    // A failed import is padded to the full reply size, but the Linux usbipd
+    // sends only the status and hangs up, so the padding is optional here.
+    fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<Packet> {
+        let mut f = Fields::new(src, "RepImport");
+        let status = f.u32("status")?;
+        if status != 0x0 {
+            skip_optional(f.src, DEVICE_INFO_LEN)?;
+            return Ok(Packet::RepImport(RepImport::failed(UsbIpStatus::from_u32(status))));
+        }
+        let path = f.fix_string("path", 256)?;
+        let busid = f.fix_ascii("busid", 32)?;
+        let busnum = f.u32("busnum")?;
+        let devnum = f.u32("devnum")?;
+        let speed = f.u32("speed")?;
+        let id_vendor = f.u16("id_vendor")?;
+        let id_product = f.u16("id_product")?;
+        let bcd_device = f.u16("bcd_device")?;
+        let device_class = f.u8("device_class")?;
+        let device_subclass = f.u8("device_subclass")?;
+        let device_protocol = f.u8("device_protocol")?;
+        let configuration_value = f.u8("configuration_value")?;
+        let num_configurations = f.u8("num_configurations")?;
+        let num_interfaces = f.u8("num_interfaces")?;
+        Ok(Packet::RepImport(RepImport{ 
+            status, path, busid, busnum, devnum, speed, id_vendor, id_product, bcd_device,
+            device_class, device_subclass, device_protocol, configuration_value,
+            num_configurations, num_interfaces
+        }))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        write_op_header(dst, PacketTypes::RepImport as u32)?;
+        dst.write_u32::<BigEndian>(self.status)?;
+        if self.status != 0 {
+            dst.write_all(&[0u8; DEVICE_INFO_LEN])?;
+            return Ok(());
+        }
+        write_fix_string(dst, &self.path, 256)?;
+        write_fix_ascii(dst, &self.busid, 32)?;
+        dst.write_u32::<BigEndian>(self.busnum)?;
+        dst.write_u32::<BigEndian>(self.devnum)?;
+        dst.write_u32::<BigEndian>(self.speed)?;
+        dst.write_u16::<BigEndian>(self.id_vendor)?;
+        dst.write_u16::<BigEndian>(self.id_product)?;
+        dst.write_u16::<BigEndian>(self.bcd_device)?;
+        dst.write_u8(self.device_class)?;
+        dst.write_u8(self.device_subclass)?;
+        dst.write_u8(self.device_protocol)?;
+        dst.write_u8(self.configuration_value)?;
+        dst.write_u8(self.num_configurations)?;
+        dst.write_u8(self.num_interfaces)?;
+        Ok(())
+    }    
+}
+
+// All zeroes on the wire: a zero-length OUT to endpoint 0 of devid 0 without
+// flags, which like every OUT carries Some (empty) data
+impl Default for CmdSubmit {
+    fn default() -> CmdSubmit {
+        CmdSubmit {
+            seqnum: 0,
+            devid: 0,
+            direction: Direction::Out,
+            ep: 0,
+            transfer_flags: TransferFlags::empty(),
+            buffer_length: 0,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup: [0u8; 8],
+            data: Some(Vec::new()),
+            iso_packets: Vec::new()
+        }
+    }
+}
+
+impl CmdSubmit {
+    pub fn bus_dev(&self) -> (u16, u16) {
+        split_devid(self.devid)
+    }
+
+    pub fn endpoint(&self) -> EndpointAddress {
+        EndpointAddress { number: (self.ep & 0x0f) as u8, direction: self.direction }
+    }
+
+    pub fn setup_packet(&self) -> UsbSetupPacket {
+        UsbSetupPacket::from_bytes(&self.setup)
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        if self.ep == 0 {
+            TransferType::Control
+        } else if !self.iso_packets.is_empty() {
+            TransferType::Isochronous
+        } else if self.interval != 0 {
+            TransferType::Interrupt
+        } else {
+            TransferType::Bulk
+        }
+    }
+
+    // The setup field is always on the wire, but only control transfers may
+    // put anything into it.
+    pub fn check_setup(&self) -> PacketResult<()> {
+        if self.transfer_type() != TransferType::Control && self.setup != [0u8; 8] {
+            return Err(PacketError::PacketError(
+                format!("Setup bytes {:?} on non-control endpoint {}", self.setup, self.ep)));
+        }
+        Ok(())
+    }
+
+    pub fn as_view(&self) -> CmdSubmitRef<'_> {
+        CmdSubmitRef {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            direction: self.direction,
+            ep: self.ep,
+            transfer_flags: self.transfer_flags,
+            buffer_length: self.buffer_length,
+            start_frame: self.start_frame,
+            num_packets: self.num_packets,
+            interval: self.interval,
+            setup: self.setup,
+            data: self.data.as_deref(),
+            iso_packets: Cow::Borrowed(&self.iso_packets)
+        }
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        // Hand the buffer over instead of copying it out of the view
+        let mut buf = Vec::new();
+        let view = CmdSubmitRef::read(src, limits, &mut buf)?;
+        let has_data = view.data.is_some();
+        let mut cmd = view.without_data();
+        if has_data {
+            cmd.data = Some(buf);
+        }
+        Ok(Packet::CmdSubmit(cmd))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        self.as_view().write(dst)
+    }
+}
+
+impl<'a> CmdSubmitRef<'a> {
+    pub fn into_owned(self) -> CmdSubmit {
+        let data = self.data.map(|d| d.to_vec());
+        CmdSubmit { data, ..self.without_data() }
+    }
+
+    fn without_data(self) -> CmdSubmit {
+        CmdSubmit {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            direction: self.direction,
+            ep: self.ep,
+            transfer_flags: self.transfer_flags,
+            buffer_length: self.buffer_length,
+            start_frame: self.start_frame,
+            num_packets: self.num_packets,
+            interval: self.interval,
+            setup: self.setup,
+            data: None,
+            iso_packets: self.iso_packets.into_owned()
+        }
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits, buf: &'a mut Vec<u8>) -> PacketResult<CmdSubmitRef<'a>> {
+        CmdSubmitRef::read_with(src, limits, move |src, len| read_payload(src, buf, len).map(Some))
+    }
+
+    // Like read, but the payload of buffer_length bytes is left to payload
+    fn read_with<R, P>(src: &mut R, limits: &ReadLimits, payload: P) -> PacketResult<CmdSubmitRef<'a>>
+        where R: ByteReader + ?Sized, P: FnOnce(&mut R, u32) -> PacketResult<Option<&'a [u8]>>
+    {
+        let mut f = Fields::new(src, "CmdSubmit");
+        let seqnum = f.u32("seqnum")?;
+        trace!("Seqnum: {:?}", seqnum);
+        let devid = f.u32("devid")?;
+        trace!("Devid: {:?}", devid);
+        let direction = f.direction("direction")?;
+        trace!("Direction: {:?}", direction);
+        let ep = f.u32("ep")?;
+        trace!("Ep: {:?}", ep);
+        let transfer_flags = TransferFlags::from_u32(f.u32("transfer_flags")?, limits.strict_transfer_flags)?;
+        trace!("flags: {:?}", transfer_flags);
+        let buffer_length = f.u32("buffer_length")?;
+        trace!("Buffer_length: {:?}", buffer_length);
+        let start_frame = f.u32("start_frame")?;
+        trace!("Start_frame: {:?}", start_frame);
+        let num_packets = f.u32("num_packets")?;
+        trace!("Num_Packets: {:?}", num_packets);
+        let interval = f.u32("interval")?;
+        trace!("Interval: {:?}", interval);
+        let mut setup = [0u8; 8];
+        f.bytes("setup", &mut setup)?;
+        trace!("Setup: {:?}", setup);
+        let mut data: Option<&'a [u8]> = None;
+        if direction == Direction::Out {
+            limits.check_payload(buffer_length)?;
+            data = f.with("data", move |src| payload(src, buffer_length))?;
+            trace!("Data: {:?}", data);
+        }
+        let iso_packets = Cow::Owned(IsoPacketDescriptor::read_all(f.src, num_packets)?);
+        Ok(CmdSubmitRef{
+            seqnum, devid, direction, ep, transfer_flags, buffer_length,
+            start_frame, num_packets, interval, setup, data, iso_packets
+        })
+    }
+
+    // The payload goes out with OUT submits only and a reader takes its length
+    // from buffer_length, so anything else would desync the stream. Whether
+    // there is data is down to the direction alone: OUT submits always have
+    // Some, which is empty for a zero-length OUT (e.g. a control transfer
+    // without data stage), IN submits always None, also with buffer_length 0.
+    // Reads produce exactly this, so a decoded submit writes back unchanged.
+    fn check_data(&self) -> PacketResult<()> {
+        match (self.direction, self.data) {
+            (Direction::In, Some(d)) => Err(PacketError::PacketError(
+                format!("IN submit {} carries {} bytes of data", self.seqnum, d.len()))),
+            (Direction::Out, None) => Err(PacketError::PacketError(
+                format!("OUT submit {} has no data", self.seqnum))),
+            (Direction::Out, Some(d)) if d.len() != self.buffer_length as usize => Err(PacketError::PacketError(
+                format!("OUT submit {} has {} bytes of data, buffer_length {}", self.seqnum, d.len(), self.buffer_length))),
+            _ => Ok(()),
+        }
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        self.check_data()?;
+        write_op_header(dst, PacketTypes::CmdSubmit as u32)?;
+        dst.write_u32::<BigEndian>(self.seqnum)?;
+        dst.write_u32::<BigEndian>(self.devid)?;
+        self.direction.write(dst)?;
+        dst.write_u32::<BigEndian>(self.ep)?;
+        dst.write_u32::<BigEndian>(self.transfer_flags.bits())?;
+        dst.write_u32::<BigEndian>(self.buffer_length)?;
+        dst.write_u32::<BigEndian>(self.start_frame)?;
+        dst.write_u32::<BigEndian>(self.num_packets)?;
+        dst.write_u32::<BigEndian>(self.interval)?;
+        dst.write_all(&self.setup)?;
+        if let Some(dv) = self.data {
+            dst.write_all(dv)?;
+        }
+        for iso in self.iso_packets.iter() {
+            iso.write(dst)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct CmdSubmitBuilder {
+    seqnum: u32,
+    devid: u32,
+    direction: Direction,
+    ep: u32,
+    transfer_flags: TransferFlags,
+    buffer_length: Option<u32>,
+    start_frame: u32,
+    num_packets: Option<u32>,
+    interval: u32,
+    setup: [u8; 8],
+    data: Option<Vec<u8>>,
+    iso_packets: Vec<IsoPacketDescriptor>
+}
+
+impl CmdSubmitBuilder {
+    pub fn new(direction: Direction, ep: u32) -> CmdSubmitBuilder {
+        CmdSubmitBuilder {
+            seqnum: 0,
+            devid: 0,
+            direction,
+            ep,
+            transfer_flags: TransferFlags::empty(),
+            buffer_length: None,
+            start_frame: 0,
+            num_packets: None,
+            interval: 0,
+            setup: [0u8; 8],
+            data: None,
+            iso_packets: Vec::new()
+        }
+    }
+
+    pub fn seqnum(mut self, seqnum: u32) -> CmdSubmitBuilder {
+        self.seqnum = seqnum;
+        self
+    }
+
+    pub fn devid(mut self, devid: u32) -> CmdSubmitBuilder {
+        self.devid = devid;
+        self
+    }
+
+    pub fn transfer_flags(mut self, transfer_flags: TransferFlags) -> CmdSubmitBuilder {
+        self.transfer_flags = transfer_flags;
+        self
+    }
+
+    pub fn buffer_length(mut self, buffer_length: u32) -> CmdSubmitBuilder {
+        self.buffer_length = Some(buffer_length);
+        self
+    }
+
+    pub fn start_frame(mut self, start_frame: u32) -> CmdSubmitBuilder {
+        self.start_frame = start_frame;
+        self
+    }
+
+    pub fn num_packets(mut self, num_packets: u32) -> CmdSubmitBuilder {
+        self.num_packets = Some(num_packets);
+        self
+    }
+
+    pub fn interval(mut self, interval: u32) -> CmdSubmitBuilder {
+        self.interval = interval;
+        self
+    }
+
+    pub fn setup(mut self, setup: [u8; 8]) -> CmdSubmitBuilder {
+        self.setup = setup;
+        self
+    }
+
+    pub fn data(mut self, data: Vec<u8>) -> CmdSubmitBuilder {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn iso_packets(mut self, iso_packets: Vec<IsoPacketDescriptor>) -> CmdSubmitBuilder {
+        self.iso_packets = iso_packets;
+        self
+    }
+
+    // Unless given explicitly, buffer_length follows the OUT payload and
+    // num_packets the number of iso descriptors. An OUT without data is a
+    // zero-length one, its data is Some(empty) as check_data wants it.
+    pub fn build(self) -> CmdSubmit {
+        let buffer_length = match (self.buffer_length, self.direction, &self.data) {
+            (Some(len), _, _) => len,
+            (None, Direction::Out, Some(dv)) => dv.len() as u32,
+            (None, _, _) => 0,
+        };
+        let num_packets = self.num_packets.unwrap_or(self.iso_packets.len() as u32);
+        let data = match (self.direction, self.data) {
+            (Direction::Out, None) if buffer_length == 0 => Some(Vec::new()),
+            (_, data) => data,
+        };
+        CmdSubmit {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            direction: self.direction,
+            ep: self.ep,
+            transfer_flags: self.transfer_flags,
+            buffer_length,
+            start_frame: self.start_frame,
+            num_packets,
+            interval: self.interval,
+            setup: self.setup,
+            data,
+            iso_packets: self.iso_packets
+        }
+    }
+}
+
+impl UsbSetupPacket {
+    // Unlike the surrounding USB/IP header, the setup packet is little-endian per the USB spec
+    pub fn from_bytes(buf: &[u8; 8]) -> UsbSetupPacket {
+        UsbSetupPacket {
+            request_type: buf[0],
+            request: buf[1],
+            value: LittleEndian::read_u16(&buf[2..4]),
+            index: LittleEndian::read_u16(&buf[4..6]),
+            length: LittleEndian::read_u16(&buf[6..8])
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[0] = self.request_type;
+        buf[1] = self.request;
+        LittleEndian::write_u16(&mut buf[2..4], self.value);
+        LittleEndian::write_u16(&mut buf[4..6], self.index);
+        LittleEndian::write_u16(&mut buf[6..8], self.length);
+        buf
+    }
+}
+
+impl RetSubmit {
+    pub fn status(&self) -> UsbIpStatus {
+        UsbIpStatus::from_u32(self.status)
+    }
+
+    pub fn bus_dev(&self) -> (u16, u16) {
+        split_devid(self.devid)
+    }
+
+    // The iso packets that failed, by index. error_count in the header is the
+    // server's tally of these, which not every server fills in.
+    pub fn failed_iso_packets(&self) -> impl Iterator<Item = (usize, &IsoPacketDescriptor)> {
+        self.iso_packets.iter().enumerate().filter(|&(_, iso)| !iso.is_ok())
+    }
+
+    pub fn iso_error_count(&self) -> u32 {
+        self.failed_iso_packets().count() as u32
+    }
+
+    // A device may return less than it was asked for, but never more
+    pub fn check_length(&self, cmd: &CmdSubmit) -> PacketResult<()> {
+        if self.length > cmd.buffer_length {
+            return Err(PacketError::PacketError(
+                format!("Reply {} has length {}, more than the buffer_length {} submitted", self.seqnum, self.length, cmd.buffer_length)));
+        }
+        Ok(())
+    }
+
+    pub fn as_view(&self) -> RetSubmitRef<'_> {
+        RetSubmitRef {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            direction: self.direction,
+            ep: self.ep,
+            status: self.status,
+            length: self.length,
+            start_frame: self.start_frame,
+            num_packets: self.num_packets,
+            error_count: self.error_count,
+            setup: self.setup,
+            data: self.data.as_deref(),
+            iso_packets: Cow::Borrowed(&self.iso_packets)
+        }
+    }
+
+    // Decodes a whole RET_SUBMIT packet as the reply to a submit in
+    // direction, whatever direction its header says (see Packet::read_reply).
+    // The reply comes back with the direction of the submit.
+    pub fn read_for<R: ByteReader + ?Sized>(src: &mut R, direction: Direction) -> PacketResult<RetSubmit> {
+        match Packet::read_reply(src, &ReadLimits::default(), |_| Some(direction))? {
+            Packet::RetSubmit(ret) => Ok(ret),
+            p => Err(PacketError::PacketError(format!("Expected RetSubmit, got {:?}", p.packet_type()))),
+        }
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits) -> PacketResult<Packet> {
+        RetSubmit::read_replying(src, limits, |_| None)
+    }
+
+    fn read_replying<R, D>(src: &mut R, limits: &ReadLimits, direction_of: D) -> PacketResult<Packet>
+        where R: ByteReader + ?Sized, D: FnOnce(u32) -> Option<Direction>
+    {
+        let mut buf = Vec::new();
+        let view = RetSubmitRef::read_with(src, limits, direction_of, |src, len| read_payload(src, &mut buf, len).map(Some))?;
+        let has_data = view.data.is_some();
+        let mut ret = view.without_data();
+        if has_data {
+            ret.data = Some(buf);
+        }
+        Ok(Packet::RetSubmit(ret))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        self.as_view().write(dst)
+    }
+}
+
+impl<'a> RetSubmitRef<'a> {
+    pub fn into_owned(self) -> RetSubmit {
+        let data = self.data.map(|d| d.to_vec());
+        RetSubmit { data, ..self.without_data() }
+    }
+
+    fn without_data(self) -> RetSubmit {
+        RetSubmit {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            direction: self.direction,
+            ep: self.ep,
+            status: self.status,
+            length: self.length,
+            start_frame: self.start_frame,
+            num_packets: self.num_packets,
+            error_count: self.error_count,
+            setup: self.setup,
+            data: None,
+            iso_packets: self.iso_packets.into_owned()
+        }
+    }
+
+    // An IN reply carries exactly length bytes, the actual length which may be
+    // short of the buffer_length submitted, and always decodes with Some(data),
+    // a zero-length packet as an empty slice. OUT replies decode with None.
+    fn read<R: ByteReader + ?Sized>(src: &mut R, limits: &ReadLimits, buf: &'a mut Vec<u8>) -> PacketResult<RetSubmitRef<'a>> {
+        RetSubmitRef::read_with(src, limits, |_| None, move |src, len| read_payload(src, buf, len).map(Some))
+    }
+
+    // direction_of gets the seqnum and may name the direction of its submit,
+    // which then decides about the payload instead of the header's direction
+    fn read_with<R, D, P>(src: &mut R, limits: &ReadLimits, direction_of: D, payload: P) -> PacketResult<RetSubmitRef<'a>>
+        where R: ByteReader + ?Sized, D: FnOnce(u32) -> Option<Direction>,
+              P: FnOnce(&mut R, u32) -> PacketResult<Option<&'a [u8]>>
+    {
+        let mut f = Fields::new(src, "RetSubmit");
+        let seqnum = f.u32("seqnum")?;
+        let devid = f.u32("devid")?;
+        let direction = match direction_of(seqnum) {
+            Some(d) => {
+                f.u32("direction")?;
+                d
+            },
+            None => f.direction("direction")?,
+        };
+        let ep = f.u32("ep")?;
+        let status = f.u32("status")?;
+        let length = f.u32("length")?;
+        let start_frame = f.u32("start_frame")?;
+        let num_packets = f.u32("num_packets")?;
+        let error_count = f.u32("error_count")?;
+        let mut setup = [0u8; 8];
+        f.bytes("setup", &mut setup)?;
+        let mut data: Option<&'a [u8]> = None;
+        if direction == Direction::In {
+            limits.check_payload(length)?;
+            data = f.with("data", move |src| payload(src, length))?;
+        }
+        let iso_packets = Cow::Owned(IsoPacketDescriptor::read_all(f.src, num_packets)?);
+        Ok(RetSubmitRef{
+            seqnum, devid, direction, ep, status, length,
+            start_frame, num_packets, error_count, setup, data, iso_packets
+        })
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        let data_len = self.data.map_or(0, |d| d.len());
+        if self.direction == Direction::In && data_len != self.length as usize {
+            return Err(PacketError::PacketError(
+                format!("IN reply {} has {} bytes of data, length {}", self.seqnum, data_len, self.length)));
+        }
+        write_op_header(dst, PacketTypes::RetSubmit as u32)?;
+        dst.write_u32::<BigEndian>(self.seqnum)?;
+        dst.write_u32::<BigEndian>(self.devid)?;
+        self.direction.write(dst)?;
+        dst.write_u32::<BigEndian>(self.ep)?;
+        dst.write_u32::<BigEndian>(self.status)?;
+        dst.write_u32::<BigEndian>(self.length)?;
+        dst.write_u32::<BigEndian>(self.start_frame)?;
+        dst.write_u32::<BigEndian>(self.num_packets)?;
+        dst.write_u32::<BigEndian>(self.error_count)?;
+        dst.write_all(&self.setup)?;
+        if self.direction == Direction::In {
+            if let Some(dv) = self.data {
+                dst.write_all(dv)?;
+            }
+        }
+        for iso in self.iso_packets.iter() {
+            iso.write(dst)?;
+        }
+        Ok(())
+    }
+}
+
+// Both unlink packets pad the URB header out to the 48 bytes of a submit.
+const UNLINK_PADDING: usize = 24;
+
+impl CmdUnlink {
+    // Cancels submit. An unlink_seq of 0 gets assigned by
+    // ImportedDevice::unlink like a submit's seqnum.
+    pub fn for_submit(submit: &CmdSubmit, unlink_seq: u32) -> CmdUnlink {
+        CmdUnlink {
+            seq: unlink_seq,
+            devid: submit.devid,
+            direction: submit.direction,
+            ep: submit.ep,
+            seqnum: submit.seqnum
+        }
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<Packet> {
+        let mut f = Fields::new(src, "CmdUnlink");
+        let seq = f.u32("seq")?;
+        let devid = f.u32("devid")?;
+        let direction = f.direction("direction")?;
+        let ep = f.u32("ep")?;
+        let seqnum = f.u32("seqnum")?;
+        f.bytes("padding", &mut [0u8; UNLINK_PADDING])?;
+        Ok(Packet::CmdUnlink(CmdUnlink{ seq, devid, direction, ep, seqnum }))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        write_op_header(dst, PacketTypes::CmdUnlink as u32)?;
+        dst.write_u32::<BigEndian>(self.seq)?;
+        dst.write_u32::<BigEndian>(self.devid)?;
+        self.direction.write(dst)?;
+        dst.write_u32::<BigEndian>(self.ep)?;
+        dst.write_u32::<BigEndian>(self.seqnum)?;
+        dst.write_all(&[0u8; UNLINK_PADDING])?;
+        Ok(())
+    }
+}
+
+impl RetUnlink {
+    fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<Packet> {
+        let mut f = Fields::new(src, "RetUnlink");
+        let seqnum = f.u32("seqnum")?;
+        let devid = f.u32("devid")?;
+        let direction = f.direction("direction")?;
+        let ep = f.u32("ep")?;
+        let status = f.u32("status")?;
+        f.bytes("padding", &mut [0u8; UNLINK_PADDING])?;
+        Ok(Packet::RetUnlink(RetUnlink{ seqnum, devid, direction, ep, status }))
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        write_op_header(dst, PacketTypes::RetUnlink as u32)?;
+        dst.write_u32::<BigEndian>(self.seqnum)?;
+        dst.write_u32::<BigEndian>(self.devid)?;
+        self.direction.write(dst)?;
+        dst.write_u32::<BigEndian>(self.ep)?;
+        dst.write_u32::<BigEndian>(self.status)?;
+        dst.write_all(&[0u8; UNLINK_PADDING])?;
+        Ok(())
+    }
+}
+
+impl IsoPacketDescriptor {
+    // Each packet has a status of its own, 0 or -errno like the URB's
+    pub fn is_ok(&self) -> bool {
+        self.status == 0
+    }
+
+    fn read<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<IsoPacketDescriptor> {
+        let mut f = Fields::new(src, "IsoPacketDescriptor");
+        let offset = f.u32("offset")?;
+        let length = f.u32("length")?;
+        let actual_length = f.u32("actual_length")?;
+        let status = f.u32("status")?;
+        Ok(IsoPacketDescriptor{ offset, length, actual_length, status })
+    }
+
+    fn read_all<R: ByteReader + ?Sized>(src: &mut R, num_packets: u32) -> PacketResult<Vec<IsoPacketDescriptor>> {
+        let mut iso_packets = Vec::new();
+        for _ in 0..num_packets {
+            let iso = IsoPacketDescriptor::read(src)?;
+            iso_packets.push(iso);
+        }
+        Ok(iso_packets)
+    }
+
+    fn write<W: ByteWriter + ?Sized>(&self, dst: &mut W) -> PacketResult<()> {
+        dst.write_u32::<BigEndian>(self.offset)?;
+        dst.write_u32::<BigEndian>(self.length)?;
+        dst.write_u32::<BigEndian>(self.actual_length)?;
+        dst.write_u32::<BigEndian>(self.status)?;
+        Ok(())
+    }
+}
+
+// Like read_u32, but an end of stream before the first byte is a clean close
+// rather than a truncated packet.
+fn read_header<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<u32> {
+    let mut buf = [0u8; 4];
+    let n = src.read(&mut buf)?;
+    if n == 0 {
+        return Err(PacketError::ConnectionClosed);
+    }
+    src.read_exact(&mut buf[n..]).map_err(mid_packet)?;
+    Ok(BigEndian::read_u32(&buf))
+}
+
+// Reads the fields of one structure, so that running out of bytes names the
+// field that was cut off instead of a bare Truncated.
+struct Fields<'a, R: ?Sized> {
+    src: &'a mut R,
+    name: &'static str,
+}
+
+impl<'a, R: ByteReader + ?Sized> Fields<'a, R> {
+    fn new(src: &'a mut R, name: &'static str) -> Fields<'a, R> {
+        Fields { src, name }
+    }
+
+    fn with<T, F: FnOnce(&mut R) -> PacketResult<T>>(&mut self, field: &'static str, read: F) -> PacketResult<T> {
+        read(&mut *self.src).map_err(|e| match e {
+            PacketError::Truncated => PacketError::TruncatedField(self.name, field),
+            e => e,
+        })
+    }
+
+    fn u8(&mut self, field: &'static str) -> PacketResult<u8> {
+        self.with(field, |src| src.read_u8())
+    }
+
+    fn u16(&mut self, field: &'static str) -> PacketResult<u16> {
+        self.with(field, |src| src.read_u16::<BigEndian>())
+    }
+
+    fn u32(&mut self, field: &'static str) -> PacketResult<u32> {
+        self.with(field, |src| src.read_u32::<BigEndian>())
+    }
+
+    fn bytes(&mut self, field: &'static str, buf: &mut [u8]) -> PacketResult<()> {
+        self.with(field, |src| src.read_exact(buf))
+    }
+
+    fn direction(&mut self, field: &'static str) -> PacketResult<Direction> {
+        self.with(field, Direction::read)
+    }
+
+    fn fix_string(&mut self, field: &'static str, len: usize) -> PacketResult<String> {
+        self.with(field, |src| read_fix_string(src, len))
+    }
+
+    fn fix_ascii(&mut self, field: &'static str, len: usize) -> PacketResult<String> {
+        self.with(field, |src| read_fix_ascii(src, len))
+    }
+}
+
+// The digits as lsusb shows them, a nibble that is not a decimal digit (bad
+// BCD) comes out as a hex digit instead of being rejected
+fn bcd_version(bcd: u16) -> String {
+    format!("{:x}.{:02x}", bcd >> 8, bcd & 0xff)
+}
+
+// Once part of a packet has been consumed a timeout can no longer be retried
+fn mid_packet(e: PacketError) -> PacketError {
+    match e {
+        PacketError::Timeout => PacketError::TimeoutMidPacket,
+        e => e,
+    }
+}
+
+fn read_packet_type<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<PacketTypes> {
+    PacketTypes::from_u32(read_op_header(src)?)
+}
+
+// The header word of an op packet for command, e.g. 0x8005 for OP_REQ_DEVLIST
+pub fn op_header(command: u16) -> u32 {
+    (USBIP_VERSION as u32) << 16 | command as u32
+}
+
+// OP packets carry version and command in one word, URB packets have version 0
+pub fn check_version(header: u32) -> PacketResult<()> {
+    let version = (header >> 16) as u16;
+    if version != 0 && version != USBIP_VERSION {
+        return Err(PacketError::UnsupportedVersion(PacketHeader::from_u32(header)));
+    }
+    Ok(())
+}
+
+// Reads the header word that starts every packet, op or URB, and checks its
+// version. The word is returned whole, unknown commands are not rejected.
+pub fn read_op_header<R: ByteReader + ?Sized>(src: &mut R) -> PacketResult<u32> {
+    let header = read_header(src)?;
+    check_version(header)?;
+    Ok(header)
+}
+
+pub fn write_op_header<W: ByteWriter + ?Sized>(dst: &mut W, header: u32) -> PacketResult<()> {
+    dst.write_u32::<BigEndian>(header)?;
+    Ok(())
+}
+
+// Consumes up to len bytes, stopping early at the end of the stream
+fn skip_optional<R: ByteReader + ?Sized>(src: &mut R, len: usize) -> PacketResult<()> {
+    let mut scratch = [0u8; 64];
+    let mut left = len;
+    while left > 0 {
+        let chunk = left.min(scratch.len());
+        let n = src.read(&mut scratch[..chunk])?;
+        if n == 0 {
+            break;
+        }
+        left -= n;
+    }
+    Ok(())
+}
+
+// Payloads are read in chunks of this size, so that a header claiming a huge
+// buffer_length only costs as much memory as the peer actually sends.
+pub(crate) const PAYLOAD_CHUNK: usize = 64 * 1024;
+
+// Reads len payload bytes into buf, growing it only if its capacity is too small.
+fn read_payload<'a, R: ByteReader + ?Sized>(src: &mut R, buf: &'a mut Vec<u8>, len: u32) -> PacketResult<&'a [u8]> {
+    let len = len as usize;
+    buf.clear();
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(len.min(start + PAYLOAD_CHUNK), 0);
+        src.read_exact(&mut buf[start..])?;
+    }
+    Ok(buf)
+}
+
+// Hands len payload bytes to on_chunk in the pieces the reads return
+fn read_payload_chunks<R, F>(src: &mut R, len: u32, on_chunk: &mut F) -> PacketResult<()>
+    where R: ByteReader + ?Sized, F: FnMut(&[u8]) -> PacketResult<()>
+{
+    let mut left = len as usize;
+    let mut chunk = vec![0u8; left.min(PAYLOAD_CHUNK)];
+    while left > 0 {
+        let n = src.read(&mut chunk[..left.min(PAYLOAD_CHUNK)])?;
+        if n == 0 {
+            return Err(PacketError::Truncated);
+        }
+        on_chunk(&chunk[..n])?;
+        left -= n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    #[cfg(feature = "std")]
+    use std::io;
+    #[cfg(feature = "std")]
+    use std::error::Error;
+    use crate::protocol::{DEVICE_INFO_LEN, PAYLOAD_CHUNK, DEFAULT_MAX_PAYLOAD, MAX_INTERFACES, USBIP_VERSION, OP_REQ_DEVLIST, OP_REP_DEVLIST,
+                          OP_REQ_IMPORT, OP_REP_IMPORT, USBIP_CMD_SUBMIT, USBIP_RET_SUBMIT, USBIP_CMD_UNLINK,
+                          USBIP_RET_UNLINK, devid, split_devid, BusId, op_header, read_op_header, write_op_header, Packet, PacketRef, PacketEncoder, PacketError,
+                          PacketErrorKind, ImportError, UsbSpeed, ReadLimits,
+                          PacketTypes, UsbIpStatus, RepDevList,
+                          DeviceDescriptor, InterfaceDescriptor, ReqImport, RepImport,
+                          CmdSubmit, CmdSubmitBuilder, RetSubmit, CmdUnlink, RetUnlink, IsoPacketDescriptor, UsbSetupPacket, Direction, TransferFlags,
+                          TransferType, EndpointAddress, PacketResult, PacketHeader};
+    use proptest::prelude::*;
+
+    // The device most tests encode, which they override the fields of that
+    // they are about
+    pub(crate) fn device() -> DeviceDescriptor {
+        DeviceDescriptor {
+            path: "/foo/bar".to_string(),
+            busid: "3-2".to_string(),
+            busnum: 3,
+            devnum: 2,
+            speed: 2,
+            id_vendor: 0xaffe,
+            id_product: 0xbeef,
+            bcd_device: 0x0110,
+            device_class: 255,
+            device_subclass: 254,
+            device_protocol: 253,
+            configuration_value: 1,
+            num_configurations: 2,
+            num_interfaces: 1,
+            interfaces: vec![InterfaceDescriptor {
+                interface_class: 23,
+                interface_subclass: 26,
+                interface_protocol: 29
+            }]
+        }
+    }
+
+    #[test]
+    fn test_req_device_list() {
+        let dl = Packet::ReqDevList;
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    #[test]
+    fn test_rep_device_list() {
+        let dl = Packet::RepDevList(RepDevList {
             status: 0,
             num_devices: 1,
             devices: vec![DeviceDescriptor {
@@ -574,31 +2359,811 @@ mod tests {
             }]
         });
         let mut buf = Vec::new();
-        dl.write(&mut buf).unwrap();
-        println!("Original structure: {:?}", dl);
-        println!("Encoded: {:?}", buf);
-        let dec = Packet::read(&mut buf.as_slice()).unwrap();
-        println!("Decoded structure: {:?}", dec);
-        assert_eq!(dl, dec);
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    // A Yubico key (composite, with the IAD class triplet and two HID
+    // interfaces) listed by the Linux usbipd. The kernel packs
+    // usbip_usb_device with every multi-byte field in network byte order,
+    // including idVendor, idProduct and bcdDevice.
+    #[test]
+    fn test_device_descriptor_fields_as_sent() {
+        let hid = InterfaceDescriptor { interface_class: 3, interface_subclass: 0, interface_protocol: 0 };
+        let mut device = DeviceDescriptor {
+            path: "/sys/bus/usb/devices/1-4".to_string(),
+            busid: "1-4".to_string(),
+            busnum: 1,
+            devnum: 4,
+            speed: 3,
+            id_vendor: 0x1050,
+            id_product: 0x0407,
+            bcd_device: 0xffff,
+            device_class: 0xef,
+            device_subclass: 0x02,
+            device_protocol: 0x01,
+            configuration_value: 0,
+            num_configurations: 1,
+            num_interfaces: 2,
+            interfaces: vec![hid.clone(), hid]
+        };
+        let buf = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![device.clone()] })
+            .write_to_vec().unwrap();
+        match Packet::read(&mut buf.as_slice()).unwrap() {
+            Packet::RepDevList(dl) => assert_eq!(dl.devices, vec![device.clone()]),
+            p => panic!("Expected RepDevList, got {:?}", p),
+        }
+
+        // A composite device reporting the class of its first interface
+        device.device_class = 3;
+        device.bcd_device = 0x0512;
+        device.speed = 6;
+        let mut buf = Vec::new();
+        device.write(&mut buf).unwrap();
+        assert_eq!(DeviceDescriptor::read(&mut buf.as_slice()).unwrap(), device);
+    }
+
+    #[test]
+    fn test_device_descriptor_validate() {
+        let mut device = DeviceDescriptor {
+            path: "/sys/bus/usb/devices/1-2".to_string(),
+            busid: "1-2".to_string(),
+            busnum: 1,
+            devnum: 4,
+            speed: 2,
+            id_vendor: 0x046d,
+            id_product: 0xc52b,
+            bcd_device: 0x1211,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 2,
+            interfaces: vec![
+                InterfaceDescriptor { interface_class: 3, interface_subclass: 1, interface_protocol: 1 },
+                InterfaceDescriptor { interface_class: 3, interface_subclass: 1, interface_protocol: 2 },
+            ]
+        };
+        device.validate().unwrap();
+
+        let check = |device: &DeviceDescriptor, expected: &str| match device.validate() {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, expected),
+            x => panic!("Expected PacketError, got {:?}", x),
+        };
+        device.num_interfaces = 3;
+        check(&device, "Device 1-2 declares 3 interfaces but has 2");
+        device.num_interfaces = 2;
+
+        device.interfaces[1].interface_class = 0;
+        check(&device, "Interface 1 of device 1-2 has class 0, but the device leaves the class to its interfaces");
+        device.device_class = 0xff;
+        device.validate().unwrap();
+
+        device.device_class = 0;
+        device.device_protocol = 1;
+        check(&device, "Device 1-2 has class 0 but subclass 0 and protocol 1");
+        device.device_protocol = 0;
+        device.interfaces[1].interface_class = 3;
+
+        device.num_configurations = 0;
+        check(&device, "Device 1-2 has no configurations");
+    }
+
+    // A reply as the Linux stub sends it: direction (and devid, ep) 0 in the
+    // header, the IN payload after it all the same
+    #[test]
+    fn test_ret_submit_read_for() {
+        let ret = RetSubmit { seqnum: 5, direction: Direction::In, length: 2, data: Some(vec![0x12, 0x01]), ..Default::default() };
+        let mut buf = Packet::RetSubmit(ret.clone()).write_to_vec().unwrap();
+        buf[12..16].copy_from_slice(&[0, 0, 0, 0]);
+        Packet::RetUnlink(RetUnlink { seqnum: 6, ..Default::default() }).write(&mut buf).unwrap();
+
+        let mut src = buf.as_slice();
+        assert_eq!(RetSubmit::read_for(&mut src, Direction::In).unwrap(), ret);
+        assert!(matches!(Packet::read(&mut src).unwrap(), Packet::RetUnlink(RetUnlink { seqnum: 6, .. })));
+
+        // Without a direction for the seqnum the header's OUT is taken
+        let mut src = buf.as_slice();
+        match Packet::read_reply(&mut src, &ReadLimits::default(), |seqnum| if seqnum == 5 { None } else { Some(Direction::In) }) {
+            Ok(Packet::RetSubmit(r)) => assert_eq!((r.direction, r.data), (Direction::Out, None)),
+            x => panic!("Expected RetSubmit, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_device_version() {
+        let device = DeviceDescriptor { bcd_device: 0x0600, ..device() };
+        assert_eq!(device.device_version(), "6.00");
+        let mut rep = RepImport::from_device(&device, UsbIpStatus::Ok.as_u32());
+        for &(bcd, version) in &[(0x0110, "1.10"), (0x0203, "2.03"), (0x1211, "12.11"), (0x0000, "0.00")] {
+            rep.bcd_device = bcd;
+            assert_eq!(rep.device_version(), version);
+        }
+    }
+
+    #[test]
+    fn test_rep_device_list_encoded_len() {
+        let interface = InterfaceDescriptor { interface_class: 23, interface_subclass: 26, interface_protocol: 29 };
+        let device = DeviceDescriptor { num_interfaces: 2, interfaces: vec![interface.clone(), interface], ..device() };
+        let mut dl = RepDevList { status: 0, num_devices: 0, devices: vec![] };
+        assert_eq!(dl.encoded_len(), 12);
+        dl.num_devices = 1;
+        dl.devices.push(device);
+        assert_eq!(dl.encoded_len(), 12 + 312 + 2 * 4);
+        let buf = Packet::RepDevList(dl.clone()).write_to_vec().unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+    }
+
+    #[test]
+    fn test_rep_device_list_usbipd_fixture() {
+        // The reply usbipd sends for one exported flash drive, 1-1.2 (0781:5567),
+        // in tcpdump -x layout. It is not a capture: no daemon was at hand, so
+        // it is written out after struct op_devlist_reply and usbip_usb_device
+        // in the kernel's tools/usb/usbip/libsrc/usbip_common.h. Replace it with
+        // the dump printed by test_usbipd_devlist_bytes in tests/usbipd.rs.
+        let fixture = crate::dump::parse_hex("
+            0111 0005 0000 0000 0000 0001 2f73 7973
+            2f64 6576 6963 6573 2f70 6369 3030 3030
+            3a30 302f 3030 3030 3a30 303a 3134 2e30
+            2f75 7362 312f 312d 312f 312d 312e 3200
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 312d 312e
+            3200 0000 0000 0000 0000 0000 0000 0000
+            0000 0000 0000 0000 0000 0000 0000 0001
+            0000 0003 0000 0003 0781 5567 0100 0000
+            0001 0101 0806 5000
+        ").unwrap();
+        assert_eq!(fixture.len(), 12 + DEVICE_INFO_LEN + 4);
+        let dev = match Packet::read(&mut fixture.as_slice()).unwrap() {
+            Packet::RepDevList(mut dl) => dl.devices.remove(0),
+            p => panic!("Unexpected {:?}", p),
+        };
+        assert_eq!(dev.busid, "1-1.2");
+        assert_eq!((dev.busnum, dev.devnum), (1, 3));
+        assert_eq!(dev.speed(), UsbSpeed::High);
+        assert_eq!(dev.id_vendor, 0x0781);
+        assert_eq!(dev.id_product, 0x5567);
+        assert_eq!(dev.bcd_device, 0x0100);
+        assert_eq!(dev.interfaces, vec![InterfaceDescriptor {
+            interface_class: 0x08,
+            interface_subclass: 0x06,
+            interface_protocol: 0x50
+        }]);
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![dev] });
+        assert_eq!(dl.write_to_vec().unwrap(), fixture);
+    }
+
+    #[test]
+    fn test_interface_class_names() {
+        let iface = |class| InterfaceDescriptor { interface_class: class, interface_subclass: 0, interface_protocol: 0 };
+        assert_eq!(iface(0x03).class_name(), "HID");
+        assert_eq!(iface(0x08).class_name(), "Mass Storage");
+        assert_eq!(iface(0xff).class_name(), "Vendor Specific");
+        assert_eq!(iface(0x42).class_name(), "Unknown");
+
+        let dev = DeviceDescriptor { num_interfaces: 2, interfaces: vec![iface(0x01), iface(0x02)], ..device() };
+        let classes: Vec<_> = dev.interface_classes().map(|(i, name)| (i.interface_class, name)).collect();
+        assert_eq!(classes, vec![(0x01, "Audio"), (0x02, "Communications (CDC)")]);
+    }
+
+    #[test]
+    fn test_rep_device_list_failed() {
+        let failed = Packet::RepDevList(RepDevList { status: 1, num_devices: 0, devices: vec![] });
+        let status_only: Vec<u8> = vec![
+            0x01, 0x11, 0x00, 0x05, // RepDevList
+            0, 0, 0, 1              // status
+        ];
+        assert_eq!(Packet::from_slice(&status_only).unwrap(), failed);
+        // A device count without the devices it announces
+        let mut with_count = status_only.clone();
+        with_count.extend_from_slice(&[0, 0, 0, 2]);
+        let mut src = with_count.as_slice();
+        assert_eq!(Packet::read(&mut src).unwrap(), failed);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_rep_device_list_empty() {
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 0, devices: vec![] });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(buf, [0x01, 0x11, 0x00, 0x05, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(dl.encoded_len(), buf.len());
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(dl, dec);
+
+        let mismatched = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![] });
+        match mismatched.write(&mut Vec::new()) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Device list declares 1 devices but has 0"),
+            x => panic!("Expected device count error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_req_import() {
+        let dl = Packet::ReqImport(ReqImport{
+            busid: "3-2".to_string()
+        });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }    
+
+    #[test]
+    fn test_rep_import_from_device() {
+        let dev = device();
+        let ri = RepImport::from_device(&dev, 0);
+        assert_eq!(ri, RepImport {
+            status: 0,
+            path: "/foo/bar".to_string(),
+            busid: "3-2".to_string(),
+            busnum: 3,
+            devnum: 2,
+            speed: 2,
+            id_vendor: 0xaffe,
+            id_product: 0xbeef,
+            bcd_device: 0x0110,
+            device_class: 255,
+            device_subclass: 254,
+            device_protocol: 253,
+            configuration_value: 1,
+            num_configurations: 2,
+            num_interfaces: 1,
+        });
+        assert_eq!(RepImport::from_device(&dev, UsbIpStatus::DeviceBusy.as_u32()).status(), UsbIpStatus::DeviceBusy);
+
+        assert!(dev.matches(&ri));
+        let other = RepImport { busid: "3-3".to_string(), ..ri.clone() };
+        assert!(!dev.matches(&other));
+        let other = RepImport { id_product: 0x6001, ..ri };
+        assert!(!dev.matches(&other));
+        assert!(!dev.matches(&RepImport::failed(UsbIpStatus::NoDevice)));
+    }
+
+    #[test]
+    fn test_req_import_reserved_status() {
+        let mut buf = Packet::ReqImport(ReqImport { busid: "3-2".to_string() }).write_to_vec().unwrap();
+        buf[7] = 1;
+        match Packet::from_slice(&buf) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Import of 3-2 has nonzero reserved status 0x00000001"),
+            x => panic!("Expected reserved status error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_rep_import() {
+        let dl = Packet::RepImport(RepImport {
+            status: 0,
+            path: "/foo/bar".to_string(),
+            busid: "3-2".to_string(),
+            busnum: 3,
+            devnum: 2,
+            speed: 2,
+            id_vendor: 0xaffe,
+            id_product: 0xbeef,
+            bcd_device: 0x0110,
+            device_class: 255,
+            device_subclass: 254,
+            device_protocol: 253,
+            configuration_value: 1,
+            num_configurations: 2,
+            num_interfaces: 2,
+        });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    #[test]
+    fn test_ret_submit() {
+        let encoded: Vec<u8> = vec![
+            0, 0, 0, 3,         // RetSubmit
+            0, 0, 0, 42,        // seqnum
+            0, 3, 0, 2,         // devid
+            0, 0, 0, 1,         // direction
+            0, 0, 0, 1,         // ep
+            0, 0, 0, 0,         // status
+            0, 0, 0, 4,         // length
+            0, 0, 0, 0,         // start_frame
+            0, 0, 0, 0,         // num_packets
+            0, 0, 0, 0,         // error_count
+            0, 0, 0, 0, 0, 0, 0, 0, // setup
+            1, 2, 3, 4          // data
+        ];
+        let expected = Packet::RetSubmit(RetSubmit {
+            seqnum: 42,
+            devid: 0x00030002,
+            direction: Direction::In,
+            ep: 1,
+            status: 0,
+            length: 4,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(vec![1, 2, 3, 4]),
+            iso_packets: Vec::new()
+        });
+        let dec = Packet::read(&mut encoded.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(expected, dec);
+    }
+
+    #[test]
+    fn test_ret_submit_short_in() {
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(9).buffer_length(64).build();
+        let reply = |data: Vec<u8>| RetSubmit {
+            seqnum: 9,
+            devid: 0,
+            direction: Direction::In,
+            ep: 1,
+            status: 0,
+            length: data.len() as u32,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(data),
+            iso_packets: vec![]
+        };
+        // Short read, followed by another packet that must not be eaten
+        let short = reply(vec![1, 2, 3]);
+        let mut buf = Packet::RetSubmit(short.clone()).write_to_vec().unwrap();
+        buf.extend(Packet::ReqDevList.write_to_vec().unwrap());
+        let mut src = buf.as_slice();
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::RetSubmit(short.clone()));
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+        assert!(short.check_length(&cmd).is_ok());
+
+        let zlp = reply(vec![]);
+        let buf = Packet::RetSubmit(zlp.clone()).write_to_vec().unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(Packet::from_slice(&buf).unwrap(), Packet::RetSubmit(zlp));
+
+        assert!(reply(vec![0u8; 65]).check_length(&cmd).is_err());
+        let mismatched = [
+            (RetSubmit { length: 4, ..short.clone() }, "IN reply 9 has 3 bytes of data, length 4"),
+            (RetSubmit { length: 2, ..short.clone() }, "IN reply 9 has 3 bytes of data, length 2"),
+            (RetSubmit { data: None, ..short.clone() }, "IN reply 9 has 0 bytes of data, length 3"),
+        ];
+        for &(ref ret, msg) in mismatched.iter() {
+            match Packet::RetSubmit(ret.clone()).write_to_vec() {
+                Err(PacketError::PacketError(ref m)) => assert_eq!(m, msg),
+                x => panic!("Expected length error, got {:?}", x)
+            }
+            assert!(ret.as_view().write(&mut Vec::new()).is_err());
+        }
+
+        // OUT replies report the length written but carry no data
+        let out = RetSubmit { direction: Direction::Out, length: 3, data: None, ..short };
+        let buf = Packet::RetSubmit(out.clone()).write_to_vec().unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(Packet::from_slice(&buf).unwrap(), Packet::RetSubmit(out));
+    }
+
+    #[test]
+    fn test_ret_submit_roundtrip() {
+        let dl = Packet::RetSubmit(RetSubmit {
+            seqnum: 17,
+            devid: 0x00030002,
+            direction: Direction::In,
+            ep: 2,
+            status: 0,
+            length: 6,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]),
+            iso_packets: Vec::new()
+        });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        assert_eq!(buf.len(), 48 + 6);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    #[test]
+    fn test_cmd_submit() {
+        let dl = Packet::CmdSubmit(CmdSubmit {
+            seqnum: 23,
+            devid: 0x00030002,
+            direction: Direction::Out,
+            ep: 2,
+            transfer_flags: TransferFlags::ZERO_PACKET,
+            buffer_length: 4,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup: [0u8; 8],
+            data: Some(vec![1, 2, 3, 4]),
+            iso_packets: Vec::new()
+        });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        assert_eq!(buf.len(), 48 + 4);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    #[test]
+    fn test_ret_submit_iso() {
+        let dl = Packet::RetSubmit(RetSubmit {
+            seqnum: 5,
+            devid: 0x00030002,
+            direction: Direction::In,
+            ep: 3,
+            status: 0,
+            length: 8,
+            start_frame: 100,
+            num_packets: 2,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            iso_packets: vec![
+                IsoPacketDescriptor {
+                    offset: 0,
+                    length: 4,
+                    actual_length: 4,
+                    status: 0
+                }, IsoPacketDescriptor {
+                    offset: 4,
+                    length: 4,
+                    actual_length: 4,
+                    status: 0
+                }
+            ]
+        });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        assert_eq!(dl.encoded_len(), buf.len());
+        println!("Original structure: {:?}", dl);
+        println!("Encoded: {:?}", buf);
+        assert_eq!(buf.len(), 48 + 8 + 2 * 16);
+        let dec = Packet::read(&mut buf.as_slice()).unwrap();
+        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl, dec);
+    }
+
+    #[test]
+    fn test_ret_submit_iso_errors() {
+        let encoded: Vec<u8> = vec![
+            0, 0, 0, 3,         // RetSubmit
+            0, 0, 0, 6,         // seqnum
+            0, 3, 0, 2,         // devid
+            0, 0, 0, 1,         // direction
+            0, 0, 0, 3,         // ep
+            0, 0, 0, 0,         // status
+            0, 0, 0, 4,         // length
+            0, 0, 0, 100,       // start_frame
+            0, 0, 0, 2,         // num_packets
+            0, 0, 0, 1,         // error_count
+            0, 0, 0, 0, 0, 0, 0, 0,
+            1, 2, 3, 4,         // data
+            0, 0, 0, 0,         // iso 0: offset
+            0, 0, 0, 4,         // length
+            0, 0, 0, 4,         // actual_length
+            0, 0, 0, 0,         // status
+            0, 0, 0, 4,         // iso 1: offset
+            0, 0, 0, 4,         // length
+            0, 0, 0, 0,         // actual_length
+            0xff, 0xff, 0xff, 0xee  // status -EXDEV
+        ];
+        let ret = match Packet::read(&mut encoded.as_slice()).unwrap() {
+            Packet::RetSubmit(ret) => ret,
+            p => panic!("Expected RetSubmit, got {:?}", p),
+        };
+        assert_eq!(ret.iso_packets, vec![
+            IsoPacketDescriptor { offset: 0, length: 4, actual_length: 4, status: 0 },
+            IsoPacketDescriptor { offset: 4, length: 4, actual_length: 0, status: -18i32 as u32 },
+        ]);
+        assert!(ret.iso_packets[0].is_ok());
+        assert!(!ret.iso_packets[1].is_ok());
+        let failed: Vec<usize> = ret.failed_iso_packets().map(|(i, _)| i).collect();
+        assert_eq!(failed, vec![1]);
+        assert_eq!(ret.iso_error_count(), ret.error_count);
+        assert!(ret.status().is_ok());
+        assert_eq!(Packet::RetSubmit(ret).write_to_vec().unwrap(), encoded);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_peek_type() {
+        let mut encoded = Packet::ReqImport(ReqImport { busid: "3-2".to_string() }).write_to_vec().unwrap();
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(9).buffer_length(8).build();
+        encoded.extend(Packet::CmdSubmit(cmd.clone()).write_to_vec().unwrap());
+        encoded.extend(Packet::ReqDevList.write_to_vec().unwrap());
+
+        let mut src = io::BufReader::new(encoded.as_slice());
+        assert_eq!(Packet::peek_type(&mut src).unwrap(), PacketTypes::ReqImport as u32);
+        assert_eq!(Packet::peek_type(&mut src).unwrap(), PacketTypes::ReqImport as u32);
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqImport(ReqImport { busid: "3-2".to_string() }));
+        assert_eq!(Packet::peek_type(&mut src).unwrap(), USBIP_CMD_SUBMIT);
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::CmdSubmit(cmd));
+        assert_eq!(PacketTypes::from_u32(Packet::peek_type(&mut src).unwrap()).unwrap(), PacketTypes::ReqDevList);
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+        match Packet::peek_type(&mut src) {
+            Err(PacketError::ConnectionClosed) => (),
+            x => panic!("Expected ConnectionClosed, got {:?}", x)
+        }
+
+        // The second header straddles the end of the 10 byte buffer
+        let encoded = [Packet::ReqDevList.write_to_vec().unwrap(), Packet::ReqDevList.write_to_vec().unwrap()].concat();
+        let mut src = io::BufReader::with_capacity(10, encoded.as_slice());
+        Packet::peek_type(&mut src).unwrap();
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+        match Packet::peek_type(&mut src) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Only 2 bytes of the next header are buffered, cannot peek"),
+            x => panic!("Expected peek error, got {:?}", x)
+        }
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_packet_error_display() {
+        let err = PacketError::PacketError("Invalid direction value".to_string());
+        assert_eq!(err.to_string(), "Invalid packet: Invalid direction value");
+        assert!(err.source().is_none());
+        let err = PacketError::from(io::Error::other("boom"));
+        assert_eq!(err.to_string(), "I/O error: boom");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_import_error() {
+        let err = PacketError::ImportFailed(ImportError::from_status(UsbIpStatus::DeviceBusy).unwrap());
+        assert_eq!(err.to_string(), "Import failed: device busy");
+        assert_eq!(ImportError::from_status(UsbIpStatus::Ok), None);
+        assert_eq!(ImportError::from_status(UsbIpStatus::NoDevice), Some(ImportError::NotFound));
+        assert_eq!(ImportError::from_status(UsbIpStatus::Error), Some(ImportError::Other(5)));
+    }
+
+    #[test]
+    fn test_usbip_status() {
+        assert_eq!(UsbIpStatus::from_u32(0), UsbIpStatus::Ok);
+        assert_eq!(UsbIpStatus::from_u32(4), UsbIpStatus::NoDevice);
+        assert_eq!(UsbIpStatus::from_u32(5), UsbIpStatus::Error);
+        assert_eq!(UsbIpStatus::from_u32(0xffffffed), UsbIpStatus::Other(0xffffffed));
+        for val in 0..8 {
+            assert_eq!(UsbIpStatus::from_u32(val).as_u32(), val);
+        }
+        assert!(UsbIpStatus::Ok.is_ok());
+        assert!(!UsbIpStatus::Other(0x1234).is_ok());
+        let ri = RepImport {
+            status: 1,
+            path: "".to_string(),
+            busid: "".to_string(),
+            busnum: 0,
+            devnum: 0,
+            speed: 0,
+            id_vendor: 0,
+            id_product: 0,
+            bcd_device: 0,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 0,
+            num_configurations: 0,
+            num_interfaces: 0,
+        };
+        assert_eq!(ri.status(), UsbIpStatus::NotAvailable);
+    }
+
+    #[test]
+    fn test_usb_speed() {
+        let names = ["Unknown Speed", "Low Speed", "Full Speed", "High Speed", "Wireless", "SuperSpeed", "SuperSpeed+"];
+        for (val, name) in names.iter().enumerate() {
+            let speed = UsbSpeed::from_u32(val as u32);
+            assert_eq!(speed.as_u32(), val as u32);
+            assert_eq!(speed.to_string(), *name);
+        }
+        assert_eq!(UsbSpeed::from_u32(3), UsbSpeed::High);
+        assert_eq!(UsbSpeed::from_u32(7), UsbSpeed::Other(7));
+        assert_eq!(UsbSpeed::Other(7).as_u32(), 7);
+        assert_eq!(UsbSpeed::Other(7).to_string(), "Speed 7");
+        assert_eq!(RepImport::failed(UsbIpStatus::NoDevice).speed(), UsbSpeed::Unknown);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let encoded: Vec<u8> = vec![0x01, 0x06, 0x80, 0x05, 0, 0, 0, 0];
+        match Packet::read(&mut encoded.as_slice()) {
+            Err(PacketError::UnsupportedVersion(PacketHeader { version: 0x0106, command: 0x8005 })) => (),
+            x => panic!("Expected UnsupportedVersion, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version_message() {
+        // A ReqDevList from some future protocol version
+        let err = Packet::from_slice(&[0x02, 0x00, 0x80, 0x05, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.kind(), PacketErrorKind::UnsupportedVersion);
+        assert_eq!(err.to_string(),
+                   "Unknown command 0x8005 for unsupported version 0x0200 (ReqDevList under version 0x0111), header 0x02008005");
+
+        let err = Packet::from_slice(&[0x02, 0x00, 0x12, 0x34, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown command 0x1234 for unsupported version 0x0200, header 0x02001234");
+        match PacketTypes::from_u32(0x02001234) {
+            Err(PacketError::UnsupportedVersion(h)) => assert_eq!(h.known_command(), None),
+            x => panic!("Expected UnsupportedVersion, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_packet_type() {
+        assert_eq!(Packet::ReqDevList.packet_type() as u32, 0x01118005);
+        assert_eq!(Packet::ReqImport(ReqImport{ busid: "3-2".to_string() }).packet_type() as u32, 0x01118003);
+        assert_eq!(Packet::RepDevList(RepDevList{ status: 0, num_devices: 0, devices: vec![] }).packet_type(),
+                   PacketTypes::RepDevList);
+        assert_eq!(PacketTypes::RepDevList as u32, 0x01110005);
+        assert_eq!(PacketTypes::RepImport as u32, 0x01110003);
+        assert_eq!(PacketTypes::CmdSubmit as u32, 0x00000001);
+        assert_eq!(PacketTypes::CmdUnlink as u32, 0x00000002);
+        assert_eq!(PacketTypes::RetSubmit as u32, 0x00000003);
+        assert_eq!(PacketTypes::RetUnlink as u32, 0x00000004);
+        let rs = Packet::RetSubmit(RetSubmit {
+            seqnum: 1,
+            devid: 0,
+            direction: Direction::Out,
+            ep: 0,
+            status: 0,
+            length: 0,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: None,
+            iso_packets: Vec::new()
+        });
+        assert_eq!(rs.packet_type(), PacketTypes::RetSubmit);
+    }
+
+    #[test]
+    fn test_op_code_constants() {
+        let codes = [
+            (OP_REQ_DEVLIST, PacketTypes::ReqDevList),
+            (OP_REP_DEVLIST, PacketTypes::RepDevList),
+            (OP_REQ_IMPORT, PacketTypes::ReqImport),
+            (OP_REP_IMPORT, PacketTypes::RepImport),
+            (USBIP_CMD_SUBMIT, PacketTypes::CmdSubmit),
+            (USBIP_RET_SUBMIT, PacketTypes::RetSubmit),
+            (USBIP_CMD_UNLINK, PacketTypes::CmdUnlink),
+            (USBIP_RET_UNLINK, PacketTypes::RetUnlink),
+        ];
+        for &(code, ptype) in codes.iter() {
+            assert_eq!(code, ptype as u32);
+            assert_eq!(PacketTypes::from_u32(code).unwrap(), ptype);
+        }
+        for &op in [OP_REQ_DEVLIST, OP_REP_DEVLIST, OP_REQ_IMPORT, OP_REP_IMPORT].iter() {
+            assert_eq!((op >> 16) as u16, USBIP_VERSION);
+        }
+    }
+
+    #[test]
+    fn test_op_header() {
+        assert_eq!(op_header(0x8005), OP_REQ_DEVLIST);
+
+        // A hand built ReqDevList
+        let mut buf = Vec::new();
+        write_op_header(&mut buf, op_header(0x8005)).unwrap();
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(buf, Packet::ReqDevList.write_to_vec().unwrap());
+        assert_eq!(read_op_header(&mut buf.as_slice()).unwrap(), OP_REQ_DEVLIST);
+        assert_eq!(Packet::from_slice(&buf).unwrap(), Packet::ReqDevList);
+
+        // Unknown commands are left to the caller, unknown versions are not
+        let mut buf = Vec::new();
+        write_op_header(&mut buf, op_header(0x8042)).unwrap();
+        write_op_header(&mut buf, 0x01000005).unwrap();
+        let mut src = buf.as_slice();
+        assert_eq!(read_op_header(&mut src).unwrap(), 0x01118042);
+        match read_op_header(&mut src) {
+            Err(PacketError::UnsupportedVersion(PacketHeader { version: 0x0100, .. })) => (),
+            x => panic!("Expected UnsupportedVersion, got {:?}", x),
+        }
     }
 
     #[test]
-    fn test_req_import() {
-        let dl = Packet::ReqImport(ReqImport{
-            busid: "3-2".to_string()
+    fn test_setup_packet() {
+        // GET_DESCRIPTOR(DEVICE), 18 bytes
+        let raw = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let setup = UsbSetupPacket::from_bytes(&raw);
+        assert_eq!(setup, UsbSetupPacket {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0100,
+            index: 0,
+            length: 18
         });
-        let mut buf = Vec::new();
-        dl.write(&mut buf).unwrap();
-        println!("Original structure: {:?}", dl);
-        println!("Encoded: {:?}", buf);
-        let dec = Packet::read(&mut buf.as_slice()).unwrap();
-        println!("Decoded structure: {:?}", dec);
-        assert_eq!(dl, dec);
-    }    
+        assert_eq!(setup.to_bytes(), raw);
+        let cmd = CmdSubmit {
+            seqnum: 1,
+            devid: 0x00030002,
+            direction: Direction::In,
+            ep: 0,
+            transfer_flags: TransferFlags::empty(),
+            buffer_length: 18,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup: raw,
+            data: None,
+            iso_packets: Vec::new()
+        };
+        assert_eq!(cmd.setup_packet(), setup);
+    }
 
     #[test]
-    fn test_rep_import() {
-        let dl = Packet::RepImport(RepImport {
+    fn test_transfer_type() {
+        let get_descriptor = CmdSubmitBuilder::new(Direction::In, 0)
+            .buffer_length(18)
+            .setup([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00])
+            .build();
+        assert_eq!(get_descriptor.transfer_type(), TransferType::Control);
+        assert!(get_descriptor.check_setup().is_ok());
+
+        let mut bulk_out = CmdSubmitBuilder::new(Direction::Out, 2)
+            .data(vec![1, 2, 3, 4])
+            .build();
+        assert_eq!(bulk_out.transfer_type(), TransferType::Bulk);
+        assert!(bulk_out.check_setup().is_ok());
+        bulk_out.setup[0] = 0x80;
+        assert!(bulk_out.check_setup().is_err());
+
+        let interrupt_in = CmdSubmitBuilder::new(Direction::In, 1)
+            .buffer_length(8)
+            .interval(10)
+            .build();
+        assert_eq!(interrupt_in.transfer_type(), TransferType::Interrupt);
+
+        let iso_in = CmdSubmitBuilder::new(Direction::In, 3)
+            .buffer_length(8)
+            .interval(1)
+            .iso_packets(vec![IsoPacketDescriptor { offset: 0, length: 8, actual_length: 0, status: 0 }])
+            .build();
+        assert_eq!(iso_in.transfer_type(), TransferType::Isochronous);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let ri = Packet::RepImport(RepImport {
             status: 0,
             path: "/foo/bar".to_string(),
             busid: "3-2".to_string(),
@@ -616,11 +3181,943 @@ mod tests {
             num_interfaces: 2,
         });
         let mut buf = Vec::new();
+        ri.write(&mut buf).unwrap();
+        let mut half = &buf[..buf.len() / 2];
+        match Packet::read(&mut half) {
+            Err(PacketError::TruncatedField("RepImport", "path")) => (),
+            x => panic!("Expected truncated path, got {:?}", x)
+        }
+        match Packet::read(&mut [0u8, 0].as_ref()) {
+            Err(PacketError::Truncated) => (),
+            x => panic!("Expected Truncated, got {:?}", x)
+        }
+        match Packet::read(&mut [0u8; 0].as_ref()) {
+            Err(PacketError::ConnectionClosed) => (),
+            x => panic!("Expected ConnectionClosed, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_cmd_submit_payload_limit() {
+        let encoded: Vec<u8> = vec![
+            0, 0, 0, 1,         // CmdSubmit
+            0, 0, 0, 1,         // seqnum
+            0, 3, 0, 2,         // devid
+            0, 0, 0, 0,         // direction
+            0, 0, 0, 2,         // ep
+            0, 0, 0, 0,         // transfer_flags
+            0xff, 0xff, 0xff, 0xff, // buffer_length
+            0, 0, 0, 0,         // start_frame
+            0, 0, 0, 0,         // num_packets
+            0, 0, 0, 0,         // interval
+            0, 0, 0, 0, 0, 0, 0, 0  // setup
+        ];
+        match Packet::read(&mut encoded.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) if msg == "buffer_length exceeds limit" => (),
+            x => panic!("Expected payload limit error, got {:?}", x)
+        }
+        let mut small = encoded.clone();
+        small[24..28].copy_from_slice(&[0, 0, 0, 4]);
+        small.extend_from_slice(&[1, 2, 3, 4]);
+        assert!(Packet::read(&mut small.as_slice()).is_ok());
+        let limits = ReadLimits { max_payload: 2, ..ReadLimits::default() };
+        assert!(Packet::read_with_limits(&mut small.as_slice(), &limits).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_address() {
+        assert_eq!(EndpointAddress::from_usb_byte(0x81), EndpointAddress { number: 1, direction: Direction::In });
+        assert_eq!(EndpointAddress::from_usb_byte(0x02), EndpointAddress { number: 2, direction: Direction::Out });
+        assert_eq!(EndpointAddress::from_usb_byte(0x81).to_usb_byte(), 0x81);
+        assert_eq!(EndpointAddress::from_usb_byte(0x02).to_usb_byte(), 0x02);
+        let cmd = CmdSubmitBuilder::new(Direction::In, 3).buffer_length(8).build();
+        assert_eq!(cmd.endpoint().to_usb_byte(), 0x83);
+    }
+
+    #[test]
+    fn test_hashable_keys() {
+        use std::collections::{HashMap, HashSet};
+
+        let directions: HashSet<Direction> = [Direction::In, Direction::Out, Direction::In].iter().cloned().collect();
+        assert_eq!(directions.len(), 2);
+        assert!(directions.contains(&Direction::In) && directions.contains(&Direction::Out));
+
+        let mut outstanding = HashMap::new();
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(5).devid(devid(3, 2)).buffer_length(8).build();
+        outstanding.insert((cmd.seqnum, cmd.devid, cmd.endpoint()), cmd.buffer_length);
+        assert_eq!(outstanding.get(&(5, devid(3, 2), EndpointAddress::from_usb_byte(0x81))), Some(&8));
+        assert_eq!(outstanding.get(&(5, devid(3, 2), EndpointAddress::from_usb_byte(0x01))), None);
+    }
+
+    #[test]
+    fn test_packet_display() {
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 1)
+            .seqnum(42)
+            .devid(devid(3, 2))
+            .data(vec![0u8; 512])
+            .build();
+        assert_eq!(Packet::CmdSubmit(cmd).to_string(), "CmdSubmit seq=42 dev=3-2 ep=1 OUT len=512");
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 0, devices: vec![] });
+        assert_eq!(dl.to_string(), "RepDevList status=0 devices=0");
+        let ru = Packet::RetUnlink(RetUnlink { seqnum: 7, devid: devid(3, 2), direction: Direction::In, ep: 1, status: -104i32 as u32 });
+        assert_eq!(ru.to_string(), "RetUnlink seq=7 dev=3-2 status=-104");
+    }
+
+    #[test]
+    fn test_cmd_submit_data_direction() {
+        let out = CmdSubmitBuilder::new(Direction::Out, 1).data(vec![1, 2, 3]).build();
+        let inp = CmdSubmitBuilder::new(Direction::In, 1).buffer_length(3).build();
+        assert!(Packet::CmdSubmit(out.clone()).write_to_vec().is_ok());
+        assert!(Packet::CmdSubmit(inp.clone()).write_to_vec().is_ok());
+
+        let bad = [
+            (CmdSubmit { data: Some(vec![1, 2, 3]), ..inp }, "IN submit 0 carries 3 bytes of data"),
+            (CmdSubmit { data: None, ..out.clone() }, "OUT submit 0 has no data"),
+            (CmdSubmit { buffer_length: 4, ..out }, "OUT submit 0 has 3 bytes of data, buffer_length 4"),
+        ];
+        for &(ref cmd, msg) in bad.iter() {
+            match Packet::CmdSubmit(cmd.clone()).write_to_vec() {
+                Err(PacketError::PacketError(ref m)) => assert_eq!(m, msg),
+                x => panic!("Expected data error, got {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_encoder_reuses_buffer() {
+        let big = CmdSubmitBuilder::new(Direction::Out, 2).seqnum(1).data(vec![0x5a; 1000]).build();
+        let small = CmdSubmitBuilder::new(Direction::In, 1).seqnum(2).buffer_length(8).build();
+        let packets = [Packet::CmdSubmit(big), Packet::ReqDevList, Packet::CmdSubmit(small)];
+
+        let mut encoder = PacketEncoder::new();
+        let first = encoder.encode(&packets[0]).unwrap().to_vec();
+        assert_eq!(first, packets[0].write_to_vec().unwrap());
+        let capacity = encoder.capacity();
+        assert!(capacity >= 1048);
+        for _ in 0..3 {
+            for pkt in packets.iter() {
+                assert_eq!(encoder.encode(pkt).unwrap(), pkt.write_to_vec().unwrap().as_slice());
+                assert_eq!(encoder.capacity(), capacity);
+            }
+        }
+
+        // A packet that fails to encode leaves nothing behind
+        let bad = Packet::CmdSubmit(CmdSubmitBuilder::new(Direction::In, 1).data(vec![1]).build());
+        assert!(encoder.encode(&bad).is_err());
+        assert_eq!(encoder.encode(&Packet::ReqDevList).unwrap(), &[0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let cmd = CmdSubmit::default();
+        assert_eq!(cmd, CmdSubmitBuilder::new(Direction::Out, 0).build());
+        let buf = Packet::CmdSubmit(cmd.clone()).write_to_vec().unwrap();
+        let mut expected = vec![0u8; 48];
+        expected[3] = 1;
+        assert_eq!(buf, expected);
+        assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), Packet::CmdSubmit(cmd));
+
+        let bulk = CmdSubmit { seqnum: 1, ep: 2, buffer_length: 3, data: Some(vec![1, 2, 3]), ..CmdSubmit::default() };
+        assert_eq!(bulk, CmdSubmitBuilder::new(Direction::Out, 2).seqnum(1).data(vec![1, 2, 3]).build());
+
+        let ret = RetSubmit::default();
+        assert_eq!(ret.data, None);
+        let buf = Packet::RetSubmit(ret.clone()).write_to_vec().unwrap();
+        expected[3] = 3;
+        assert_eq!(buf, expected);
+        assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), Packet::RetSubmit(ret));
+
+        assert_eq!(TransferFlags::default(), TransferFlags::empty());
+        assert_eq!(Direction::default(), Direction::Out);
+        assert_eq!(UsbSetupPacket::default().to_bytes(), [0u8; 8]);
+        assert_eq!(Packet::CmdUnlink(CmdUnlink::default()).write_to_vec().unwrap()[4..], [0u8; 44]);
+        assert_eq!(Packet::RetUnlink(RetUnlink::default()).write_to_vec().unwrap()[4..], [0u8; 44]);
+    }
+
+    #[test]
+    fn test_zero_length_control_out() {
+        // SET_CONFIGURATION 1, which has no data stage
+        let setup = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 0).seqnum(3).setup(setup).build();
+        assert_eq!(cmd.buffer_length, 0);
+        assert_eq!(cmd.data, Some(vec![]));
+        assert_eq!(cmd.setup_packet().length, 0);
+
+        let buf = Packet::CmdSubmit(cmd.clone()).write_to_vec().unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), Packet::CmdSubmit(cmd.clone()));
+
+        match Packet::CmdSubmit(CmdSubmit { data: None, ..cmd }).write_to_vec() {
+            Err(PacketError::PacketError(ref m)) => assert_eq!(m, "OUT submit 3 has no data"),
+            x => panic!("Expected data error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_in_status_stage() {
+        let setup = [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let cmd = CmdSubmitBuilder::new(Direction::In, 0).seqnum(4).setup(setup).build();
+        assert_eq!(cmd.buffer_length, 0);
+        assert_eq!(cmd.data, None);
+        let buf = Packet::CmdSubmit(cmd.clone()).write_to_vec().unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), Packet::CmdSubmit(cmd.clone()));
+        assert!(Packet::CmdSubmit(CmdSubmit { data: Some(vec![]), ..cmd.clone() }).write_to_vec().is_err());
+
+        let ret = RetSubmit {
+            seqnum: 4,
+            devid: 0,
+            direction: Direction::In,
+            ep: 0,
+            status: 0,
+            length: 0,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup,
+            data: Some(vec![]),
+            iso_packets: Vec::new()
+        };
+        ret.check_length(&cmd).unwrap();
+        let buf = Packet::RetSubmit(ret.clone()).write_to_vec().unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), Packet::RetSubmit(ret));
+    }
+
+    #[test]
+    fn test_cmd_submit_payload_claim() {
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 1).data(vec![1, 2, 3, 4]).build();
+        let mut encoded = Packet::CmdSubmit(cmd).write_to_vec().unwrap();
+        encoded[24..28].copy_from_slice(&DEFAULT_MAX_PAYLOAD.to_be_bytes());
+        let mut buf = Vec::new();
+        match Packet::read_into(&mut encoded.as_slice(), &mut buf) {
+            Err(PacketError::TruncatedField("CmdSubmit", "data")) => (),
+            x => panic!("Expected truncated data, got {:?}", x)
+        }
+        assert!(buf.capacity() <= PAYLOAD_CHUNK);
+    }
+
+    #[test]
+    fn test_read_with_payload_callback() {
+        use crate::util::ByteReader;
+
+        // A slow socket, handing out at most 1000 bytes per read
+        struct Trickle<'a>(&'a [u8]);
+
+        impl<'a> ByteReader for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> PacketResult<usize> {
+                let n = buf.len().min(self.0.len()).min(1000);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 2).seqnum(3).data(data.clone()).build();
+        let mut encoded = Packet::CmdSubmit(cmd.clone()).write_to_vec().unwrap();
+        Packet::ReqDevList.write(&mut encoded).unwrap();
+
+        let mut src = Trickle(&encoded);
+        let mut received = Vec::new();
+        let mut chunks = 0;
+        let pkt = Packet::read_with_payload_callback(&mut src, &ReadLimits::default(), |chunk| {
+            assert!(chunk.len() <= 1000);
+            chunks += 1;
+            received.extend_from_slice(chunk);
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks, 200);
+        assert_eq!(received, data);
+        assert_eq!(pkt, Packet::CmdSubmit(CmdSubmit { data: None, ..cmd }));
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+
+        // Other packets decode as usual
+        let ret = Packet::RetSubmit(RetSubmit {
+            seqnum: 3, devid: 0, direction: Direction::Out, ep: 2, status: 0, length: 200_000,
+            start_frame: 0, num_packets: 0, error_count: 0, setup: [0u8; 8], data: None, iso_packets: vec![]
+        });
+        let encoded = ret.write_to_vec().unwrap();
+        let pkt = Packet::read_with_payload_callback(&mut Trickle(&encoded), &ReadLimits::default(), |_| {
+            panic!("OUT reply has no payload")
+        }).unwrap();
+        assert_eq!(pkt, ret);
+
+        // A failing callback ends the read after the chunks so far
+        let encoded = Packet::CmdSubmit(CmdSubmitBuilder::new(Direction::Out, 2).data(data).build()).write_to_vec().unwrap();
+        let mut received = 0;
+        let res = Packet::read_with_payload_callback(&mut Trickle(&encoded), &ReadLimits::default(), |chunk| {
+            received += chunk.len();
+            if received >= 5000 {
+                return Err(PacketError::PacketError("Peer gone".to_string()));
+            }
+            Ok(())
+        });
+        match res {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Peer gone"),
+            x => panic!("Expected callback error, got {:?}", x)
+        }
+        assert_eq!(received, 5000);
+
+        // and so does the stream ending within the payload
+        let mut received = 0;
+        let res = Packet::read_with_payload_callback(&mut Trickle(&encoded[..48 + 2500]), &ReadLimits::default(), |chunk| {
+            received += chunk.len();
+            Ok(())
+        });
+        match res {
+            Err(PacketError::TruncatedField("CmdSubmit", "data")) => (),
+            x => panic!("Expected truncated data, got {:?}", x)
+        }
+        assert_eq!(received, 2500);
+    }
+
+    #[test]
+    fn test_rep_device_list_device_limit() {
+        let encoded: Vec<u8> = vec![
+            0x01, 0x11, 0x00, 0x05, // RepDevList
+            0, 0, 0, 0,             // status
+            0xee, 0x6b, 0x28, 0x00  // num_devices
+        ];
+        match Packet::read(&mut encoded.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert!(msg.contains("exceeds limit")),
+            x => panic!("Expected device limit error, got {:?}", x)
+        }
+        let limits = ReadLimits { max_devices: 0xffffffff, ..ReadLimits::default() };
+        match Packet::read_with_limits(&mut encoded.as_slice(), &limits) {
+            Err(PacketError::TruncatedField("DeviceDescriptor", "path")) => (),
+            x => panic!("Expected truncated path, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_truncated_field() {
+        let mut encoded: Vec<u8> = vec![
+            0x01, 0x11, 0x00, 0x05, // RepDevList
+            0, 0, 0, 0,             // status
+            0, 0, 0, 1              // num_devices
+        ];
+        encoded.extend_from_slice(b"/foo/bar");
+        encoded.resize(12 + 256, 0);    // path, cut off before busid
+        let err = Packet::read(&mut encoded.as_slice()).unwrap_err();
+        match err {
+            PacketError::TruncatedField("DeviceDescriptor", "busid") => (),
+            ref x => panic!("Expected truncated busid, got {:?}", x)
+        }
+        assert_eq!(err.kind(), PacketErrorKind::Truncated);
+        assert_eq!(err.to_string(), "EOF while reading DeviceDescriptor.busid");
+    }
+
+    #[test]
+    fn test_encoded_len_failed_import() {
+        let dl = Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice));
+        let mut buf = Vec::new();
         dl.write(&mut buf).unwrap();
-        println!("Original structure: {:?}", dl);
-        println!("Encoded: {:?}", buf);
-        let dec = Packet::read(&mut buf.as_slice()).unwrap();
-        println!("Decoded structure: {:?}", dec);
+        assert_eq!(dl.encoded_len(), buf.len());
+        assert_eq!(buf.len(), 8 + DEVICE_INFO_LEN);
+        assert_eq!(Packet::ReqDevList.encoded_len(), 8);
+    }
+
+    #[test]
+    fn test_failed_import_padding() {
+        let dl = Packet::RepImport(RepImport::failed(UsbIpStatus::DeviceBusy));
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+        Packet::ReqDevList.write(&mut buf).unwrap();
+        let mut src = buf.as_slice();
+        assert_eq!(Packet::read(&mut src).unwrap(), dl);
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqDevList);
+
+        // Linux usbipd: status only, then the connection is closed
+        let short: Vec<u8> = vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 2];
+        assert_eq!(Packet::read(&mut short.as_slice()).unwrap(), dl);
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(Packet::read(&mut &[][..]).unwrap_err().kind(), PacketErrorKind::ConnectionClosed);
+        assert_eq!(Packet::read(&mut &[0x01, 0x11][..]).unwrap_err().kind(), PacketErrorKind::Truncated);
+        assert_eq!(Packet::read(&mut &[0x01, 0x06, 0x80, 0x05][..]).unwrap_err().kind(),
+                   PacketErrorKind::UnsupportedVersion);
+        assert_eq!(PacketError::ImportFailed(ImportError::DeviceBusy).kind(), PacketErrorKind::ImportFailed);
+    }
+
+    #[test]
+    fn test_unknown_commands() {
+        let op: Vec<u8> = vec![0x01, 0x11, 0x80, 0x42, 0, 0, 0, 0];
+        match Packet::read(&mut op.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Unknown op command: 0x8042"),
+            x => panic!("Expected unknown op command, got {:?}", x)
+        }
+        let urb: Vec<u8> = vec![0, 0, 0, 0x17, 0, 0, 0, 0];
+        match Packet::read(&mut urb.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Unknown URB command: 0x00000017"),
+            x => panic!("Expected unknown URB command, got {:?}", x)
+        }
+        assert!(PacketTypes::from_u32(0xdeadbeef).is_err());
+        assert_eq!(PacketTypes::from_u32(0x01118003).unwrap(), PacketTypes::ReqImport);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_json_roundtrip() {
+        let dl = Packet::RepDevList(RepDevList {
+            status: 0,
+            num_devices: 1,
+            devices: vec![device()]
+        });
+        let json = serde_json::to_string(&dl).unwrap();
+        println!("JSON: {}", json);
+        let dec: Packet = serde_json::from_str(&json).unwrap();
         assert_eq!(dl, dec);
+
+        assert_eq!(serde_json::to_string(&Direction::In).unwrap(), "\"In\"");
+        assert_eq!(serde_json::to_string(&(TransferFlags::SHORT_NOT_OK | TransferFlags::ZERO_PACKET)).unwrap(), "65");
+        assert!(serde_json::from_str::<TransferFlags>("1024").is_err());
+    }
+
+    #[test]
+    fn test_clone_cmd_submit() {
+        let cmd = CmdSubmit {
+            seqnum: 9,
+            devid: 0x00030002,
+            direction: Direction::Out,
+            ep: 1,
+            transfer_flags: TransferFlags::SHORT_NOT_OK,
+            buffer_length: 3,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup: [0u8; 8],
+            data: Some(vec![7, 8, 9]),
+            iso_packets: Vec::new()
+        };
+        let copy = cmd.clone();
+        assert_eq!(cmd, copy);
+        assert_eq!(Packet::CmdSubmit(cmd.clone()), Packet::CmdSubmit(copy).clone());
+    }
+
+    #[test]
+    fn test_cmd_submit_builder() {
+        let expected = CmdSubmit {
+            seqnum: 23,
+            devid: 0x00030002,
+            direction: Direction::Out,
+            ep: 2,
+            transfer_flags: TransferFlags::ZERO_PACKET,
+            buffer_length: 4,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup: [0u8; 8],
+            data: Some(vec![1, 2, 3, 4]),
+            iso_packets: Vec::new()
+        };
+        let built = CmdSubmitBuilder::new(Direction::Out, 2)
+            .seqnum(23)
+            .devid(0x00030002)
+            .transfer_flags(TransferFlags::ZERO_PACKET)
+            .data(vec![1, 2, 3, 4])
+            .build();
+        assert_eq!(expected, built);
+
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let control = CmdSubmitBuilder::new(Direction::In, 0)
+            .seqnum(1)
+            .buffer_length(18)
+            .setup(setup)
+            .build();
+        assert_eq!(control, CmdSubmit {
+            seqnum: 1,
+            devid: 0,
+            direction: Direction::In,
+            ep: 0,
+            transfer_flags: TransferFlags::empty(),
+            buffer_length: 18,
+            start_frame: 0,
+            num_packets: 0,
+            interval: 0,
+            setup,
+            data: None,
+            iso_packets: Vec::new()
+        });
+    }
+
+    #[test]
+    fn test_read_into_borrowed() {
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 2)
+            .seqnum(7)
+            .data(vec![1, 2, 3, 4])
+            .build();
+        let ret = RetSubmit {
+            seqnum: 7,
+            devid: 0,
+            direction: Direction::In,
+            ep: 1,
+            status: 0,
+            length: 3,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(vec![5, 6, 7]),
+            iso_packets: Vec::new()
+        };
+        let mut encoded = Vec::new();
+        Packet::CmdSubmit(cmd.clone()).write(&mut encoded).unwrap();
+        Packet::RetSubmit(ret.clone()).write(&mut encoded).unwrap();
+        Packet::ReqDevList.write(&mut encoded).unwrap();
+
+        let mut src = encoded.as_slice();
+        let mut buf = Vec::with_capacity(16);
+        let capacity = buf.capacity();
+        match Packet::read_into(&mut src, &mut buf).unwrap() {
+            PacketRef::CmdSubmit(view) => {
+                assert_eq!(view.data, Some(&[1u8, 2, 3, 4][..]));
+                assert_eq!(view, cmd.as_view());
+                let mut forwarded = Vec::new();
+                view.write(&mut forwarded).unwrap();
+                assert_eq!(forwarded, &encoded[..48 + 4]);
+                assert_eq!(view.into_owned(), cmd);
+            },
+            x => panic!("Expected CmdSubmit, got {:?}", x)
+        }
+        match Packet::read_into(&mut src, &mut buf).unwrap() {
+            PacketRef::RetSubmit(view) => assert_eq!(view.into_owned(), ret),
+            x => panic!("Expected RetSubmit, got {:?}", x)
+        }
+        assert_eq!(buf.capacity(), capacity);
+        let other = Packet::read_into(&mut src, &mut buf).unwrap();
+        assert_eq!(other, PacketRef::Other(Packet::ReqDevList));
+    }
+
+    #[test]
+    fn test_unlink_roundtrip() {
+        let cmd = Packet::CmdUnlink(CmdUnlink {
+            seq: 24,
+            devid: 0x00030002,
+            direction: Direction::Out,
+            ep: 2,
+            seqnum: 23
+        });
+        let ret = Packet::RetUnlink(RetUnlink {
+            seqnum: 24,
+            devid: 0x00030002,
+            direction: Direction::Out,
+            ep: 2,
+            status: 0
+        });
+        for dl in &[cmd, ret] {
+            let mut buf = Vec::new();
+            dl.write(&mut buf).unwrap();
+            println!("Original structure: {:?}", dl);
+            println!("Encoded: {:?}", buf);
+            assert_eq!(buf.len(), 48);
+            assert_eq!(dl.encoded_len(), buf.len());
+            assert_eq!(&buf[12..16], &[0, 0, 0, 0]);
+            let dec = Packet::read(&mut buf.as_slice()).unwrap();
+            assert_eq!(*dl, dec);
+        }
+    }
+
+    #[test]
+    fn test_busid() {
+        for &valid in ["3-2", "1-1.4.2", "10-12", "2-1.1.1.1.1"].iter() {
+            assert_eq!(valid.parse::<BusId>().unwrap().as_str(), valid);
+        }
+        for &invalid in ["", "3", "3-", "-2", "3-2.", "3-.2", "3-2-1", "usb1", "a-1", "3 -2", "3-2\n", "１-2"].iter() {
+            match invalid.parse::<BusId>() {
+                Err(PacketError::PacketError(ref msg)) => assert!(msg.starts_with("Invalid busid"), "{}", msg),
+                x => panic!("Expected busid error for {:?}, got {:?}", invalid, x)
+            }
+        }
+        let long = format!("1-{}", vec!["1"; 15].join("."));
+        assert_eq!(long.len(), 31);
+        assert!(long.parse::<BusId>().is_ok());
+        match format!("{}.1", long).parse::<BusId>() {
+            Err(PacketError::PacketError(ref msg)) => assert!(msg.ends_with("is longer than 31 bytes")),
+            x => panic!("Expected length error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_cmd_unlink_for_submit() {
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(41).devid(devid(3, 2)).buffer_length(64).build();
+        let unlink = CmdUnlink::for_submit(&cmd, 42);
+        assert_eq!(unlink, CmdUnlink { seq: 42, devid: 0x00030002, direction: Direction::In, ep: 1, seqnum: 41 });
+        assert_eq!(Packet::CmdUnlink(unlink).to_string(), "CmdUnlink seq=42 dev=3-2 unlink=41");
+    }
+
+    #[test]
+    fn test_direction_helpers() {
+        assert!(Direction::In.is_in());
+        assert!(!Direction::In.is_out());
+        assert!(Direction::Out.is_out());
+        assert!(!Direction::Out.is_in());
+        assert_eq!(Direction::In.opposite(), Direction::Out);
+        assert_eq!(Direction::Out.opposite(), Direction::In);
+        assert_eq!(Direction::In.opposite().opposite(), Direction::In);
+    }
+
+    #[test]
+    fn test_direction_encoding() {
+        assert_eq!(Direction::Out.to_u32(), 0x00000000);
+        assert_eq!(Direction::In.to_u32(), 0x00000001);
+        assert_eq!(Direction::from_u32(0).unwrap(), Direction::Out);
+        assert_eq!(Direction::from_u32(1).unwrap(), Direction::In);
+        assert!(Direction::from_u32(2).is_err());
+
+        let ret = Packet::RetSubmit(RetSubmit {
+            seqnum: 1,
+            devid: 0,
+            direction: Direction::Out,
+            ep: 2,
+            status: 0,
+            length: 4,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: None,
+            iso_packets: Vec::new()
+        });
+        let mut buf = Vec::new();
+        ret.write(&mut buf).unwrap();
+        match Packet::read(&mut buf.as_slice()).unwrap() {
+            Packet::RetSubmit(r) => assert_eq!(r.direction, Direction::Out),
+            x => panic!("Expected RetSubmit, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_rep_device_list_interface_count() {
+        let mut device = DeviceDescriptor { num_interfaces: 3, interfaces: vec![device().interfaces[0].clone(); 3], ..device() };
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![device.clone()] });
+        let mut buf = Vec::new();
+        dl.write(&mut buf).unwrap();
+
+        // Cut off in the middle of the third interface
+        let truncated = &buf[..buf.len() - 2];
+        match Packet::read(&mut &truncated[..]) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Device 3-2 declared 3 interfaces, stream ended after 2"),
+            x => panic!("Expected interface count error, got {:?}", x)
+        }
+
+        // num_interfaces is the last byte of the device info block
+        let mut excessive = buf.clone();
+        excessive[12 + DEVICE_INFO_LEN - 1] = MAX_INTERFACES + 1;
+        match Packet::read(&mut excessive.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert!(msg.contains("at most 32")),
+            x => panic!("Expected interface limit error, got {:?}", x)
+        }
+
+        device.num_interfaces = 2;
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 1, devices: vec![device] });
+        assert!(dl.write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_rep_device_list_interface_limit() {
+        let interface = InterfaceDescriptor { interface_class: 3, interface_subclass: 1, interface_protocol: 1 };
+        let device = DeviceDescriptor { num_interfaces: 3, interfaces: vec![interface.clone(), interface.clone(), interface], ..device() };
+        let dl = Packet::RepDevList(RepDevList { status: 0, num_devices: 2, devices: vec![device.clone(), device] });
+        let buf = dl.write_to_vec().unwrap();
+
+        let limits = ReadLimits { max_interfaces: 2, ..ReadLimits::default() };
+        match Packet::read_with_limits(&mut buf.as_slice(), &limits) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Device 3-2 declares 3 interfaces, at most 2 are allowed"),
+            x => panic!("Expected interface limit error, got {:?}", x)
+        }
+        let limits = ReadLimits { max_devices: 1, ..ReadLimits::default() };
+        match Packet::read_with_limits(&mut buf.as_slice(), &limits) {
+            Err(PacketError::PacketError(ref msg)) => assert!(msg.contains("exceeds limit")),
+            x => panic!("Expected device limit error, got {:?}", x)
+        }
+        let limits = ReadLimits { max_devices: 2, max_interfaces: 3, ..ReadLimits::default() };
+        assert_eq!(Packet::read_with_limits(&mut buf.as_slice(), &limits).unwrap(), dl);
+    }
+
+    #[test]
+    fn test_requests_without_status() {
+        let mut encoded = vec![0x01, 0x11, 0x80, 0x03];
+        encoded.extend_from_slice(b"1-1.4");
+        encoded.resize(4 + 32, 0);
+        encoded.extend_from_slice(&[0x01, 0x11, 0x80, 0x05]);
+        let limits = ReadLimits { request_status: false, ..ReadLimits::default() };
+        let mut src = encoded.as_slice();
+        assert_eq!(Packet::read_with_limits(&mut src, &limits).unwrap(),
+                   Packet::ReqImport(ReqImport { busid: "1-1.4".to_string() }));
+        assert_eq!(Packet::read_with_limits(&mut src, &limits).unwrap(), Packet::ReqDevList);
+        assert!(src.is_empty());
+
+        // By default the first 4 bytes of the busid are taken for the status
+        match Packet::read(&mut encoded.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert!(msg.contains("nonzero reserved status 0x312d312e"), "{}", msg),
+            x => panic!("Expected reserved status error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_unpadded_interfaces() {
+        let hid = InterfaceDescriptor { interface_class: 3, interface_subclass: 1, interface_protocol: 2 };
+        let storage = InterfaceDescriptor { interface_class: 8, interface_subclass: 6, interface_protocol: 0x50 };
+        let device = DeviceDescriptor { num_interfaces: 2, interfaces: vec![hid.clone(), storage.clone()], ..device() };
+        let mut padded = Vec::new();
+        device.write(&mut padded).unwrap();
+        assert_eq!(&padded[312..], &[3, 1, 2, 0, 8, 6, 0x50, 0]);
+        assert_eq!(DeviceDescriptor::read(&mut padded.as_slice()).unwrap(), device);
+
+        let mut packed = padded[..312].to_vec();
+        packed.extend_from_slice(&[3, 1, 2, 8, 6, 0x50]);
+        let limits = ReadLimits { padded_interfaces: false, ..ReadLimits::default() };
+        let mut src = packed.as_slice();
+        assert_eq!(DeviceDescriptor::read_with_limits(&mut src, &limits).unwrap(), device);
+        assert!(src.is_empty());
+
+        // Read as padded, the packed interfaces come out shifted
+        match DeviceDescriptor::read(&mut packed.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Device 3-2 declared 2 interfaces, stream ended after 1"),
+            x => panic!("Expected truncation error, got {:?}", x)
+        }
+        assert_eq!(InterfaceDescriptor::read_padded(&mut &[8u8, 6, 0x50][..], false).unwrap(), storage);
+        assert_eq!(InterfaceDescriptor::read_padded(&mut &[3u8, 1, 2, 0][..], true).unwrap(), hid);
+    }
+
+    #[test]
+    fn test_transfer_flags() {
+        // SHORT_NOT_OK | DIR_IN | DMA_MAP_SINGLE as sent by a Linux vhci
+        let mut encoded: Vec<u8> = vec![
+            0, 0, 0, 1,         // CmdSubmit
+            0, 0, 0, 1,         // seqnum
+            0, 3, 0, 2,         // devid
+            0, 0, 0, 1,         // direction
+            0, 0, 0, 1,         // ep
+            0, 1, 2, 1,         // transfer_flags
+            0, 0, 0, 8,         // buffer_length
+            0, 0, 0, 0,         // start_frame
+            0, 0, 0, 0,         // num_packets
+            0, 0, 0, 0,         // interval
+            0, 0, 0, 0, 0, 0, 0, 0
+        ];
+        match Packet::read(&mut encoded.as_slice()).unwrap() {
+            Packet::CmdSubmit(cmd) => assert_eq!(cmd.transfer_flags,
+                TransferFlags::SHORT_NOT_OK | TransferFlags::DIR_IN | TransferFlags::DMA_MAP_SINGLE),
+            x => panic!("Expected CmdSubmit, got {:?}", x)
+        }
+
+        // A reserved bit fails strict decoding and is dropped otherwise
+        encoded[20] = 0x80;
+        match Packet::read(&mut encoded.as_slice()) {
+            Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Invalid transfer_flags: 0x80010201"),
+            x => panic!("Expected transfer_flags error, got {:?}", x)
+        }
+        assert_eq!(Packet::read(&mut encoded.as_slice()).unwrap_err().kind(), PacketErrorKind::Protocol);
+        let limits = ReadLimits { strict_transfer_flags: false, ..ReadLimits::default() };
+        match Packet::read_with_limits(&mut encoded.as_slice(), &limits).unwrap() {
+            Packet::CmdSubmit(cmd) => assert_eq!(cmd.transfer_flags.bits(), 0x00010201),
+            x => panic!("Expected CmdSubmit, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn test_devid() {
+        assert_eq!(devid(3, 2), 0x00030002);
+        assert_eq!(split_devid(0x00030002), (3, 2));
+        assert_eq!(split_devid(devid(0xffff, 0x1234)), (0xffff, 0x1234));
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).devid(devid(3, 2)).build();
+        assert_eq!(cmd.bus_dev(), (3, 2));
+    }
+
+    // Hands out one byte per read and times out once stall_at bytes are gone
+    #[cfg(feature = "std")]
+    struct SlowReader {
+        data: Vec<u8>,
+        pos: usize,
+        stall_at: usize,
+    }
+
+    #[cfg(feature = "std")]
+    impl io::Read for SlowReader {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.stall_at {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            if self.pos == self.data.len() || dst.is_empty() {
+                return Ok(0);
+            }
+            dst[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    // Like an unbuffered socket getting its segments in the worst possible cut
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_one_byte_reads() {
+        let cmd = CmdSubmitBuilder::new(Direction::Out, 3)
+            .seqnum(11)
+            .devid(devid(3, 2))
+            .transfer_flags(TransferFlags::ISO_ASAP)
+            .data((0..64).collect())
+            .iso_packets(vec![
+                IsoPacketDescriptor { offset: 0, length: 32, actual_length: 0, status: 0 },
+                IsoPacketDescriptor { offset: 32, length: 32, actual_length: 0, status: 0 }
+            ])
+            .build();
+        let packets = [
+            Packet::ReqDevList,
+            Packet::ReqImport(ReqImport { busid: "3-2".to_string() }),
+            Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice)),
+            Packet::CmdSubmit(cmd),
+            Packet::CmdUnlink(CmdUnlink { seq: 12, devid: devid(3, 2), direction: Direction::Out, ep: 3, seqnum: 11 }),
+        ];
+        let mut data = Vec::new();
+        for pkt in packets.iter() {
+            pkt.write(&mut data).unwrap();
+        }
+
+        let mut src = SlowReader { data: data.clone(), pos: 0, stall_at: usize::MAX };
+        for pkt in packets.iter() {
+            assert_eq!(&Packet::read(&mut src).unwrap(), pkt);
+        }
+        assert_eq!(src.pos, data.len());
+        assert_eq!(Packet::read(&mut src).unwrap_err().kind(), PacketErrorKind::ConnectionClosed);
+
+        let mut src = SlowReader { data, pos: 0, stall_at: usize::MAX };
+        let mut buf = Vec::new();
+        for pkt in packets.iter() {
+            assert_eq!(&Packet::read_into(&mut src, &mut buf).unwrap().into_owned(), pkt);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_timeout() {
+        let mut data = Vec::new();
+        Packet::ReqImport(ReqImport { busid: "3-2".to_string() }).write(&mut data).unwrap();
+        let kind = |stall_at| {
+            let mut src = SlowReader { data: data.clone(), pos: 0, stall_at };
+            Packet::read(&mut src).unwrap_err().kind()
+        };
+        assert_eq!(kind(0), PacketErrorKind::Timeout);
+        assert_eq!(kind(2), PacketErrorKind::TimeoutMidPacket);
+        assert_eq!(kind(20), PacketErrorKind::TimeoutMidPacket);
+
+        let mut src = SlowReader { data: data.clone(), pos: 0, stall_at: 0 };
+        assert_eq!(Packet::read(&mut src).unwrap_err().kind(), PacketErrorKind::Timeout);
+        src.stall_at = data.len() + 1;
+        assert_eq!(Packet::read(&mut src).unwrap(), Packet::ReqImport(ReqImport { busid: "3-2".to_string() }));
+    }
+
+    // Generators for test_roundtrip_property. What they produce always
+    // encodes and decodes back to itself: strings fit their fields (busids in
+    // ASCII), the data goes with the direction and matches buffer_length or
+    // length, and num_packets is the number of iso descriptors.
+    fn arb_direction() -> impl Strategy<Value = Direction> {
+        prop_oneof![Just(Direction::In), Just(Direction::Out)]
+    }
+
+    fn arb_iso_packets() -> impl Strategy<Value = Vec<IsoPacketDescriptor>> {
+        prop::collection::vec(any::<[u32; 4]>(), 0..4).prop_map(|isos| isos.into_iter()
+            .map(|[offset, length, actual_length, status]| IsoPacketDescriptor { offset, length, actual_length, status })
+            .collect())
+    }
+
+    fn arb_device() -> impl Strategy<Value = DeviceDescriptor> {
+        let ids = any::<(u32, u32, u32, u16, u16, u16)>();
+        let interfaces = prop::collection::vec(any::<[u8; 3]>(), 0..4);
+        ("\\PC{0,60}", "[ -~]{0,31}", ids, any::<[u8; 5]>(), interfaces).prop_map(|(path, busid, ids, bytes, interfaces)| {
+            let (busnum, devnum, speed, id_vendor, id_product, bcd_device) = ids;
+            let [device_class, device_subclass, device_protocol, configuration_value, num_configurations] = bytes;
+            DeviceDescriptor {
+                path, busid, busnum, devnum, speed, id_vendor, id_product, bcd_device,
+                device_class, device_subclass, device_protocol, configuration_value, num_configurations,
+                num_interfaces: interfaces.len() as u8,
+                interfaces: interfaces.into_iter()
+                    .map(|[interface_class, interface_subclass, interface_protocol]| InterfaceDescriptor {
+                        interface_class, interface_subclass, interface_protocol
+                    })
+                    .collect()
+            }
+        })
+    }
+
+    fn arb_rep_devlist() -> impl Strategy<Value = RepDevList> {
+        prop_oneof![
+            prop::collection::vec(arb_device(), 0..3)
+                .prop_map(|devices| RepDevList { status: 0, num_devices: devices.len() as u32, devices }),
+            // A failed list comes without devices
+            (1..=u32::MAX).prop_map(|status| RepDevList { status, num_devices: 0, devices: vec![] }),
+        ]
+    }
+
+    fn arb_rep_import() -> impl Strategy<Value = RepImport> {
+        prop_oneof![
+            arb_device().prop_map(|dev| RepImport::from_device(&dev, 0)),
+            (1..=u32::MAX).prop_map(|status| RepImport::failed(UsbIpStatus::from_u32(status))),
+        ]
+    }
+
+    fn arb_cmd_submit() -> impl Strategy<Value = CmdSubmit> {
+        let header = any::<(u32, u32, u32, u32, u32, u32, u32)>();
+        let data = prop::collection::vec(any::<u8>(), 0..64);
+        (header, arb_direction(), any::<[u8; 8]>(), data, arb_iso_packets()).prop_map(|(header, direction, setup, data, iso_packets)| {
+            let (seqnum, devid, ep, flags, in_length, start_frame, interval) = header;
+            let (buffer_length, data) = match direction {
+                Direction::In => (in_length, None),
+                Direction::Out => (data.len() as u32, Some(data)),
+            };
+            CmdSubmit {
+                seqnum, devid, direction, ep,
+                transfer_flags: TransferFlags::from_bits_truncate(flags),
+                buffer_length, start_frame,
+                num_packets: iso_packets.len() as u32,
+                interval, setup, data, iso_packets
+            }
+        })
+    }
+
+    fn arb_ret_submit() -> impl Strategy<Value = RetSubmit> {
+        let header = any::<(u32, u32, u32, u32, u32, u32, u32)>();
+        let data = prop::collection::vec(any::<u8>(), 0..64);
+        (header, arb_direction(), any::<[u8; 8]>(), data, arb_iso_packets()).prop_map(|(header, direction, setup, data, iso_packets)| {
+            let (seqnum, devid, ep, status, out_length, start_frame, error_count) = header;
+            let (length, data) = match direction {
+                Direction::In => (data.len() as u32, Some(data)),
+                Direction::Out => (out_length, None),
+            };
+            RetSubmit {
+                seqnum, devid, direction, ep, status, length, start_frame,
+                num_packets: iso_packets.len() as u32,
+                error_count, setup, data, iso_packets
+            }
+        })
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        prop_oneof![
+            Just(Packet::ReqDevList),
+            arb_rep_devlist().prop_map(Packet::RepDevList),
+            "[ -~]{0,31}".prop_map(|busid| Packet::ReqImport(ReqImport { busid })),
+            arb_rep_import().prop_map(Packet::RepImport),
+            arb_cmd_submit().prop_map(Packet::CmdSubmit),
+            arb_ret_submit().prop_map(Packet::RetSubmit),
+            (any::<(u32, u32, u32, u32)>(), arb_direction()).prop_map(|((seq, devid, ep, seqnum), direction)| {
+                Packet::CmdUnlink(CmdUnlink { seq, devid, direction, ep, seqnum })
+            }),
+            (any::<(u32, u32, u32, u32)>(), arb_direction()).prop_map(|((seqnum, devid, ep, status), direction)| {
+                Packet::RetUnlink(RetUnlink { seqnum, devid, direction, ep, status })
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip_property(pkt in arb_packet()) {
+            let buf = pkt.write_to_vec().unwrap();
+            prop_assert_eq!(buf.len(), pkt.encoded_len());
+            prop_assert_eq!(Packet::read(&mut buf.as_slice()).unwrap(), pkt);
+        }
     }
 }
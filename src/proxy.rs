@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::client::send;
+use crate::memory::{MemoryReader, MemoryStream, MemoryWriter};
+use crate::protocol::{Direction, Packet, PacketError, PacketResult, ReadLimits};
+use crate::stream::PacketStream;
+use crate::util::ByteReader;
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum ProxyDirection {
+    ClientToServer,
+    ServerToClient,
+}
+
+// A connection the proxy can read from on one thread while writing to it on
+// another, as URBs flow both ways at once. Dropping the writer has to end
+// the stream for the peer even though the reader is still around.
+pub trait Duplex {
+    type Reader: Read + Send;
+    type Writer: Write + Send;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl Duplex for MemoryStream {
+    type Reader = MemoryReader;
+    type Writer = MemoryWriter;
+
+    fn split(self) -> io::Result<(MemoryReader, MemoryWriter)> {
+        Ok(MemoryStream::split(self))
+    }
+}
+
+impl Duplex for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpWriter;
+
+    fn split(self) -> io::Result<(TcpStream, TcpWriter)> {
+        Ok((self.try_clone()?, TcpWriter(self)))
+    }
+}
+
+// The URBs forwarded to the server that are still waiting for their reply:
+// the direction of each submit, which a RetSubmit from the Linux stub does not
+// carry, and the submit each unlink is after
+#[derive(Default)]
+struct InFlight {
+    directions: HashMap<u32, Direction>,
+    unlinks: HashMap<u32, u32>,
+}
+
+impl InFlight {
+    fn sent(&mut self, pkt: &Packet) {
+        match *pkt {
+            Packet::CmdSubmit(ref cmd) => { self.directions.insert(cmd.seqnum, cmd.direction); },
+            Packet::CmdUnlink(ref cmd) => { self.unlinks.insert(cmd.seq, cmd.seqnum); },
+            _ => (),
+        }
+    }
+
+    // A RetSubmit has taken its direction already, a cancelled URB gets no
+    // RetSubmit at all
+    fn answered(&mut self, pkt: &Packet) {
+        if let Packet::RetUnlink(ref ret) = *pkt {
+            if let Some(target) = self.unlinks.remove(&ret.seqnum) {
+                if ret.status != 0 {
+                    self.directions.remove(&target);
+                }
+            }
+        }
+    }
+}
+
+// Shuts down the sending side of the socket when dropped, which closing one
+// of several handles to it does not do
+pub struct TcpWriter(TcpStream);
+
+impl Write for TcpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TcpWriter {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Write);
+    }
+}
+
+// Forwards packets between a client and a server, handing each one to
+// observer (with the way it is going) before passing it on, e.g. to record
+// the traffic. Returns once both sides have closed their connection, or with
+// the first error, which closes both.
+//
+// The op phase is strictly request and reply, so the proxy reads one reply
+// from the server for each request of the client. Only a successful import
+// switches both connections to URBs, which are then forwarded independently
+// in each direction as they come. A client sending URBs before that would
+// leave the two sides out of step and fails the proxy.
+pub fn proxy<C, S, F>(client: C, server: S, observer: F) -> PacketResult<()>
+    where C: Duplex, S: Duplex, F: FnMut(ProxyDirection, &Packet) + Send
+{
+    let (client_rx, client_tx) = client.split()?;
+    let (server_rx, server_tx) = server.split()?;
+    let mut from_client = PacketStream::new(BufReader::new(client_rx));
+    let mut from_server = PacketStream::new(BufReader::new(server_rx));
+    let mut to_client = BufWriter::new(client_tx);
+    let mut to_server = BufWriter::new(server_tx);
+    let observer = Mutex::new(observer);
+
+    loop {
+        let req = match from_client.next() {
+            Some(req) => req?,
+            None => return Ok(()),
+        };
+        match req {
+            Packet::ReqDevList | Packet::ReqImport(_) => (),
+            p => return Err(PacketError::PacketError(
+                format!("Unexpected {:?} packet from the client before a device was imported", p.packet_type()))),
+        }
+        forward(&observer, ProxyDirection::ClientToServer, &req, &mut to_server)?;
+        let reply = match from_server.next() {
+            Some(reply) => reply?,
+            None => return Err(PacketError::ConnectionClosed),
+        };
+        forward(&observer, ProxyDirection::ServerToClient, &reply, &mut to_client)?;
+        if let Packet::RepImport(ref rep) = reply {
+            if rep.status().is_ok() {
+                break;
+            }
+        }
+    }
+
+    debug!("Device imported, forwarding URBs");
+    let observer = &observer;
+    let in_flight = &Mutex::new(InFlight::default());
+    thread::scope(|scope| {
+        let upstream = scope.spawn(move || pump_requests(from_client, to_server, in_flight, observer));
+        let downstream = pump_replies(from_server.into_inner(), to_client, in_flight, observer);
+        downstream.and(upstream.join().unwrap())
+    })
+}
+
+// Each direction of the URB phase is forwarded until its source is done or
+// fails, then the destination is hung up on, which in turn ends the other
+// direction. A submit is recorded before it goes out, so its direction is
+// known by the time the reply comes in.
+fn pump_requests<R, W, F>(packets: PacketStream<R>, mut dst: W, in_flight: &Mutex<InFlight>,
+                          observer: &Mutex<F>) -> PacketResult<()>
+    where R: Read, W: Write, F: FnMut(ProxyDirection, &Packet)
+{
+    for pkt in packets {
+        let pkt = pkt?;
+        in_flight.lock().unwrap().sent(&pkt);
+        forward(observer, ProxyDirection::ClientToServer, &pkt, &mut dst)?;
+    }
+    Ok(())
+}
+
+fn pump_replies<R, W, F>(mut src: R, mut dst: W, in_flight: &Mutex<InFlight>, observer: &Mutex<F>) -> PacketResult<()>
+    where R: ByteReader, W: Write, F: FnMut(ProxyDirection, &Packet)
+{
+    loop {
+        let direction_of = |seqnum| in_flight.lock().unwrap().directions.remove(&seqnum);
+        let pkt = match Packet::read_reply(&mut src, &ReadLimits::default(), direction_of) {
+            Ok(pkt) => pkt,
+            Err(PacketError::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        in_flight.lock().unwrap().answered(&pkt);
+        forward(observer, ProxyDirection::ServerToClient, &pkt, &mut dst)?;
+    }
+}
+
+fn forward<F, W>(observer: &Mutex<F>, dir: ProxyDirection, pkt: &Packet, dst: &mut W) -> PacketResult<()>
+    where F: FnMut(ProxyDirection, &Packet), W: Write
+{
+    (observer.lock().unwrap())(dir, pkt);
+    send(dst, pkt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use crate::client::UsbIpClient;
+    use crate::memory;
+    use crate::protocol::{self, Packet, RepImport, RetSubmit, CmdSubmitBuilder, Direction, PacketTypes};
+    use crate::proxy::{proxy, ProxyDirection};
+    use crate::server::UsbIpServer;
+    use crate::test_util::StubDevice;
+
+    #[test]
+    fn test_proxy_handshake() {
+        let (client_end, proxy_client_end) = memory::pair();
+        let (proxy_server_end, server_end) = memory::pair();
+        let srv = thread::spawn(move || UsbIpServer::new(StubDevice).handle(server_end));
+        let (tx, rx) = channel();
+        let prx = thread::spawn(move || proxy(proxy_client_end, proxy_server_end, |dir, pkt| {
+            tx.send((dir, pkt.packet_type())).unwrap();
+        }));
+
+        let mut client = UsbIpClient::new(client_end);
+        assert_eq!(client.list_devices().unwrap(), vec![StubDevice::descriptor()]);
+        let mut dev = client.import("1-1").unwrap();
+        let cmd = CmdSubmitBuilder::new(Direction::In, 0).devid(protocol::devid(1, 2)).buffer_length(2).build();
+        let seqnum = dev.submit(cmd).unwrap();
+        match dev.read_reply().unwrap() {
+            protocol::Packet::RetSubmit(ret) => {
+                assert_eq!(ret.seqnum, seqnum);
+                assert_eq!(ret.data, Some(vec![0x12, 0x01]));
+            },
+            p => panic!("Expected RetSubmit, got {:?}", p),
+        }
+        drop(dev);
+        prx.join().unwrap().unwrap();
+        srv.join().unwrap().unwrap();
+
+        use self::ProxyDirection::*;
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![
+            (ClientToServer, PacketTypes::ReqDevList),
+            (ServerToClient, PacketTypes::RepDevList),
+            (ClientToServer, PacketTypes::ReqImport),
+            (ServerToClient, PacketTypes::RepImport),
+            (ClientToServer, PacketTypes::CmdSubmit),
+            (ServerToClient, PacketTypes::RetSubmit),
+        ]);
+    }
+
+    // A RetSubmit as the Linux stub sends it, with devid, direction and ep
+    // left at 0
+    fn stub_style_reply(seqnum: u32, length: u32, data: Option<Vec<u8>>) -> Vec<u8> {
+        let direction = if data.is_some() { Direction::In } else { Direction::Out };
+        let mut buf = Packet::RetSubmit(RetSubmit { seqnum, devid: 0x10002, direction, ep: 1, length, data,
+                                                    ..Default::default() }).write_to_vec().unwrap();
+        buf[8..20].copy_from_slice(&[0; 12]);
+        buf
+    }
+
+    #[test]
+    fn test_proxy_stub_style_replies() {
+        let (client_end, proxy_client_end) = memory::pair();
+        let (proxy_server_end, mut server_end) = memory::pair();
+        let srv = thread::spawn(move || {
+            match Packet::read(&mut server_end).unwrap() {
+                Packet::ReqImport(_) => (),
+                p => panic!("Unexpected {:?}", p),
+            }
+            Packet::RepImport(RepImport::from_device(&StubDevice::descriptor(), 0)).write(&mut server_end).unwrap();
+            for _ in 0..3 {
+                let reply = match Packet::read(&mut server_end).unwrap() {
+                    Packet::CmdSubmit(cmd) if cmd.direction == Direction::In =>
+                        stub_style_reply(cmd.seqnum, 2, Some(vec![0x12, 0x01])),
+                    Packet::CmdSubmit(cmd) => stub_style_reply(cmd.seqnum, cmd.buffer_length, None),
+                    p => panic!("Unexpected {:?}", p),
+                };
+                server_end.write_all(&reply).unwrap();
+            }
+        });
+        let prx = thread::spawn(move || proxy(proxy_client_end, proxy_server_end, |_, _| ()));
+
+        let mut dev = UsbIpClient::new(client_end).import("1-1").unwrap();
+        let cmd = CmdSubmitBuilder::new(Direction::In, 1).devid(protocol::devid(1, 2)).buffer_length(2).build();
+        dev.submit(cmd.clone()).unwrap();
+        dev.submit(CmdSubmitBuilder::new(Direction::Out, 1).devid(protocol::devid(1, 2)).data(vec![1, 2, 3]).build()).unwrap();
+        dev.submit(cmd).unwrap();
+        for &(seqnum, ref data) in &[(1, Some(vec![0x12, 0x01])), (2, None), (3, Some(vec![0x12, 0x01]))] {
+            match dev.read_reply().unwrap() {
+                Packet::RetSubmit(ret) => assert_eq!((ret.seqnum, &ret.data), (seqnum, data)),
+                p => panic!("Expected RetSubmit, got {:?}", p),
+            }
+        }
+        drop(dev);
+        prx.join().unwrap().unwrap();
+        srv.join().unwrap();
+    }
+}
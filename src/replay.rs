@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{Packet, PacketError, PacketResult, Direction, ReadLimits};
+use crate::stream::PacketStream;
+use crate::client::send;
+
+// Time from writing a CmdSubmit until its RetSubmit arrived
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Latency {
+    pub seqnum: u32,
+    pub elapsed: Duration,
+}
+
+// Replays the CmdSubmits of a recording against an imported device, for load
+// testing a server. Up to window submits are in flight at a time and servers
+// may complete them in any order, so replies are matched up by seqnum.
+pub struct Replay {
+    interval: Duration,
+    window: usize,
+}
+
+impl Default for Replay {
+    fn default() -> Replay {
+        Replay::new()
+    }
+}
+
+impl Replay {
+    pub fn new() -> Replay {
+        Replay { interval: Duration::from_secs(0), window: 1 }
+    }
+
+    // Pause between two submits, to replay at a controlled rate
+    pub fn interval(mut self, interval: Duration) -> Replay {
+        self.interval = interval;
+        self
+    }
+
+    pub fn window(mut self, window: usize) -> Replay {
+        self.window = window.max(1);
+        self
+    }
+
+    // Every other packet of the recording (e.g. the replies of the recorded
+    // session) is skipped. Latencies are returned in the order the replies
+    // came in.
+    pub fn run<R: Read, S: Read + Write>(&self, recording: R, conn: &mut S) -> PacketResult<Vec<Latency>> {
+        let mut in_flight: HashMap<u32, (Instant, Direction)> = HashMap::new();
+        let mut latencies = Vec::new();
+        let submits = PacketStream::new(recording).filter_map(|p| match p {
+            Ok(Packet::CmdSubmit(cmd)) => Some(Ok(cmd)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        });
+        for (i, cmd) in submits.enumerate() {
+            let cmd = cmd?;
+            if in_flight.len() >= self.window {
+                latencies.push(Replay::complete(conn, &mut in_flight)?);
+            }
+            if i > 0 && self.interval > Duration::from_secs(0) {
+                thread::sleep(self.interval);
+            }
+            if in_flight.contains_key(&cmd.seqnum) {
+                return Err(PacketError::PacketError(format!("Seqnum {} is already in flight", cmd.seqnum)));
+            }
+            let (seqnum, direction) = (cmd.seqnum, cmd.direction);
+            send(conn, &Packet::CmdSubmit(cmd))?;
+            in_flight.insert(seqnum, (Instant::now(), direction));
+        }
+        while !in_flight.is_empty() {
+            latencies.push(Replay::complete(conn, &mut in_flight)?);
+        }
+        Ok(latencies)
+    }
+
+    // Replies are decoded with the direction of their submit, see Packet::read_reply
+    fn complete<S: Read>(conn: &mut S, in_flight: &mut HashMap<u32, (Instant, Direction)>) -> PacketResult<Latency> {
+        let reply = Packet::read_reply(conn, &ReadLimits::default(), |seqnum| in_flight.get(&seqnum).map(|&(_, d)| d))?;
+        match reply {
+            Packet::RetSubmit(ret) => match in_flight.remove(&ret.seqnum) {
+                Some((sent, _)) => Ok(Latency { seqnum: ret.seqnum, elapsed: sent.elapsed() }),
+                None => Err(PacketError::PacketError(format!("RetSubmit for unknown seqnum {}", ret.seqnum))),
+            },
+            p => Err(PacketError::PacketError(format!("Unexpected {:?} during replay", p.packet_type()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::memory;
+    use crate::protocol::{Packet, CmdSubmitBuilder, RetSubmit, Direction};
+    use crate::replay::Replay;
+
+    #[test]
+    fn test_replay_out_of_order() {
+        let mut recording = Vec::new();
+        for seqnum in 1..=3 {
+            let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(seqnum).buffer_length(4).build();
+            Packet::CmdSubmit(cmd).write(&mut recording).unwrap();
+        }
+        Packet::ReqDevList.write(&mut recording).unwrap();
+
+        let (mut client, mut server) = memory::pair();
+        let srv = thread::spawn(move || {
+            let mut cmds = Vec::new();
+            for _ in 0..3 {
+                match Packet::read(&mut server).unwrap() {
+                    Packet::CmdSubmit(cmd) => cmds.push(cmd),
+                    p => panic!("Unexpected {:?}", p),
+                }
+            }
+            for cmd in cmds.iter().rev() {
+                Packet::RetSubmit(RetSubmit {
+                    seqnum: cmd.seqnum,
+                    devid: cmd.devid,
+                    direction: cmd.direction,
+                    ep: cmd.ep,
+                    status: 0,
+                    length: 0,
+                    start_frame: 0,
+                    num_packets: 0,
+                    error_count: 0,
+                    setup: [0u8; 8],
+                    data: Some(vec![]),
+                    iso_packets: vec![]
+                }).write(&mut server).unwrap();
+            }
+        });
+
+        let latencies = Replay::new().window(3).run(recording.as_slice(), &mut client).unwrap();
+        let seqnums: Vec<u32> = latencies.iter().map(|l| l.seqnum).collect();
+        assert_eq!(seqnums, vec![3, 2, 1]);
+        srv.join().unwrap();
+    }
+}
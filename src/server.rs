@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bufstream::BufStream;
+
+use crate::protocol::{Packet, PacketError, PacketResult, DeviceDescriptor, RepDevList, RepImport,
+                      CmdSubmit, RetSubmit, RetUnlink, Direction, UsbIpStatus};
+use crate::stream::PacketStream;
+use crate::spans;
+use crate::client::{send, set_timeout, log_packet};
+
+// Shared by all connections, each of which is handled on its own thread
+pub trait DeviceProvider: Send + Sync {
+    fn list(&self) -> Vec<DeviceDescriptor>;
+    fn import(&self, busid: &str) -> Option<RepImport>;
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit;
+
+    // Returning None leaves the URB outstanding without a reply, e.g. an
+    // interrupt IN transfer with nothing to report, until it is unlinked.
+    fn submit(&self, cmd: &CmdSubmit) -> Option<RetSubmit> {
+        Some(self.handle_submit(cmd))
+    }
+
+    // Called when the client cancels an outstanding URB
+    fn unlink(&self, _seqnum: u32, _urb: &PendingUrb) {}
+
+    // Called once the connection that imported device has ended, however it
+    // ended, e.g. to hand the device back to the host
+    fn release(&self, _device: &RepImport) {}
+
+    // Gets the packets the server has no answer for, e.g. a CmdSubmit before
+    // any device was imported. Returning None (the default) closes the
+    // connection, a reply such as error_reply's keeps it going.
+    fn unhandled(&self, _pkt: &Packet, _imported: bool) -> Option<Packet> {
+        None
+    }
+}
+
+// What the server remembers about a submit it has not answered yet
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub struct PendingUrb {
+    pub devid: u32,
+    pub ep: u32,
+    pub direction: Direction,
+}
+
+// Outstanding submits by seqnum, so that a CmdUnlink can find its target
+#[derive(Debug,Default)]
+pub struct OutstandingUrbs {
+    urbs: HashMap<u32, PendingUrb>,
+}
+
+impl OutstandingUrbs {
+    pub fn new() -> OutstandingUrbs {
+        OutstandingUrbs::default()
+    }
+
+    pub fn insert(&mut self, cmd: &CmdSubmit) {
+        self.urbs.insert(cmd.seqnum, PendingUrb { devid: cmd.devid, ep: cmd.ep, direction: cmd.direction });
+    }
+
+    pub fn get(&self, seqnum: u32) -> Option<&PendingUrb> {
+        self.urbs.get(&seqnum)
+    }
+
+    pub fn remove(&mut self, seqnum: u32) -> Option<PendingUrb> {
+        self.urbs.remove(&seqnum)
+    }
+
+    pub fn len(&self) -> usize {
+        self.urbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.urbs.is_empty()
+    }
+}
+
+const ECONNRESET: i32 = 104;
+
+// The failed answer to a request: URB commands get -errno as their status,
+// op requests the generic UsbIpStatus::Error. Replies have no answer.
+pub fn error_reply(pkt: &Packet, errno: i32) -> Option<Packet> {
+    match *pkt {
+        Packet::ReqDevList => Some(Packet::RepDevList(RepDevList {
+            status: UsbIpStatus::Error.as_u32(), num_devices: 0, devices: Vec::new()
+        })),
+        Packet::ReqImport(_) => Some(Packet::RepImport(RepImport::failed(UsbIpStatus::Error))),
+        Packet::CmdSubmit(ref cmd) => Some(Packet::RetSubmit(RetSubmit {
+            seqnum: cmd.seqnum,
+            devid: cmd.devid,
+            direction: cmd.direction,
+            ep: cmd.ep,
+            status: -errno as u32,
+            length: 0,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: cmd.setup,
+            data: match cmd.direction {
+                Direction::In => Some(Vec::new()),
+                Direction::Out => None,
+            },
+            iso_packets: Vec::new()
+        })),
+        Packet::CmdUnlink(ref cmd) => Some(Packet::RetUnlink(RetUnlink {
+            seqnum: cmd.seq, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep, status: -errno as u32
+        })),
+        _ => None,
+    }
+}
+
+pub struct UsbIpServer<P: DeviceProvider> {
+    provider: Arc<P>,
+    timeout: Option<Duration>,
+}
+
+impl<P: DeviceProvider> Clone for UsbIpServer<P> {
+    fn clone(&self) -> UsbIpServer<P> {
+        UsbIpServer { provider: Arc::clone(&self.provider), timeout: self.timeout }
+    }
+}
+
+impl<P: DeviceProvider> UsbIpServer<P> {
+    pub fn new(provider: P) -> UsbIpServer<P> {
+        UsbIpServer { provider: Arc::new(provider), timeout: None }
+    }
+
+    // Drops TCP clients that stay silent (or stop reading) for longer than
+    // timeout, instead of blocking on them forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> PacketResult<()> where P: 'static {
+        self.serve_listener(TcpListener::bind(addr)?)
+    }
+
+    // Accepts connections until the listener fails, handling each client on a
+    // thread of its own so that one slow client does not hold up the rest.
+    pub fn serve_listener(&self, listener: TcpListener) -> PacketResult<()> where P: 'static {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle_tcp(stream) {
+                    warn!("Connection closed with error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub fn handle_tcp(&self, stream: TcpStream) -> PacketResult<()> {
+        info!("Client connected from {:?}", stream.peer_addr());
+        set_timeout(&stream, self.timeout)?;
+        self.handle(BufStream::new(stream))
+    }
+
+    // Runs the op phase (devlist / import) until a device is imported, then
+    // switches to exchanging URBs until the client disconnects. The imported
+    // device is released when the connection ends, also with an error.
+    pub fn handle<S: Read + Write>(&self, stream: S) -> PacketResult<()> {
+        let mut device = None;
+        let res = self.exchange(stream, &mut device);
+        if let Some(device) = device {
+            self.provider.release(&device);
+        }
+        res
+    }
+
+    fn exchange<S: Read + Write>(&self, stream: S, device: &mut Option<RepImport>) -> PacketResult<()> {
+        let mut imported = false;
+        let mut outstanding = OutstandingUrbs::new();
+        let mut packets = PacketStream::new(stream);
+        while let Some(pkt) = packets.next() {
+            let pkt = pkt?;
+            log_packet("Received", &pkt);
+            let _span = spans::handshake(&pkt);
+            let reply = match (imported, pkt) {
+                (false, Packet::ReqDevList) => {
+                    let devices = self.provider.list();
+                    Packet::RepDevList(RepDevList {
+                        status: UsbIpStatus::Ok.as_u32(),
+                        num_devices: devices.len() as u32,
+                        devices
+                    })
+                },
+                (false, Packet::ReqImport(req)) => match self.provider.import(&req.busid) {
+                    Some(ri) => {
+                        imported = ri.status().is_ok();
+                        if imported {
+                            *device = Some(ri.clone());
+                        }
+                        Packet::RepImport(ri)
+                    },
+                    None => Packet::RepImport(RepImport::failed(UsbIpStatus::NoDevice)),
+                },
+                (true, Packet::CmdSubmit(cmd)) => match self.provider.submit(&cmd) {
+                    Some(ret) => Packet::RetSubmit(ret),
+                    None => {
+                        outstanding.insert(&cmd);
+                        continue;
+                    },
+                },
+                // As the Linux stub answers: -ECONNRESET if the URB was cancelled
+                // (and gets no RetSubmit), 0 if it had already completed
+                (true, Packet::CmdUnlink(cmd)) => {
+                    let status = match outstanding.remove(cmd.seqnum) {
+                        Some(urb) => {
+                            self.provider.unlink(cmd.seqnum, &urb);
+                            -ECONNRESET as u32
+                        },
+                        None => 0,
+                    };
+                    Packet::RetUnlink(RetUnlink {
+                        seqnum: cmd.seq, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep, status
+                    })
+                },
+                (_, p) => match self.provider.unhandled(&p, imported) {
+                    Some(reply) => reply,
+                    None => return Err(PacketError::PacketError(
+                        format!("Unexpected {:?} packet (device imported: {})", p.packet_type(), imported))),
+                },
+            };
+            send(packets.get_mut(), &reply)?;
+        }
+        debug!("Client disconnected");
+        Ok(())
+    }
+}
+
+// The loop of the testsrv binary, serving provider until the listener fails.
+// A test binds the listener to port 0 and connects to its local_addr.
+pub fn run_test_server<P: DeviceProvider + 'static>(listener: TcpListener, provider: P) -> PacketResult<()> {
+    info!("Listening on {:?}", listener.local_addr());
+    UsbIpServer::new(provider).serve_listener(listener)
+}
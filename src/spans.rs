@@ -0,0 +1,75 @@
+// tracing spans around reading and writing packets and the op phase steps of
+// the client and server, with the packet type, seqnum and devid as fields.
+// Without the tracing feature Span is empty and all of this compiles away.
+
+#[cfg(feature = "tracing")]
+pub(crate) use self::enabled::*;
+#[cfg(not(feature = "tracing"))]
+pub(crate) use self::disabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use tracing::field::{self, Empty};
+
+    use crate::protocol::Packet;
+
+    pub(crate) type Span = tracing::span::EnteredSpan;
+
+    // The fields are only known once the packet has been decoded, see record
+    pub(crate) fn read() -> Span {
+        tracing::debug_span!("read", packet_type = Empty, seqnum = Empty, devid = Empty).entered()
+    }
+
+    pub(crate) fn write(pkt: &Packet) -> Span {
+        let span = tracing::debug_span!("write", packet_type = Empty, seqnum = Empty, devid = Empty).entered();
+        record(&span, pkt);
+        span
+    }
+
+    // Devlist and import requests, for everything else the span is disabled
+    #[cfg(feature = "std")]
+    pub(crate) fn handshake(pkt: &Packet) -> Span {
+        let step = match *pkt {
+            Packet::ReqDevList => "devlist",
+            Packet::ReqImport(_) => "import",
+            _ => return tracing::Span::none().entered(),
+        };
+        tracing::debug_span!("handshake", step).entered()
+    }
+
+    pub(crate) fn record(span: &Span, pkt: &Packet) {
+        span.record("packet_type", field::debug(pkt.packet_type()));
+        if let Some(seqnum) = pkt.seqnum() {
+            span.record("seqnum", seqnum);
+        }
+        if let Some(devid) = pkt.devid() {
+            span.record("devid", devid);
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use crate::protocol::Packet;
+
+    pub(crate) struct Span;
+
+    #[inline(always)]
+    pub(crate) fn read() -> Span {
+        Span
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(_pkt: &Packet) -> Span {
+        Span
+    }
+
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub(crate) fn handshake(_pkt: &Packet) -> Span {
+        Span
+    }
+
+    #[inline(always)]
+    pub(crate) fn record(_span: &Span, _pkt: &Packet) {}
+}
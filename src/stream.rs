@@ -0,0 +1,87 @@
+use crate::protocol::{Packet, PacketError, PacketResult, ReadLimits};
+use crate::util::ByteReader;
+
+// Decodes packets back to back until the peer goes away. A close between two
+// packets ends the iteration with None, anything else (including a close in
+// the middle of a packet) is yielded as an error, after which the stream is
+// exhausted since its position within the protocol is lost. The exception is
+// a Timeout before the start of a packet, after which reading can go on.
+pub struct PacketStream<R> {
+    src: R,
+    limits: ReadLimits,
+    done: bool,
+}
+
+impl<R: ByteReader> PacketStream<R> {
+    pub fn new(src: R) -> PacketStream<R> {
+        PacketStream::with_limits(src, ReadLimits::default())
+    }
+
+    pub fn with_limits(src: R, limits: ReadLimits) -> PacketStream<R> {
+        PacketStream { src, limits, done: false }
+    }
+
+    // Gives access to the underlying connection, e.g. to write replies
+    // between two calls to next.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.src
+    }
+
+    pub fn into_inner(self) -> R {
+        self.src
+    }
+}
+
+impl<R: ByteReader> Iterator for PacketStream<R> {
+    type Item = PacketResult<Packet>;
+
+    fn next(&mut self) -> Option<PacketResult<Packet>> {
+        if self.done {
+            return None;
+        }
+        match Packet::read_with_limits(&mut self.src, &self.limits) {
+            Ok(p) => Some(Ok(p)),
+            Err(PacketError::ConnectionClosed) => {
+                self.done = true;
+                None
+            },
+            Err(PacketError::Timeout) => Some(Err(PacketError::Timeout)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{Packet, PacketError};
+    use crate::stream::PacketStream;
+
+    #[test]
+    fn test_two_packets_then_eof() {
+        let mut buf = Vec::new();
+        Packet::ReqDevList.write(&mut buf).unwrap();
+        Packet::ReqDevList.write(&mut buf).unwrap();
+        let mut packets = PacketStream::new(buf.as_slice());
+        assert_eq!(packets.next().unwrap().unwrap(), Packet::ReqDevList);
+        assert_eq!(packets.next().unwrap().unwrap(), Packet::ReqDevList);
+        assert!(packets.next().is_none());
+    }
+
+    #[test]
+    fn test_truncated_packet() {
+        let mut buf = Vec::new();
+        Packet::ReqDevList.write(&mut buf).unwrap();
+        Packet::ReqDevList.write(&mut buf).unwrap();
+        buf.truncate(12);
+        let mut packets = PacketStream::new(buf.as_slice());
+        assert_eq!(packets.next().unwrap().unwrap(), Packet::ReqDevList);
+        match packets.next() {
+            Some(Err(PacketError::TruncatedField("ReqDevList", "status"))) => (),
+            x => panic!("Expected truncated status, got {:?}", x)
+        }
+        assert!(packets.next().is_none());
+    }
+}
@@ -0,0 +1,61 @@
+// Fixtures for tests, of this crate and of code using it. Outside of the
+// crate's own unit tests this takes the test-util feature.
+
+use crate::protocol::{CmdSubmit, DeviceDescriptor, Direction, RepImport, RetSubmit, UsbIpStatus};
+use crate::server::DeviceProvider;
+
+/// What an IN transfer reads, cut to its buffer_length: the start of a device
+/// descriptor
+pub const STUB_DATA: [u8; 8] = [0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40];
+
+// A single device, 1-1, that completes every submit. IN transfers get
+// STUB_DATA, OUT transfers report their whole buffer as written.
+pub struct StubDevice;
+
+impl StubDevice {
+    pub fn descriptor() -> DeviceDescriptor {
+        DeviceDescriptor {
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 2,
+            speed: 2,
+            id_vendor: 0x0403,
+            id_product: 0x6001,
+            bcd_device: 0x0600,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 0,
+            interfaces: vec![]
+        }
+    }
+}
+
+impl DeviceProvider for StubDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![StubDevice::descriptor()]
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        if busid == "1-1" {
+            Some(RepImport::from_device(&StubDevice::descriptor(), UsbIpStatus::Ok.as_u32()))
+        } else {
+            None
+        }
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        let (length, data) = match cmd.direction {
+            Direction::In => {
+                let data = STUB_DATA.iter().cloned().take(cmd.buffer_length as usize).collect::<Vec<_>>();
+                (data.len() as u32, Some(data))
+            },
+            Direction::Out => (cmd.buffer_length, None),
+        };
+        RetSubmit { seqnum: cmd.seqnum, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep,
+                    length, setup: cmd.setup, data, ..Default::default() }
+    }
+}
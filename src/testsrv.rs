@@ -1,55 +1,44 @@
 extern crate vusbip;
-extern crate bufstream;
-use bufstream::BufStream;
-use vusbip::protocol::{Packet, PacketError, DeviceDescriptor, InterfaceDescriptor, RepDevList, RepImport, ReqImport};
-use std::io::{Read,Write};
-use std::net::{TcpListener, TcpStream};
-use std::io;
+use std::env;
+use std::net::TcpListener;
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:3240").unwrap();
-    println!("USBIP Testserver");
-    for s in listener.incoming() {
-        let mut stream = s.unwrap();
-        handle_stream(stream);
+use vusbip::protocol::{Packet, DeviceDescriptor, InterfaceDescriptor, RepImport, CmdSubmit, RetSubmit, Direction};
+use vusbip::server::{DeviceProvider, error_reply, run_test_server};
+
+const ENOSYS: i32 = 38;
+
+// Used unless the address is given as the first argument or in
+// USBIP_TESTSRV_ADDR, e.g. 0.0.0.0:3240 in a container
+const DEFAULT_ADDR: &str = "127.0.0.1:3240";
+
+struct TestDevice {
+    device: DeviceDescriptor,
+}
+
+impl DeviceProvider for TestDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![self.device.clone()]
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        println!("Import of {}", busid);
+        Some(RepImport::from_device(&self.device, 0))
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        println!("Received: {:?}", cmd);
+        ret_submit(cmd)
+    }
+
+    fn unhandled(&self, pkt: &Packet, imported: bool) -> Option<Packet> {
+        println!("Unhandled (imported: {}): {:?}", imported, pkt);
+        error_reply(pkt, ENOSYS)
     }
 }
 
-fn handle_stream(mut tcp_stream: TcpStream) {
-    let mut stream = BufStream::new(tcp_stream);
-    let dl = Packet::RepDevList(RepDevList {
-        status: 0,
-        num_devices: 1,
-        devices: vec![DeviceDescriptor {
-            path: "/foo/bar".to_string(),
-            busid: "3-2".to_string(),
-            busnum: 3,
-            devnum: 2,
-            speed: 2,
-            id_vendor: 0x0403,
-            id_product: 0x6001,
-            bcd_device: 0x0110,
-            device_class: 255,
-            device_subclass: 0,
-            device_protocol: 0,
-            configuration_value: 1,
-            num_configurations: 2,
-            num_interfaces: 2,
-            interfaces: vec![
-                InterfaceDescriptor {
-                    interface_class: 255,
-                    interface_subclass: 26,
-                    interface_protocol: 29
-                }, InterfaceDescriptor {
-                    interface_class: 255,
-                    interface_subclass: 85,
-                    interface_protocol: 2
-                }
-            ]
-        }]
-    });
-    let ri = Packet::RepImport(RepImport {
-        status: 0,
+fn main() {
+    println!("USBIP Testserver");
+    let device = DeviceDescriptor {
         path: "/foo/bar".to_string(),
         busid: "3-2".to_string(),
         busnum: 3,
@@ -62,19 +51,50 @@ fn handle_stream(mut tcp_stream: TcpStream) {
         device_subclass: 0,
         device_protocol: 0,
         configuration_value: 1,
-        num_configurations: 2,
+        // The interfaces below are all there is, there is no second
+        // configuration to switch to
+        num_configurations: 1,
         num_interfaces: 2,
-    });
-    println!("Client connected");
-    loop {
-        let pkt = Packet::read(&mut stream);
-        println!("Received: {:?}", pkt);
-        match pkt {
-            Ok(Packet::ReqDevList) => { dl.write(&mut stream).unwrap(); stream.flush().unwrap() },
-            Ok(Packet::ReqImport(_)) => { ri.write(&mut stream).unwrap(); stream.flush().unwrap() },
-            Ok(s) => println!("Unhandled packet received: {:?}", s),
-            Err(PacketError::PacketError(_)) => println!("Invalid packet received"),
-            Err(_) => {println!("Error, closing connection"); return},
-        }
+        interfaces: vec![
+            InterfaceDescriptor {
+                interface_class: 255,
+                interface_subclass: 26,
+                interface_protocol: 29
+            }, InterfaceDescriptor {
+                interface_class: 255,
+                interface_subclass: 85,
+                interface_protocol: 2
+            }
+        ]
+    };
+    device.validate().unwrap();
+    let addr = env::args().nth(1)
+        .or_else(|| env::var("USBIP_TESTSRV_ADDR").ok())
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let listener = TcpListener::bind(&addr).unwrap();
+    println!("Listening on {}", listener.local_addr().unwrap());
+    run_test_server(listener, TestDevice { device }).unwrap();
+}
+
+// Minimal successful reply: IN transfers return no data, OUT transfers report
+// the whole buffer as transferred.
+fn ret_submit(cmd: &CmdSubmit) -> RetSubmit {
+    let (length, data) = match cmd.direction {
+        Direction::In => (0, Some(Vec::new())),
+        Direction::Out => (cmd.buffer_length, None),
+    };
+    RetSubmit {
+        seqnum: cmd.seqnum,
+        devid: cmd.devid,
+        direction: cmd.direction,
+        ep: cmd.ep,
+        status: 0,
+        length,
+        start_frame: 0,
+        num_packets: 0,
+        error_count: 0,
+        setup: cmd.setup,
+        data,
+        iso_packets: Vec::new()
     }
 }
@@ -0,0 +1,215 @@
+#[cfg(feature = "std")]
+use std::io;
+use alloc::vec;
+use alloc::string::{String, ToString};
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use byteorder::ByteOrder;
+
+use crate::protocol::{PacketError, PacketResult};
+
+/// Source of bytes for the packet decoder.
+///
+/// With the `std` feature this is implemented for every `std::io::Read`,
+/// otherwise only for byte slices.
+pub trait ByteReader {
+    /// Reads up to `buf.len()` bytes, returning 0 at end of stream.
+    fn read(&mut self, buf: &mut [u8]) -> PacketResult<usize>;
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> PacketResult<()> {
+        while !buf.is_empty() {
+            let n = self.read(buf)?;
+            if n == 0 {
+                return Err(PacketError::Truncated);
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> PacketResult<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16<T: ByteOrder>(&mut self) -> PacketResult<u16> {
+        let mut buf = [0u8; 2];
+        self.read_exact(&mut buf)?;
+        Ok(T::read_u16(&buf))
+    }
+
+    fn read_u32<T: ByteOrder>(&mut self) -> PacketResult<u32> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(T::read_u32(&buf))
+    }
+}
+
+/// Sink for bytes produced by the packet encoder.
+///
+/// With the `std` feature this is implemented for every `std::io::Write`,
+/// otherwise only for `Vec<u8>`.
+pub trait ByteWriter {
+    fn write_all(&mut self, buf: &[u8]) -> PacketResult<()>;
+
+    fn write_u8(&mut self, val: u8) -> PacketResult<()> {
+        self.write_all(&[val])
+    }
+
+    fn write_u16<T: ByteOrder>(&mut self, val: u16) -> PacketResult<()> {
+        let mut buf = [0u8; 2];
+        T::write_u16(&mut buf, val);
+        self.write_all(&buf)
+    }
+
+    fn write_u32<T: ByteOrder>(&mut self, val: u32) -> PacketResult<()> {
+        let mut buf = [0u8; 4];
+        T::write_u32(&mut buf, val);
+        self.write_all(&buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: io::Read + ?Sized> ByteReader for R {
+    fn read(&mut self, buf: &mut [u8]) -> PacketResult<usize> {
+        loop {
+            match io::Read::read(self, buf) {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> PacketResult<()> {
+        io::Read::read_exact(self, buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write + ?Sized> ByteWriter for W {
+    fn write_all(&mut self, buf: &[u8]) -> PacketResult<()> {
+        io::Write::write_all(self, buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteReader for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> PacketResult<usize> {
+        let n = core::cmp::min(buf.len(), self.len());
+        buf[..n].copy_from_slice(&self[..n]);
+        *self = &self[n..];
+        Ok(n)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteWriter for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> PacketResult<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Reads a fixed-size, NUL-padded UTF-8 string field of `len` bytes.
+pub fn read_fix_string<R: ByteReader + ?Sized>(src: &mut R, len: usize) -> PacketResult<String> {
+    let mut buf = vec![0u8; len];
+    src.read_exact(&mut buf)?;
+    let len = match buf.iter().position(|&x| x == 0) {
+        Some(i) => i,
+        None => buf.len()
+    };
+    buf.truncate(len);
+    let s = String::from_utf8(buf)?;
+    Ok(s)
+}
+
+/// Like `read_fix_string`, but rejects anything that is not plain ASCII.
+pub fn read_fix_ascii<R: ByteReader + ?Sized>(src: &mut R, len: usize) -> PacketResult<String> {
+    let s = read_fix_string(src, len)?;
+    if !s.is_ascii() {
+        return Err(PacketError::PacketError("Read string is not ASCII".to_string()));
+    }
+    Ok(s)
+}
+
+/// Writes `s` into a fixed-size, NUL-padded UTF-8 string field of `size` bytes.
+///
+/// At least one trailing NUL is always written, so `s` may be at most
+/// `size - 1` bytes long.
+///
+/// ```
+/// use vusbip::util::write_fix_ascii;
+///
+/// let mut buf = Vec::new();
+/// write_fix_ascii(&mut buf, "3-2", 32).unwrap();
+/// assert_eq!(buf.len(), 32);
+/// assert_eq!(&buf[..4], b"3-2\0");
+/// ```
+pub fn write_fix_string<W: ByteWriter + ?Sized>(dst: &mut W, s: &str, size: usize) -> PacketResult<()> {
+    if s.len() > (size-1) { // We require one 0-byte at end
+        return Err(PacketError::PacketError("Write string is longer than buffer".to_string()));
+    }
+    dst.write_all(s.as_bytes())?;
+    if s.len() < size {
+        let padding = vec![0u8; size-s.len()];
+        dst.write_all(&padding)?;
+    }
+    Ok(())
+}
+
+/// Like `write_fix_string`, but rejects anything that is not plain ASCII.
+pub fn write_fix_ascii<W: ByteWriter + ?Sized>(dst: &mut W, s: &str, size: usize) -> PacketResult<()> {
+    if !s.is_ascii() {
+        return Err(PacketError::PacketError("Write string is not ASCII".to_string()));
+    }
+    write_fix_string(dst, s, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::PacketError;
+    use crate::util::{read_fix_string, write_fix_string, read_fix_ascii, write_fix_ascii};
+
+    #[test]
+    fn test_read_fix_string() {
+        let data1 : Vec<u8>= vec![b'a', b'b', b'c', 0, 0];
+        assert_eq!(read_fix_string(&mut data1.as_slice(), 5).unwrap(), "abc");
+        assert_eq!(read_fix_string(&mut data1.as_slice(), 3).unwrap(), "abc");
+    }
+
+    #[test]
+    fn test_write_fix_string() {
+        let s = "abc";
+        let mut buf = Vec::with_capacity(5);
+        write_fix_string(&mut buf, s, 5).unwrap();
+        assert_eq!(buf, [97, 98, 99, 0, 0])
+    }
+
+    #[test]
+    fn test_utf8_path_roundtrip() {
+        let path = "/sys/devices/pci0000:00/gerät-1";
+        let mut buf = Vec::new();
+        write_fix_string(&mut buf, path, 256).unwrap();
+        assert_eq!(buf.len(), 256);
+        assert_eq!(read_fix_string(&mut buf.as_slice(), 256).unwrap(), path);
+        match read_fix_ascii(&mut buf.as_slice(), 256) {
+            Err(PacketError::PacketError(_)) => (),
+            x => panic!("Expected ASCII error, got {:?}", x)
+        }
+        assert!(write_fix_ascii(&mut Vec::new(), path, 256).is_err());
+    }
+
+    #[test]
+    fn test_invalid_utf8_rejected() {
+        let data: Vec<u8> = vec![b'a', 0xc3, 0x28, 0, 0];
+        match read_fix_string(&mut data.as_slice(), 5) {
+            Err(PacketError::Utf8Error(_)) => (),
+            x => panic!("Expected Utf8Error, got {:?}", x)
+        }
+    }
+}
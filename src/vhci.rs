@@ -0,0 +1,56 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use crate::protocol::{devid, PacketResult, RepImport};
+
+pub const VHCI_ATTACH_PATH: &str = "/sys/devices/platform/vhci_hcd.0/attach";
+
+// The record vhci_hcd parses from its attach file: "<port> <sockfd> <devid> <speed>".
+// The port has to be a free one on the root hub matching the device speed,
+// see the status files next to the attach file.
+pub fn attach_string(port: u32, sockfd: RawFd, info: &RepImport) -> String {
+    format!("{} {} {} {}", port, sockfd, devid(info.busnum as u16, info.devnum as u16), info.speed)
+}
+
+// Hands the socket an import was done on over to the kernel. The fd has to
+// stay open until the write returns, afterwards the kernel holds its own
+// reference and the connection must not be used from userspace any more.
+pub fn attach(port: u32, sockfd: RawFd, info: &RepImport) -> PacketResult<()> {
+    attach_at(VHCI_ATTACH_PATH, port, sockfd, info)
+}
+
+pub fn attach_at<P: AsRef<Path>>(path: P, port: u32, sockfd: RawFd, info: &RepImport) -> PacketResult<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(attach_string(port, sockfd, info).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::RepImport;
+    use crate::vhci::attach_string;
+
+    #[test]
+    fn test_attach_string() {
+        let info = RepImport {
+            status: 0,
+            path: "/sys/devices/pci0000:00/0000:00:1d.0/usb3/3-2".to_string(),
+            busid: "3-2".to_string(),
+            busnum: 3,
+            devnum: 2,
+            speed: 3,
+            id_vendor: 0x0403,
+            id_product: 0x6001,
+            bcd_device: 0x0110,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 1,
+        };
+        assert_eq!(attach_string(4, 7, &info), "4 7 196610 3");
+    }
+}
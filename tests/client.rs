@@ -0,0 +1,394 @@
+extern crate vusbip;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use vusbip::client::{UsbIpClient, SeqnumAllocator};
+use vusbip::memory;
+use vusbip::protocol::{Packet, PacketError, PacketHeader, ImportError, RepDevList, RepImport, CmdSubmit, CmdSubmitBuilder,
+                       RetSubmit, Direction, TransferFlags, UsbIpStatus};
+
+struct TestServer(Child);
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// testsrv binds a free port and reports it on stdout, so tests running in
+// parallel (or a usbipd on 3240) do not get in each other's way. The rest of
+// its output is drained on a thread of its own.
+fn start_testsrv() -> (TestServer, UsbIpClient) {
+    let mut srv = TestServer(Command::new(env!("CARGO_BIN_EXE_testsrv")).arg("127.0.0.1:0")
+                             .stdout(Stdio::piped()).spawn().unwrap());
+    let mut lines = BufReader::new(srv.0.stdout.take().unwrap()).lines();
+    let addr = lines.by_ref()
+        .map(|line| line.unwrap())
+        .find_map(|line| line.strip_prefix("Listening on ").map(str::to_string))
+        .expect("testsrv did not come up");
+    thread::spawn(move || lines.for_each(drop));
+    let client = UsbIpClient::connect(&addr).unwrap();
+    (srv, client)
+}
+
+#[test]
+fn test_client_against_testsrv() {
+    let (_srv, mut client) = start_testsrv();
+
+    let devices = client.list_devices().unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].busid, "3-2");
+    assert_eq!(devices[0].id_vendor, 0x0403);
+    assert_eq!(devices[0].interfaces.len(), 2);
+
+    let mut dev = client.import("3-2").unwrap();
+    assert!(dev.info().status().is_ok());
+    assert_eq!(dev.info().busid, "3-2");
+    assert_eq!(dev.info().id_product, 0x6001);
+
+    dev.submit(CmdSubmitBuilder::new(Direction::Out, 2).seqnum(7).data(vec![1, 2, 3]).build()).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetSubmit(ret) => {
+            assert_eq!(ret.seqnum, 7);
+            assert!(ret.status().is_ok());
+            assert_eq!(ret.length, 3);
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+}
+
+// After a successful import the connection only carries URBs, so an op reply
+// arriving on it is a protocol error rather than something to decode as usual.
+#[test]
+fn test_imported_device_rejects_op_packets() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        match Packet::read(&mut stream).unwrap() {
+            Packet::ReqImport(req) => assert_eq!(req.busid, "1-1"),
+            p => panic!("Unexpected {:?}", p),
+        }
+        Packet::RepImport(RepImport {
+            status: 0,
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 1,
+            speed: 3,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 1,
+        }).write(&mut stream).unwrap();
+        let cmd = match Packet::read(&mut stream).unwrap() {
+            Packet::CmdSubmit(cmd) => cmd,
+            p => panic!("Unexpected {:?}", p),
+        };
+        Packet::RetSubmit(RetSubmit {
+            seqnum: cmd.seqnum,
+            devid: cmd.devid,
+            direction: cmd.direction,
+            ep: cmd.ep,
+            status: 0,
+            length: 0,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: Some(vec![]),
+            iso_packets: vec![]
+        }).write(&mut stream).unwrap();
+        Packet::RepDevList(RepDevList { status: 0, num_devices: 0, devices: vec![] })
+            .write(&mut stream).unwrap();
+    });
+
+    let client = UsbIpClient::connect(addr).unwrap();
+    let mut dev = client.import("1-1").unwrap();
+    dev.submit(CmdSubmit {
+        seqnum: 1,
+        devid: 0x00010001,
+        direction: Direction::In,
+        ep: 0,
+        transfer_flags: TransferFlags::empty(),
+        buffer_length: 0,
+        start_frame: 0,
+        num_packets: 0,
+        interval: 0,
+        setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+        data: None,
+        iso_packets: vec![]
+    }).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetSubmit(ret) => assert_eq!(ret.seqnum, 1),
+        p => panic!("Unexpected {:?}", p),
+    }
+    assert!(dev.read_reply().is_err());
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_import_failure_status() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        match Packet::read(&mut stream).unwrap() {
+            Packet::ReqImport(req) => assert_eq!(req.busid, "1-1"),
+            p => panic!("Unexpected {:?}", p),
+        }
+        Packet::RepImport(RepImport::failed(UsbIpStatus::NotAvailable)).write(&mut stream).unwrap();
+    });
+
+    let client = UsbIpClient::connect(addr).unwrap();
+    match client.import("1-1") {
+        Err(e @ PacketError::ImportFailed(ImportError::NotAvailable)) => {
+            assert_eq!(e.to_string(), "Import failed: device not available");
+        },
+        Err(e) => panic!("Expected NotAvailable, got {:?}", e),
+        Ok(_) => panic!("Import succeeded"),
+    }
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_import_invalid_busid() {
+    let (client_end, mut server_end) = memory::pair();
+    match UsbIpClient::new(client_end).import("3-2 ") {
+        Err(PacketError::PacketError(ref msg)) => assert_eq!(msg, "Invalid busid \"3-2 \", expected e.g. 3-2 or 1-1.4.2"),
+        Err(e) => panic!("Expected busid error, got {:?}", e),
+        Ok(_) => panic!("Import succeeded"),
+    }
+    // Nothing reached the server before the client went away
+    match Packet::read(&mut server_end) {
+        Err(PacketError::ConnectionClosed) => (),
+        x => panic!("Expected ConnectionClosed, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        match Packet::read(&mut stream) {
+            Err(PacketError::ConnectionClosed) => (),
+            x => panic!("Expected ConnectionClosed, got {:?}", x),
+        }
+
+        // An imported device closed with its submit still unanswered
+        let (mut stream, _) = listener.accept().unwrap();
+        match Packet::read(&mut stream).unwrap() {
+            Packet::ReqImport(req) => assert_eq!(req.busid, "1-1"),
+            p => panic!("Unexpected {:?}", p),
+        }
+        Packet::RepImport(RepImport {
+            status: 0,
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 1,
+            speed: 3,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 0
+        }).write(&mut stream).unwrap();
+        match Packet::read(&mut stream).unwrap() {
+            Packet::CmdSubmit(cmd) => assert_eq!(cmd.seqnum, 1),
+            p => panic!("Unexpected {:?}", p),
+        }
+        match Packet::read(&mut stream) {
+            Err(PacketError::ConnectionClosed) => (),
+            x => panic!("Expected ConnectionClosed, got {:?}", x),
+        }
+    });
+
+    UsbIpClient::connect(addr).unwrap().close().unwrap();
+
+    let mut dev = UsbIpClient::connect(addr).unwrap().import("1-1").unwrap();
+    dev.queue(CmdSubmitBuilder::new(Direction::In, 1).buffer_length(8).build()).unwrap();
+    dev.close().unwrap();
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_list_no_devices() {
+    let (client_end, mut server_end) = memory::pair();
+    let srv = thread::spawn(move || {
+        assert_eq!(Packet::read(&mut server_end).unwrap(), Packet::ReqDevList);
+        Packet::RepDevList(RepDevList { status: 0, num_devices: 0, devices: vec![] })
+            .write(&mut server_end).unwrap();
+    });
+
+    let mut client = UsbIpClient::new(client_end);
+    assert_eq!(client.list_devices().unwrap(), vec![]);
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_list_unsupported_version() {
+    let (client_end, mut server_end) = memory::pair();
+    let srv = thread::spawn(move || {
+        assert_eq!(Packet::read(&mut server_end).unwrap(), Packet::ReqDevList);
+        // A devlist reply as a server on protocol version 2.00 might send it
+        let mut reply = Packet::RepDevList(RepDevList { status: 0, num_devices: 0, devices: vec![] })
+            .write_to_vec().unwrap();
+        reply[..2].copy_from_slice(&[0x02, 0x00]);
+        server_end.write_all(&reply).unwrap();
+    });
+
+    let mut client = UsbIpClient::new(client_end);
+    match client.list_devices() {
+        Err(e @ PacketError::UnsupportedVersion(PacketHeader { version: 0x0200, command: 0x0005 })) => {
+            assert!(e.to_string().contains("unsupported version 0x0200"), "{}", e);
+        },
+        x => panic!("Expected UnsupportedVersion, got {:?}", x),
+    }
+    srv.join().unwrap();
+}
+
+// The client sits on a BufStream, a submit that stayed in its buffer would
+// leave both sides waiting for each other.
+#[test]
+fn test_submit_reaches_peer_without_flush() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        match Packet::read(&mut stream).unwrap() {
+            Packet::ReqImport(req) => assert_eq!(req.busid, "1-1"),
+            p => panic!("Unexpected {:?}", p),
+        }
+        let mut reply = RepImport::failed(UsbIpStatus::Ok);
+        reply.busid = "1-1".to_string();
+        Packet::RepImport(reply).write(&mut stream).unwrap();
+        let mut seqnums = Vec::new();
+        for _ in 0..3 {
+            match Packet::read(&mut stream).unwrap() {
+                Packet::CmdSubmit(cmd) => seqnums.push(cmd.seqnum),
+                p => panic!("Unexpected {:?}", p),
+            }
+        }
+        seqnums
+    });
+
+    let client = UsbIpClient::connect(addr).unwrap();
+    let mut dev = client.import("1-1").unwrap();
+    dev.submit(CmdSubmitBuilder::new(Direction::Out, 1).seqnum(1).data(vec![1]).build()).unwrap();
+    // Batched submits go out with the explicit flush
+    dev.queue(CmdSubmitBuilder::new(Direction::Out, 1).seqnum(2).data(vec![2]).build()).unwrap();
+    dev.queue(CmdSubmitBuilder::new(Direction::Out, 1).seqnum(3).data(vec![3]).build()).unwrap();
+    dev.flush().unwrap();
+    assert_eq!(srv.join().unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_seqnum_allocator() {
+    let seqnums = SeqnumAllocator::new();
+    let first: Vec<u32> = (0..1000).map(|_| seqnums.next()).collect();
+    assert_eq!(first, (1..=1000).collect::<Vec<u32>>());
+
+    // 0 is skipped on wrapping around
+    let seqnums = SeqnumAllocator::starting_at(u32::MAX - 1);
+    let wrapped: Vec<u32> = (0..4).map(|_| seqnums.next()).collect();
+    assert_eq!(wrapped, vec![u32::MAX - 1, u32::MAX, 1, 2]);
+    assert_eq!(SeqnumAllocator::starting_at(0).next(), 1);
+}
+
+// The Linux stub sends every RET_SUBMIT with devid, direction and ep 0, so
+// the payload of an IN reply follows a header that says OUT
+fn kernel_style_reply(seqnum: u32, length: u32, data: Option<Vec<u8>>) -> Vec<u8> {
+    let mut buf = Packet::RetSubmit(RetSubmit {
+        seqnum, length, direction: if data.is_some() { Direction::In } else { Direction::Out }, data,
+        ..Default::default()
+    }).write_to_vec().unwrap();
+    buf[12..16].copy_from_slice(&[0, 0, 0, 0]);
+    buf
+}
+
+#[test]
+fn test_kernel_style_replies() {
+    let (client_end, mut server_end) = memory::pair();
+    let srv = thread::spawn(move || {
+        match Packet::read(&mut server_end).unwrap() {
+            Packet::ReqImport(_) => (),
+            p => panic!("Unexpected {:?}", p),
+        }
+        let mut reply = RepImport::failed(UsbIpStatus::Ok);
+        reply.busid = "1-1".to_string();
+        Packet::RepImport(reply).write(&mut server_end).unwrap();
+        for _ in 0..2 {
+            Packet::read(&mut server_end).unwrap();
+        }
+        server_end.write_all(&kernel_style_reply(1, 2, Some(vec![0x12, 0x01]))).unwrap();
+        server_end.write_all(&kernel_style_reply(2, 3, None)).unwrap();
+    });
+
+    let mut dev = UsbIpClient::new(client_end).import("1-1").unwrap();
+    dev.submit(CmdSubmitBuilder::new(Direction::In, 0).buffer_length(18).build()).unwrap();
+    dev.submit(CmdSubmitBuilder::new(Direction::Out, 1).data(vec![1, 2, 3]).build()).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetSubmit(ret) => {
+            assert_eq!(ret.seqnum, 1);
+            assert_eq!(ret.direction, Direction::In);
+            assert_eq!(ret.data, Some(vec![0x12, 0x01]));
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    match dev.read_reply().unwrap() {
+        Packet::RetSubmit(ret) => {
+            assert_eq!(ret.seqnum, 2);
+            assert_eq!(ret.length, 3);
+            assert_eq!(ret.data, None);
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_submit_assigns_seqnums() {
+    let (client_end, mut server_end) = memory::pair();
+    let srv = thread::spawn(move || {
+        match Packet::read(&mut server_end).unwrap() {
+            Packet::ReqImport(_) => (),
+            p => panic!("Unexpected {:?}", p),
+        }
+        let mut reply = RepImport::failed(UsbIpStatus::Ok);
+        reply.busid = "1-1".to_string();
+        Packet::RepImport(reply).write(&mut server_end).unwrap();
+        let mut seqnums = Vec::new();
+        for _ in 0..3 {
+            match Packet::read(&mut server_end).unwrap() {
+                Packet::CmdSubmit(cmd) => seqnums.push(cmd.seqnum),
+                p => panic!("Unexpected {:?}", p),
+            }
+        }
+        seqnums
+    });
+
+    let mut dev = UsbIpClient::new(client_end).import("1-1").unwrap();
+    let submit = || CmdSubmitBuilder::new(Direction::In, 1).buffer_length(8).build();
+    assert_eq!(dev.submit(submit()).unwrap(), 1);
+    assert_eq!(dev.submit(CmdSubmitBuilder::new(Direction::In, 1).seqnum(100).buffer_length(8).build()).unwrap(), 100);
+    assert_eq!(dev.submit(submit()).unwrap(), 2);
+    assert_eq!(srv.join().unwrap(), vec![1, 100, 2]);
+}
@@ -0,0 +1,22 @@
+#![no_std]
+
+extern crate alloc;
+extern crate vusbip;
+
+use alloc::vec::Vec;
+
+use vusbip::protocol::{Packet, PacketResult, ReqImport};
+
+pub fn encode(pkt: &Packet) -> PacketResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    pkt.write(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn decode(mut buf: &[u8]) -> PacketResult<Packet> {
+    Packet::read(&mut buf)
+}
+
+pub fn import_request(busid: &str) -> PacketResult<Vec<u8>> {
+    encode(&Packet::ReqImport(ReqImport { busid: busid.into() }))
+}
@@ -0,0 +1,330 @@
+extern crate vusbip;
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use vusbip::client::UsbIpClient;
+use vusbip::memory;
+use vusbip::protocol::{Packet, PacketError, PacketErrorKind, ImportError, DeviceDescriptor, RepImport, CmdSubmit, CmdSubmitBuilder,
+                       RetSubmit, CmdUnlink, ReqImport, Direction};
+use vusbip::server::{DeviceProvider, UsbIpServer, PendingUrb, error_reply, run_test_server};
+
+struct EmptyDevice;
+
+impl DeviceProvider for EmptyDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![DeviceDescriptor {
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 1,
+            speed: 3,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 0,
+            interfaces: vec![]
+        }]
+    }
+
+    fn import(&self, _busid: &str) -> Option<RepImport> {
+        None
+    }
+
+    fn handle_submit(&self, _cmd: &CmdSubmit) -> RetSubmit {
+        unreachable!()
+    }
+}
+
+#[test]
+fn test_server_devlist_and_failed_import() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let server = UsbIpServer::new(EmptyDevice);
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_tcp(stream)
+    });
+
+    let mut client = UsbIpClient::connect(addr).unwrap();
+    let devices = client.list_devices().unwrap();
+    assert_eq!(devices, EmptyDevice.list());
+    match client.import("1-1") {
+        Err(PacketError::ImportFailed(ImportError::NotFound)) => (),
+        Err(e) => panic!("Expected NotFound, got {:?}", e),
+        Ok(_) => panic!("Import succeeded"),
+    }
+
+    srv.join().unwrap().unwrap();
+}
+
+// Exports the device listed by EmptyDevice and completes every URB
+struct LoopbackDevice;
+
+impl DeviceProvider for LoopbackDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        EmptyDevice.list()
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        let dev = self.list().into_iter().find(|d| d.busid == busid)?;
+        Some(RepImport::from_device(&dev, 0))
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        RetSubmit {
+            seqnum: cmd.seqnum,
+            devid: cmd.devid,
+            direction: cmd.direction,
+            ep: cmd.ep,
+            status: 0,
+            length: cmd.buffer_length,
+            start_frame: 0,
+            num_packets: 0,
+            error_count: 0,
+            setup: [0u8; 8],
+            data: None,
+            iso_packets: vec![]
+        }
+    }
+}
+
+#[test]
+fn test_server_in_memory() {
+    let (client_end, server_end) = memory::pair();
+    let srv = thread::spawn(move || UsbIpServer::new(LoopbackDevice).handle(server_end));
+
+    let mut client = UsbIpClient::new(client_end);
+    assert_eq!(client.list_devices().unwrap(), LoopbackDevice.list());
+    let mut dev = client.import("1-1").unwrap();
+    assert_eq!(dev.info().busid, "1-1");
+    assert_eq!(dev.info().id_vendor, 0x1d6b);
+    dev.submit(CmdSubmitBuilder::new(Direction::Out, 1).seqnum(3).data(vec![1, 2]).build()).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetSubmit(ret) => {
+            assert_eq!(ret.seqnum, 3);
+            assert_eq!(ret.length, 2);
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    drop(dev);
+
+    srv.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_run_test_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    thread::spawn(move || run_test_server(listener, LoopbackDevice));
+
+    let mut client = UsbIpClient::connect(addr).unwrap();
+    client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(client.list_devices().unwrap(), LoopbackDevice.list());
+    let dev = client.import("1-1").unwrap();
+    assert_eq!(dev.info().busid, "1-1");
+}
+
+#[test]
+fn test_server_drops_silent_client() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = thread::spawn(move || {
+        let mut server = UsbIpServer::new(EmptyDevice);
+        server.set_timeout(Some(Duration::from_millis(50)));
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_tcp(stream)
+    });
+
+    let _silent = TcpStream::connect(addr).unwrap();
+    assert_eq!(srv.join().unwrap().unwrap_err().kind(), PacketErrorKind::Timeout);
+}
+
+#[test]
+fn test_server_concurrent_clients() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || UsbIpServer::new(EmptyDevice).serve_listener(listener));
+
+    // The first client stays connected while the second one is served
+    let mut first = UsbIpClient::connect(addr).unwrap();
+    let mut second = UsbIpClient::connect(addr).unwrap();
+    first.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    second.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(second.list_devices().unwrap(), EmptyDevice.list());
+    assert_eq!(first.list_devices().unwrap(), EmptyDevice.list());
+}
+
+// Like LoopbackDevice, but interrupt IN transfers on endpoint 1 never complete
+struct PendingDevice {
+    unlinked: AtomicU32,
+}
+
+impl DeviceProvider for PendingDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        LoopbackDevice.list()
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        LoopbackDevice.import(busid)
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        LoopbackDevice.handle_submit(cmd)
+    }
+
+    fn submit(&self, cmd: &CmdSubmit) -> Option<RetSubmit> {
+        match (cmd.ep, cmd.direction) {
+            (1, Direction::In) => None,
+            _ => Some(self.handle_submit(cmd)),
+        }
+    }
+
+    fn unlink(&self, seqnum: u32, urb: &PendingUrb) {
+        assert_eq!(urb.ep, 1);
+        self.unlinked.store(seqnum, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_server_unlink() {
+    let (client_end, server_end) = memory::pair();
+    let srv = thread::spawn(move || {
+        let server = UsbIpServer::new(PendingDevice { unlinked: AtomicU32::new(0) });
+        server.handle(server_end).unwrap();
+        server.provider().unlinked.load(Ordering::SeqCst)
+    });
+
+    let mut dev = UsbIpClient::new(client_end).import("1-1").unwrap();
+    dev.submit(CmdSubmitBuilder::new(Direction::In, 1).seqnum(5).buffer_length(8).interval(10).build()).unwrap();
+    let unlink = |seq| CmdUnlink { seq, devid: 0x00010001, direction: Direction::In, ep: 1, seqnum: 5 };
+    dev.unlink(unlink(6)).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetUnlink(ret) => {
+            assert_eq!(ret.seqnum, 6);
+            assert_eq!(ret.status as i32, -104);
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    // Already gone the second time around
+    dev.unlink(unlink(7)).unwrap();
+    match dev.read_reply().unwrap() {
+        Packet::RetUnlink(ret) => {
+            assert_eq!(ret.seqnum, 7);
+            assert_eq!(ret.status, 0);
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    drop(dev);
+
+    assert_eq!(srv.join().unwrap(), 5);
+}
+
+// Like EmptyDevice, but answers what it does not implement with an error
+struct FallbackDevice;
+
+impl DeviceProvider for FallbackDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        EmptyDevice.list()
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        EmptyDevice.import(busid)
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        EmptyDevice.handle_submit(cmd)
+    }
+
+    fn unhandled(&self, pkt: &Packet, imported: bool) -> Option<Packet> {
+        assert!(!imported);
+        error_reply(pkt, 38)
+    }
+}
+
+#[test]
+fn test_server_unhandled_fallback() {
+    let (mut client_end, server_end) = memory::pair();
+    let srv = thread::spawn(move || UsbIpServer::new(FallbackDevice).handle(server_end));
+
+    // Nothing is imported, so the submit gets the fallback answer -ENOSYS
+    let cmd = CmdSubmitBuilder::new(Direction::In, 1).seqnum(9).buffer_length(8).build();
+    Packet::CmdSubmit(cmd).write(&mut client_end).unwrap();
+    match Packet::read(&mut client_end).unwrap() {
+        Packet::RetSubmit(ret) => {
+            assert_eq!(ret.seqnum, 9);
+            assert_eq!(ret.status as i32, -38);
+            assert_eq!(ret.data, Some(vec![]));
+        },
+        p => panic!("Unexpected {:?}", p),
+    }
+    // and the connection stays usable
+    let mut client = UsbIpClient::new(client_end);
+    assert_eq!(client.list_devices().unwrap(), EmptyDevice.list());
+    drop(client);
+
+    srv.join().unwrap().unwrap();
+}
+
+// LoopbackDevice counting the devices handed back to it
+struct ReleasingDevice {
+    released: AtomicU32,
+}
+
+impl DeviceProvider for ReleasingDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        EmptyDevice.list()
+    }
+
+    fn import(&self, busid: &str) -> Option<RepImport> {
+        LoopbackDevice.import(busid)
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        LoopbackDevice.handle_submit(cmd)
+    }
+
+    fn release(&self, device: &RepImport) {
+        assert_eq!(device.busid, "1-1");
+        self.released.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_server_releases_device() {
+    let server = UsbIpServer::new(ReleasingDevice { released: AtomicU32::new(0) });
+    let released = |server: &UsbIpServer<ReleasingDevice>| server.provider().released.load(Ordering::SeqCst);
+
+    // A failed import has nothing to release
+    let (client_end, server_end) = memory::pair();
+    let srv = server.clone();
+    let handler = thread::spawn(move || srv.handle(server_end));
+    assert!(UsbIpClient::new(client_end).import("2-1").is_err());
+    handler.join().unwrap().unwrap();
+    assert_eq!(released(&server), 0);
+
+    let (client_end, server_end) = memory::pair();
+    let srv = server.clone();
+    let handler = thread::spawn(move || srv.handle(server_end));
+    drop(UsbIpClient::new(client_end).import("1-1").unwrap());
+    handler.join().unwrap().unwrap();
+    assert_eq!(released(&server), 1);
+
+    // Also when the connection ends with an error
+    let (mut client_end, server_end) = memory::pair();
+    let srv = server.clone();
+    let handler = thread::spawn(move || srv.handle(server_end));
+    Packet::ReqImport(ReqImport { busid: "1-1".to_string() }).write(&mut client_end).unwrap();
+    assert!(matches!(Packet::read(&mut client_end).unwrap(), Packet::RepImport(_)));
+    Packet::ReqDevList.write(&mut client_end).unwrap();
+    assert!(handler.join().unwrap().is_err());
+    assert_eq!(released(&server), 2);
+}
@@ -0,0 +1,139 @@
+extern crate tracing;
+extern crate vusbip;
+
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use vusbip::client::UsbIpClient;
+use vusbip::memory;
+use vusbip::protocol::{self, DeviceDescriptor, RepImport, CmdSubmit, CmdSubmitBuilder,
+                       RetSubmit, UsbIpStatus, Direction};
+use vusbip::server::{DeviceProvider, UsbIpServer};
+
+// Collects every span created by this test binary with the fields recorded on
+// it, so it must stay the only test in this file. Span n has Id n + 1.
+struct CaptureSubscriber;
+
+type Fields = Vec<(String, String)>;
+
+static SPANS: Mutex<Vec<(String, Fields)>> = Mutex::new(Vec::new());
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
+
+impl Subscriber for CaptureSubscriber {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes) -> Id {
+        let mut fields = Vec::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let mut spans = SPANS.lock().unwrap();
+        spans.push((attrs.metadata().name().to_string(), fields));
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record) {
+        let mut spans = SPANS.lock().unwrap();
+        values.record(&mut FieldVisitor(&mut spans[span.into_u64() as usize - 1].1));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+struct HubDevice;
+
+impl HubDevice {
+    fn descriptor() -> DeviceDescriptor {
+        DeviceDescriptor {
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 2,
+            speed: 3,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 0,
+            interfaces: vec![]
+        }
+    }
+}
+
+impl DeviceProvider for HubDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![HubDevice::descriptor()]
+    }
+
+    fn import(&self, _busid: &str) -> Option<RepImport> {
+        Some(RepImport::from_device(&HubDevice::descriptor(), UsbIpStatus::Ok.as_u32()))
+    }
+
+    fn handle_submit(&self, cmd: &CmdSubmit) -> RetSubmit {
+        RetSubmit { seqnum: cmd.seqnum, devid: cmd.devid, direction: cmd.direction, ep: cmd.ep, data: Some(vec![0x09]), length: 1,
+                    ..Default::default() }
+    }
+}
+
+fn fields(pairs: &[(&str, &str)]) -> Fields {
+    pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn test_packet_spans() {
+    tracing::subscriber::set_global_default(CaptureSubscriber).unwrap();
+
+    let (client_end, server_end) = memory::pair();
+    let srv = thread::spawn(move || UsbIpServer::new(HubDevice).handle(server_end));
+    let mut client = UsbIpClient::new(client_end);
+    assert_eq!(client.list_devices().unwrap().len(), 1);
+    let mut dev = client.import("1-1").unwrap();
+    let cmd = CmdSubmitBuilder::new(Direction::In, 1).devid(protocol::devid(1, 2)).buffer_length(1).build();
+    assert_eq!(dev.submit(cmd).unwrap(), 1);
+    dev.read_reply().unwrap();
+    drop(dev);
+    srv.join().unwrap().unwrap();
+
+    // Client and server run on their own threads, so only the number of
+    // spans alike is certain, not their order
+    let spans = SPANS.lock().unwrap();
+    let count = |name: &str, expected: Fields| spans.iter().filter(|s| s.0 == name && s.1 == expected).count();
+    assert_eq!(count("handshake", fields(&[("step", "devlist")])), 2);
+    assert_eq!(count("handshake", fields(&[("step", "import")])), 2);
+    for &name in &["write", "read"] {
+        assert_eq!(count(name, fields(&[("packet_type", "ReqDevList")])), 1);
+        assert_eq!(count(name, fields(&[("packet_type", "RepImport")])), 1);
+        for &ptype in &["CmdSubmit", "RetSubmit"] {
+            let urb = fields(&[("packet_type", ptype), ("seqnum", "1"), ("devid", "65538")]);
+            assert_eq!(count(name, urb), 1, "{} {}", name, ptype);
+        }
+    }
+    // The read at the end of the stream fails and records nothing
+    assert!(spans.iter().any(|s| s.0 == "read" && s.1.is_empty()));
+}
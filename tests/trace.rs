@@ -0,0 +1,107 @@
+extern crate log;
+extern crate vusbip;
+
+use std::sync::Mutex;
+use std::thread;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use vusbip::client::UsbIpClient;
+use vusbip::memory;
+use vusbip::protocol::{DeviceDescriptor, RepImport, CmdSubmit, RetSubmit, UsbIpStatus};
+use vusbip::server::{DeviceProvider, UsbIpServer};
+
+// Collects every record logged by this test binary, so it must stay the only
+// test in this file.
+struct CaptureLogger;
+
+static RECORDS: Mutex<Vec<(String, Level, String)>> = Mutex::new(Vec::new());
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS.lock().unwrap().push((record.target().to_string(), record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger;
+
+struct HubDevice;
+
+impl HubDevice {
+    fn descriptor() -> DeviceDescriptor {
+        DeviceDescriptor {
+            path: "/sys/devices/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 2,
+            speed: 3,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 0,
+            interfaces: vec![]
+        }
+    }
+}
+
+impl DeviceProvider for HubDevice {
+    fn list(&self) -> Vec<DeviceDescriptor> {
+        vec![HubDevice::descriptor()]
+    }
+
+    fn import(&self, _busid: &str) -> Option<RepImport> {
+        Some(RepImport::from_device(&HubDevice::descriptor(), UsbIpStatus::Ok.as_u32()))
+    }
+
+    fn handle_submit(&self, _cmd: &CmdSubmit) -> RetSubmit {
+        unreachable!()
+    }
+}
+
+#[test]
+fn test_handshake_trace() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let (client_end, server_end) = memory::pair();
+    let srv = thread::spawn(move || UsbIpServer::new(HubDevice).handle(server_end));
+    let mut client = UsbIpClient::new(client_end);
+    assert_eq!(client.list_devices().unwrap().len(), 1);
+    let dev = client.import("1-1").unwrap();
+    drop(dev);
+    srv.join().unwrap().unwrap();
+
+    // Both sides log a packet before sending and after receiving it, so the
+    // lines come in the order of the exchange
+    let records = RECORDS.lock().unwrap();
+    let lines = |level: Level| records.iter()
+        .filter(|&&(ref target, l, ref msg)| target.starts_with("vusbip::") && l == level
+                && (msg.starts_with("Sent") || msg.starts_with("Received")))
+        .map(|(_, _, msg)| msg.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(lines(Level::Debug), vec![
+        "Sent: ReqDevList",
+        "Received: ReqDevList",
+        "Sent: RepDevList status=0 devices=1",
+        "Received: RepDevList status=0 devices=1",
+        "Sent: ReqImport busid=1-1",
+        "Received: ReqImport busid=1-1",
+        "Sent: RepImport status=0 busid=1-1",
+        "Received: RepImport status=0 busid=1-1",
+    ]);
+    let hex = lines(Level::Trace);
+    assert_eq!(hex.len(), 8);
+    assert_eq!(hex[0], "Sent bytes: 0111800500000000");
+    assert!(hex[3].starts_with("Received bytes: 01110005000000000000000"));
+}
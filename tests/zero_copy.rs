@@ -0,0 +1,77 @@
+extern crate vusbip;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use vusbip::protocol::{Packet, PacketRef, PacketEncoder, CmdSubmitBuilder, Direction};
+
+// Counts the allocations of each thread, so that those of the test harness
+// running alongside do not show up in the test's. Replaces the allocator of
+// this test binary, so it must stay the only test in this file.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn read_into_does_not_allocate() {
+    const LEN: usize = 64 * 1024;
+    const ROUNDS: usize = 100;
+    let cmd = CmdSubmitBuilder::new(Direction::Out, 2)
+        .data(vec![0xa5; LEN])
+        .build();
+    let mut encoded = Vec::new();
+    Packet::CmdSubmit(cmd).write(&mut encoded).unwrap();
+    let mut buf = Vec::with_capacity(LEN);
+
+    let before = allocations();
+    for _ in 0..ROUNDS {
+        match Packet::read_into(&mut encoded.as_slice(), &mut buf).unwrap() {
+            PacketRef::CmdSubmit(view) => assert_eq!(view.data.map(|d| d.len()), Some(LEN)),
+            _ => panic!("Expected CmdSubmit"),
+        }
+    }
+    assert_eq!(allocations() - before, 0);
+
+    // The owned path needs a fresh buffer for every packet
+    let before = allocations();
+    for _ in 0..ROUNDS {
+        Packet::read(&mut encoded.as_slice()).unwrap();
+    }
+    assert!(allocations() - before >= ROUNDS);
+
+    // Same on the writing side, once the encoder's buffer has grown
+    let pkt = Packet::read(&mut encoded.as_slice()).unwrap();
+    let mut encoder = PacketEncoder::new();
+    encoder.encode(&pkt).unwrap();
+    let before = allocations();
+    for _ in 0..ROUNDS {
+        assert_eq!(encoder.encode(&pkt).unwrap().len(), encoded.len());
+    }
+    assert_eq!(allocations() - before, 0);
+
+    let before = allocations();
+    for _ in 0..ROUNDS {
+        pkt.write_to_vec().unwrap();
+    }
+    assert!(allocations() - before >= ROUNDS);
+}